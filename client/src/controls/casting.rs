@@ -0,0 +1,52 @@
+use crate::{input, replication::entity_mapping, ui::gameplay, world_connection, SystemSets};
+use bevy::prelude::*;
+use lib_spells::shared;
+
+const SPELL_SLOTS: u8 = 8;
+
+/// Send cast & stop casting requests for spell bar buttons. Spells are cast at the UI target,
+/// or at ourselves with no target. Bar slot N casts spell ID N, which the server keeps pointing
+/// at the same spell across reloads.
+pub fn sys_cast_spells(
+    buttons: Res<input::ActionButtons>,
+    mut conn: ResMut<world_connection::Connection>,
+    entity_map: Res<entity_mapping::EntityMap>,
+    ui_target: Query<Entity, With<gameplay::UITarget>>,
+    time: Res<Time>,
+) {
+    let timestamp = time.elapsed();
+    if buttons.get_button_state(input::Action::StopCasting) == input::ButtonState::Pressed {
+        conn.enqueue_cancel_cast(timestamp);
+        return;
+    }
+
+    for slot in 0..SPELL_SLOTS {
+        if buttons.get_button_state(input::Action::Spell(slot)) != input::ButtonState::Pressed {
+            continue;
+        }
+        let target = ui_target
+            .get_single()
+            .ok()
+            .and_then(|target| entity_map.get_world_entity(target))
+            .unwrap_or(conn.client_info().you);
+        conn.enqueue_cast(
+            timestamp,
+            shared::SpellID::from(slot as usize),
+            shared::SpellTarget::Entity(target),
+        );
+        return;
+    }
+}
+
+pub struct CastingPlugin;
+
+impl Plugin for CastingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            sys_cast_spells
+                .run_if(resource_exists::<world_connection::Connection>)
+                .in_set(SystemSets::Controls),
+        );
+    }
+}
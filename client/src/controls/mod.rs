@@ -1,2 +1,3 @@
 pub mod cameras;
+pub mod casting;
 pub mod wish_dir;
@@ -1,5 +1,5 @@
 use bevy::prelude::*;
-use lib_spells::net;
+use lib_spells::{combat_log, net, shared};
 use crate::render::terrain;
 
 /// World connected
@@ -10,10 +10,21 @@ pub struct ConnectedEvent;
 #[derive(Debug, Event)]
 pub struct WorldStateEvent {
     pub seq: u8,
-    pub state: net::WorldState,
+    pub snapshot: net::Snapshot,
     pub client_info: net::ClientInfo,
 }
 
+/// The server refused or stopped one of our casts
+#[derive(Debug, Event)]
+pub struct CastRejectedEvent {
+    pub spell_id: shared::SpellID,
+    pub reason: shared::CastRejectedReason,
+}
+
+/// Something happened in combat. Entities are the server's, see `EntityMap::get_game_entity`.
+#[derive(Debug, Event)]
+pub struct CombatLogEvent(pub combat_log::CombatLogEntry);
+
 /// World disconnected
 #[derive(Debug, Event)]
 pub struct DisconnectedEvent(pub Option<String>);
@@ -37,6 +48,8 @@ impl Plugin for EventsPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<Events<WorldStateEvent>>();
         app.add_event::<ConnectedEvent>();
+        app.add_event::<CastRejectedEvent>();
+        app.add_event::<CombatLogEvent>();
         app.add_event::<DisconnectedEvent>();
         app.add_event::<GenerateTerrainEvent>();
         app.add_event::<DestroyTerrainEvent>();
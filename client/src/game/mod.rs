@@ -1,5 +1,5 @@
 use crate::{
-    controls::{cameras, wish_dir},
+    controls::{cameras, casting, wish_dir},
     render::terrain,
     replication, world_connection,
 };
@@ -25,6 +25,7 @@ impl Plugin for GamePlugin {
         app.add_plugins((
             cameras::follow_cam::FollowCameraPlugin,
             wish_dir::WishDirPlugin,
+            casting::CastingPlugin,
             replication::ReplicationPlugin,
             world_connection::WorldConnectionPlugin,
             main_menu::MainMenuPlugin,
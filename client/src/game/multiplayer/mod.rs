@@ -32,6 +32,7 @@ impl Plugin for MultiplayerPlugin {
             Update,
             (
                 render::sys_add_player_rendering,
+                render::sys_add_projectile_rendering,
                 sys_exit_multiplayer.run_if(on_event::<events::DisconnectedEvent>()),
             )
                 .run_if(in_state(GameStates::Game)),
@@ -44,6 +44,28 @@ pub fn sys_add_player_rendering(
     }
 }
 
+/// Add rendering to all new `Projectile` entities, they're moved by position extrapolation.
+pub fn sys_add_projectile_rendering(
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut commands: Commands,
+    query: Query<Entity, Added<shared::Projectile>>,
+) {
+    let projectile_mesh = meshes.add(Sphere::new(0.3));
+    let projectile_mat = materials.add(Color::ORANGE_RED);
+
+    for projectile_entity in query.iter() {
+        commands.entity(projectile_entity).insert((
+            Cleanup,
+            PbrBundle {
+                mesh: projectile_mesh.clone(),
+                material: projectile_mat.clone(),
+                ..default()
+            },
+        ));
+    }
+}
+
 /// Add a follow camera that follows the `PredictedPlayer`.
 pub fn sys_follow_cam_predicted_player(
     mut commands: Commands,
@@ -10,6 +10,9 @@ pub enum Action {
     Primary,
     Secondary,
     Pause,
+    /// Cast the spell in the given bar slot
+    Spell(u8),
+    StopCasting,
 }
 
 #[derive(Copy, PartialEq, Debug, Clone)]
@@ -87,6 +90,15 @@ impl Default for InputButtonActionMap {
             (Input::KeyCode(KeyCode::Tab), Action::Target),
             (Input::MouseButton(MouseButton::Left), Action::Primary),
             (Input::MouseButton(MouseButton::Right), Action::Secondary),
+            (Input::KeyCode(KeyCode::Digit1), Action::Spell(0)),
+            (Input::KeyCode(KeyCode::Digit2), Action::Spell(1)),
+            (Input::KeyCode(KeyCode::Digit3), Action::Spell(2)),
+            (Input::KeyCode(KeyCode::Digit4), Action::Spell(3)),
+            (Input::KeyCode(KeyCode::Digit5), Action::Spell(4)),
+            (Input::KeyCode(KeyCode::Digit6), Action::Spell(5)),
+            (Input::KeyCode(KeyCode::Digit7), Action::Spell(6)),
+            (Input::KeyCode(KeyCode::Digit8), Action::Spell(7)),
+            (Input::KeyCode(KeyCode::KeyX), Action::StopCasting),
         ]))
     }
 }
@@ -9,9 +9,13 @@ use bevy::log;
 pub struct MappedEntities(EntityHashMap<Entity>);
 
 impl EntityMapper for MappedEntities {
+    /// Entities we don't know about, like a cast's target that has since despawned, map to
+    /// `Entity::PLACEHOLDER`
     fn map_entity(&mut self, entity: Entity) -> Entity {
-        // todo: this could crash
-        self.0.get(&entity).copied().unwrap()
+        self.0.get(&entity).copied().unwrap_or_else(|| {
+            log::debug!("no mapping for {:?}", entity);
+            Entity::PLACEHOLDER
+        })
     }
 }
 
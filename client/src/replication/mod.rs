@@ -1,6 +1,6 @@
 /*! Replicates world state into the game world */
 
-mod entity_mapping;
+pub mod entity_mapping;
 
 use crate::{controls::wish_dir, events, world_connection, SystemSets};
 use bevy::{
@@ -8,7 +8,7 @@ use bevy::{
     log,
     prelude::*,
 };
-use lib_spells::shared;
+use lib_spells::{net, shared};
 use std::collections::VecDeque;
 use std::time::Duration;
 
@@ -29,6 +29,7 @@ struct LastVelocityChange(Option<Duration>);
 struct ReplicationSys<'w, 's> {
     commands: Commands<'w, 's>,
     entity_map: ResMut<'w, entity_mapping::EntityMap>,
+    history: ResMut<'w, net::SnapshotHistory>,
     replication_completed_ev: ResMut<'w, Events<events::ReplicationCompleted>>,
     replicated_query: Query<'w, 's, Entity, With<Replicated>>,
 }
@@ -46,18 +47,15 @@ impl<'w, 's> ReplicationSys<'w, 's> {
             self.commands.entity(entity).despawn_recursive();
         }
         self.entity_map.clear();
+        self.history.clear();
     }
 
-    fn update_world_entity(
-        &mut self,
-        world_entity: Entity,
-        mut state: lib_spells::net::EntityState,
-    ) {
+    fn update_world_entity(&mut self, world_entity: Entity, mut delta: net::EntityDelta) {
         let game_entity = self.entity_map.get_game_entity(world_entity).unwrap();
-        state.map_entities(self.entity_map.world_to_game());
-        self.commands.add(lib_spells::net::AddEntityStateCommand {
+        delta.map_entities(self.entity_map.world_to_game());
+        self.commands.add(net::ApplyEntityDeltaCommand {
             entity: game_entity,
-            entity_state: state,
+            delta,
         });
     }
 
@@ -76,30 +74,58 @@ impl<'w, 's> ReplicationSys<'w, 's> {
         self.entity_map.world_entity_is_mapped(world_entity)
     }
 
+    /// Rebuild the state `snapshot` was taken of from its baseline, then replicate what changed
+    /// since the last state we replicated. Entities left out of a delta are unchanged, not gone.
+    /// Returns the replicated tick, or `None` if it is stale or we no longer have the baseline.
     fn replicate_state(
         &mut self,
-        mut state: lib_spells::net::WorldState,
+        snapshot: net::Snapshot,
         server_player_entity: Entity,
-    ) {
-        // find entities we're tracking that don't exist in this state, and kill them
-        let mapped_world_entities = self.entity_map.collect_world();
-        let lost = mapped_world_entities.iter().filter(|world| !state.entity_state_map.contains_key(world));
-        for entity in lost {
-            self.despawn_world_entity(*entity);
+    ) -> Option<u64> {
+        // world states over UDP can land out of order with ones over TCP
+        if self
+            .history
+            .latest()
+            .is_some_and(|(latest, _)| snapshot.tick <= latest)
+        {
+            return None;
         }
-
-        for (world_entity, state) in state.entity_state_map.drain() {
-            if !self.has_world_entity(world_entity) {
-                let spawned = self.spawn_world_entity(world_entity);
-                if world_entity == server_player_entity {
-                    log::debug!("marking player server entity {:?}", world_entity);
-                    self.commands.entity(spawned).insert(PredictedPlayer);
+        let state = match snapshot.baseline {
+            Some(baseline) => match self.history.get(baseline) {
+                Some(baseline_state) => baseline_state.apply(&snapshot),
+                None => {
+                    log::warn!(
+                        "dropping snapshot {}, baseline {} is gone",
+                        snapshot.tick,
+                        baseline
+                    );
+                    return None;
                 }
+            },
+            None => net::WorldState::default().apply(&snapshot),
+        };
+        let mut since_replicated = state.snapshot(snapshot.tick, self.history.latest());
+
+        for world_entity in since_replicated.despawned {
+            if self.has_world_entity(world_entity) {
+                self.despawn_world_entity(world_entity);
             }
-            self.update_world_entity(world_entity, state);
         }
+        for world_entity in since_replicated.spawned {
+            let spawned = self.spawn_world_entity(world_entity);
+            if world_entity == server_player_entity {
+                log::debug!("marking player server entity {:?}", world_entity);
+                self.commands.entity(spawned).insert(PredictedPlayer);
+            }
+        }
+        for (world_entity, delta) in since_replicated.changes.drain() {
+            self.update_world_entity(world_entity, delta);
+        }
+
+        self.history.push(snapshot.tick, state);
         self.replication_completed_ev
             .send(events::ReplicationCompleted);
+        Some(snapshot.tick)
     }
 }
 
@@ -186,22 +212,44 @@ fn sys_replicate_world_state(
     mut state_events: ResMut<Events<events::WorldStateEvent>>,
     mut replication: ReplicationSys,
     mut cached: ResMut<InputCache>,
+    mut conn: Option<ResMut<world_connection::Connection>>,
+    time: Res<Time>,
 ) {
     for state_ev in state_events.drain() {
-        replication.replicate_state(state_ev.state, state_ev.client_info.you);
+        let replicated = replication.replicate_state(state_ev.snapshot, state_ev.client_info.you);
+        if let (Some(tick), Some(conn)) = (replicated, conn.as_mut()) {
+            conn.enqueue_snapshot_ack(time.elapsed(), tick);
+        }
         cached.drop_to_sequence(state_ev.seq);
     }
 }
 
+/// Speed multiplier for the player's movement, mirroring the server's
+fn movement_speed(is_dead: bool, multiplier: Option<&shared::MovementSpeed>) -> f32 {
+    match is_dead {
+        true => 0.0,
+        false => multiplier.map_or(1.0, |multiplier| multiplier.0),
+    }
+}
+
+type ReconcileQuery = (
+    &'static mut Transform,
+    &'static shared::Position,
+    Has<shared::Dead>,
+    Option<&'static shared::MovementSpeed>,
+);
+
 fn sys_reconcile_player(
     time: Res<Time>,
     cached: ResMut<InputCache>,
-    mut predicted_pos_query: Query<(&mut Transform, &shared::Position), With<PredictedPlayer>>,
+    mut predicted_pos_query: Query<ReconcileQuery, With<PredictedPlayer>>,
 ) {
-    let (mut player_actual_pos, player_server_pos) = match predicted_pos_query.get_single_mut() {
-        Ok(v) => v,
-        _ => return,
-    };
+    let (mut player_actual_pos, player_server_pos, is_dead, multiplier) =
+        match predicted_pos_query.get_single_mut() {
+            Ok(v) => v,
+            _ => return,
+        };
+    let speed = movement_speed(is_dead, multiplier);
 
     let mut replayed_pos = player_server_pos.0;
     for (i, input) in cached.iter().enumerate() {
@@ -211,7 +259,7 @@ fn sys_reconcile_player(
             None => time.elapsed(),
         };
         let t_passed = (t - input.time).as_secs_f32();
-        replayed_pos += input.wish_dir * t_passed;
+        replayed_pos += input.wish_dir * speed * t_passed;
     }
     player_actual_pos.translation = replayed_pos;
 }
@@ -238,17 +286,24 @@ fn sys_enqueue_movements(
     }
 }
 
+type PredictQuery = (
+    &'static mut Transform,
+    Has<shared::Dead>,
+    Option<&'static shared::MovementSpeed>,
+);
+
 /// Read the set wish dir on the predicted player and predict a new translation
 fn sys_predict_player_pos(
-    mut predicted_query: Query<&mut Transform, With<PredictedPlayer>>,
+    mut predicted_query: Query<PredictQuery, With<PredictedPlayer>>,
     wish_dir: Res<wish_dir::WishDir>,
     time: Res<Time>,
 ) {
-    let mut predicted_trans = match predicted_query.get_single_mut() {
+    let (mut predicted_trans, is_dead, multiplier) = match predicted_query.get_single_mut() {
         Ok(t) => t,
         Err(_) => return,
     };
-    predicted_trans.translation += wish_dir.0 * time.delta_seconds();
+    let speed = movement_speed(is_dead, multiplier);
+    predicted_trans.translation += wish_dir.0 * speed * time.delta_seconds();
 }
 
 fn sys_mark_velocity_change(
@@ -296,6 +351,7 @@ impl Plugin for ReplicationPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(entity_mapping::EntityMappingPlugin);
         app.insert_resource(InputCache::default());
+        app.init_resource::<net::SnapshotHistory>();
         app.add_systems(
             Update,
             (
@@ -1,14 +1,19 @@
-use crate::{input, ui::widgets};
+use crate::{events, input, ui::widgets};
 use bevy::{log, prelude::*};
 use lib_spells::shared;
 
 const NAME_UI_GAP: f32 = 0.2;
+const CAST_REJECTED_SECS: f32 = 2.0;
 
 #[derive(Component)]
 pub struct GameplayUIWidget;
 #[derive(Component, Debug)]
 pub struct CastingSpellText(Entity);
 
+/// Shows why our last cast was rejected, until the timer runs out
+#[derive(Component, Debug)]
+pub struct CastRejectedText(Timer);
+
 #[derive(Component, Debug)]
 pub struct PlayerUnitFrame;
 #[derive(Component, Debug)]
@@ -16,6 +21,8 @@ pub struct TargetUnitFrame;
 #[derive(Component, Debug)]
 pub struct UnitFrameHealthText;
 #[derive(Component, Debug)]
+pub struct UnitFramePowerText;
+#[derive(Component, Debug)]
 pub struct UnitFrameNameText;
 #[derive(Component, Debug)]
 pub struct UnitFrame;
@@ -131,9 +138,17 @@ pub fn sys_setup_unitframes(
             ))
             .id();
 
+        let power_text = commands
+            .spawn((
+                GameplayUIWidget,
+                UnitFramePowerText,
+                widgets::text("NONE".into()),
+            ))
+            .id();
+
         commands
             .entity(unitframe)
-            .insert_children(2, &[name_text, hp_text]);
+            .insert_children(2, &[name_text, hp_text, power_text]);
     }
 }
 
@@ -146,7 +161,7 @@ pub fn sys_render_unitframe_health<F: Component, E: Component>(
     let mut iter = has_unitframe_health_text.iter_many_mut(unitframe_children);
     let mut text = iter.fetch_next().unwrap();
     let hp = match is_tracked_health.get_single() {
-        Ok(hp) => hp.0,
+        Ok(hp) => hp,
         Err(err) => match err {
             bevy::ecs::query::QuerySingleError::NoEntities(_) => {
                 text.sections[0].value = "None".to_string();
@@ -157,7 +172,22 @@ pub fn sys_render_unitframe_health<F: Component, E: Component>(
             }
         },
     };
-    text.sections[0].value = format!("{} HP", hp);
+    text.sections[0].value = format!("{}/{} HP", hp.current, hp.max);
+}
+
+pub fn sys_render_unitframe_power<F: Component, E: Component>(
+    is_unitframe_children: Query<&Children, With<F>>,
+    is_tracked_power: Query<&shared::Power, With<E>>,
+    mut has_unitframe_power_text: Query<&mut Text, With<UnitFramePowerText>>,
+) {
+    let unitframe_children = is_unitframe_children.single();
+    let mut iter = has_unitframe_power_text.iter_many_mut(unitframe_children);
+    let mut text = iter.fetch_next().unwrap();
+    // units without power (or no unit at all) show nothing
+    text.sections[0].value = match is_tracked_power.get_single() {
+        Ok(power) => format!("{:.0} / {:.0} Power", power.current.floor(), power.max),
+        Err(_) => String::new(),
+    };
 }
 
 pub fn sys_render_unitframe_name<F: Component, E: Component>(
@@ -182,10 +212,13 @@ pub fn sys_render_unitframe_name<F: Component, E: Component>(
     };
     text.sections[0].value = name.clone();
 }
-/// Add the child text entity & tag it when something is casting if there's no text already.
+type CastStarted = Or<(Added<shared::CastingSpell>, Added<shared::ChannelingSpell>)>;
+
+/// Add the child text entity & tag it when something is casting or channelling if there's no
+/// text already.
 pub fn sys_add_casting_ui(
     mut commands: Commands,
-    casting_added: Query<(Entity, Option<&Children>), Added<shared::CastingSpell>>,
+    casting_added: Query<(Entity, Option<&Children>), CastStarted>,
     text_query: Query<Has<CastingSpellText>>,
 ) {
     for (caster_entity, caster_children) in casting_added.iter() {
@@ -207,21 +240,61 @@ pub fn sys_add_casting_ui(
     }
 }
 
-/// Update casting spell text for casting parents. Despawn text with invalid entities.
+/// Update casting spell text for casting parents, channels count down. Despawn text with invalid
+/// entities.
 pub fn sys_render_casters_ui(
     mut commands: Commands,
     is_casting: Query<&shared::CastingSpell>,
+    is_channeling: Query<&shared::ChannelingSpell>,
     mut has_casting_text: Query<(Entity, &CastingSpellText, &mut Text)>,
 ) {
     for (entity, casting_text, mut text) in has_casting_text.iter_mut() {
         if let Ok(casting_spell) = is_casting.get(casting_text.0) {
             text.sections[0].value = casting_spell.cast_timer.elapsed_secs().to_string();
+        } else if let Ok(channeling_spell) = is_channeling.get(casting_text.0) {
+            text.sections[0].value = channeling_spell
+                .duration
+                .remaining_secs()
+                .to_string();
         } else {
             commands.entity(entity).despawn_recursive();
         }
     }
 }
 
+/// Replace the cast rejection text with the latest rejection reason
+pub fn sys_add_cast_rejected_ui(
+    mut commands: Commands,
+    mut rejected_ev_r: EventReader<events::CastRejectedEvent>,
+    has_rejected_text: Query<Entity, With<CastRejectedText>>,
+) {
+    let reason = match rejected_ev_r.read().last() {
+        Some(ev) => ev.reason,
+        None => return,
+    };
+    for entity in has_rejected_text.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    commands.spawn((
+        GameplayUIWidget,
+        widgets::text(reason.to_string()),
+        CastRejectedText(Timer::from_seconds(CAST_REJECTED_SECS, TimerMode::Once)),
+    ));
+}
+
+/// Despawn cast rejection text once it's been up long enough
+pub fn sys_clear_cast_rejected_ui(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut has_rejected_text: Query<(Entity, &mut CastRejectedText)>,
+) {
+    for (entity, mut rejected_text) in has_rejected_text.iter_mut() {
+        if rejected_text.0.tick(time.delta()).finished() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
 #[derive(Component, Debug)]
 pub struct AABB {
     half_extents: Vec3,
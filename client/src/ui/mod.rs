@@ -1,4 +1,4 @@
-mod gameplay;
+pub mod gameplay;
 pub mod widgets;
 
 use crate::{replication, window};
@@ -34,6 +34,10 @@ impl Plugin for UiPlugin {
                             gameplay::PlayerUnitFrame,
                             replication::PredictedPlayer,
                         >,
+                        gameplay::sys_render_unitframe_power::<
+                            gameplay::PlayerUnitFrame,
+                            replication::PredictedPlayer,
+                        >,
                         // target unitframe
                         gameplay::sys_render_unitframe_name::<
                             gameplay::TargetUnitFrame,
@@ -43,6 +47,10 @@ impl Plugin for UiPlugin {
                             gameplay::TargetUnitFrame,
                             gameplay::UITarget,
                         >,
+                        gameplay::sys_render_unitframe_power::<
+                            gameplay::TargetUnitFrame,
+                            gameplay::UITarget,
+                        >,
                     ),
                 )
                     .chain(),
@@ -50,6 +58,8 @@ impl Plugin for UiPlugin {
                 (
                     gameplay::sys_add_casting_ui,
                     gameplay::sys_render_casters_ui,
+                    gameplay::sys_add_cast_rejected_ui,
+                    gameplay::sys_clear_cast_rejected_ui,
                 )
                     .chain(),
                 // name rendering
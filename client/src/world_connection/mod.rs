@@ -1,7 +1,10 @@
 mod stream;
 use crate::{events, SystemSets};
 use bevy::{ecs::system::SystemId, log, prelude::*, tasks};
-use lib_spells::net::{self, packet};
+use lib_spells::{
+    net::{self, packet},
+    shared,
+};
 use std::time::Duration;
 
 const PING_FREQ: Duration = Duration::from_secs(4);
@@ -11,7 +14,9 @@ pub struct Connection {
     connection: stream::Connection,
     ping_timer: Timer,
     client_info: net::ClientInfo,
-    movement_inputs: Vec<(Duration, u8, Vec3)>,
+    movement_input: Option<(Duration, u8, Vec3)>,
+    cast_commands: Vec<packet::Packet>,
+    snapshot_ack: Option<(Duration, u64)>,
 }
 
 impl Connection {
@@ -25,9 +30,40 @@ impl Connection {
         self.client_info
     }
 
-    /// Queue a movement input to be sent out
+    /// Queue a movement input to be sent out. Only the newest is kept, it replaces any still
+    /// waiting on the connection.
     pub fn enqueue_input(&mut self, timestamp: Duration, seq: u8, input: Vec3) {
-        self.movement_inputs.push((timestamp, seq, input));
+        self.movement_input = Some((timestamp, seq, input));
+    }
+
+    /// Queue a request to start casting `spell_id` at `target` (a world entity)
+    pub fn enqueue_cast(
+        &mut self,
+        timestamp: Duration,
+        spell_id: shared::SpellID,
+        target: shared::SpellTarget,
+    ) {
+        self.cast_commands.push(packet::Packet {
+            timestamp,
+            seq: 0,
+            command_type: packet::PacketType::CastSpell,
+            command_data: packet::PacketData::CastSpell(packet::SpellCast { spell_id, target }),
+        });
+    }
+
+    /// Let the server know we applied the world state snapshot of `tick`. Only the latest is sent.
+    pub fn enqueue_snapshot_ack(&mut self, timestamp: Duration, tick: u64) {
+        self.snapshot_ack = Some((timestamp, tick));
+    }
+
+    /// Queue a request to stop the current cast
+    pub fn enqueue_cancel_cast(&mut self, timestamp: Duration) {
+        self.cast_commands.push(packet::Packet {
+            timestamp,
+            seq: 0,
+            command_type: packet::PacketType::CancelCast,
+            command_data: packet::PacketData::Noop,
+        });
     }
 
     fn new(conn: stream::Connection, client_info: net::ClientInfo) -> Self {
@@ -35,7 +71,9 @@ impl Connection {
             connection: conn,
             client_info,
             ping_timer: Timer::new(PING_FREQ, TimerMode::Repeating),
-            movement_inputs: Vec::new(),
+            movement_input: None,
+            cast_commands: Vec::new(),
+            snapshot_ack: None,
         }
     }
 }
@@ -49,23 +87,56 @@ fn sys_net_send_ping(time: Res<Time>, mut conn: ResMut<Connection>) -> stream::R
     Ok(())
 }
 
-/// Write all buffered movement inputs
-/// TODO: batching
+/// Write the newest movement input. The server only needs the latest direction & timestamp, so
+/// while the connection is backed up the input waits & gets replaced by newer ones.
 fn sys_net_send_movement(mut conn: ResMut<Connection>) -> stream::Result<()> {
-    conn.movement_inputs
-        .drain(..)
-        .collect::<Vec<(Duration, u8, Vec3)>>()
-        .into_iter()
-        .try_for_each(|(timestamp, seq, dir)| {
-            conn.connection
-                .send_packet(lib_spells::net::packet::Packet {
-                    timestamp,
-                    seq,
-                    command_type: packet::PacketType::Move,
-                    command_data: packet::PacketData::Movement(dir.into()),
-                })?;
-            Ok(())
-        })
+    if let Some((timestamp, seq, dir)) = conn.movement_input.take() {
+        let sent = conn.connection.send_packet(packet::Packet {
+            timestamp,
+            seq,
+            command_type: packet::PacketType::Move,
+            command_data: packet::PacketData::Movement(dir.into()),
+        })?;
+        if !sent {
+            conn.movement_input = Some((timestamp, seq, dir));
+        }
+    }
+    Ok(())
+}
+
+/// Write buffered cast commands in order, keeping the rest for later if the connection is
+/// backed up
+fn sys_net_send_casts(mut conn: ResMut<Connection>) -> stream::Result<()> {
+    let mut sent = 0;
+    for packet in conn.cast_commands.clone() {
+        if !conn.connection.send_packet(packet)? {
+            break;
+        }
+        sent += 1;
+    }
+    conn.cast_commands.drain(..sent);
+    Ok(())
+}
+
+/// Send packets queued this frame, & any the server hasn't acknowledged, if we're on UDP
+fn sys_net_send_datagram(mut conn: ResMut<Connection>) -> stream::Result<()> {
+    conn.connection.send_datagram()
+}
+
+/// Write the latest snapshot ack, if there's a new one
+fn sys_net_send_snapshot_ack(mut conn: ResMut<Connection>) -> stream::Result<()> {
+    if let Some((timestamp, tick)) = conn.snapshot_ack.take() {
+        let sent = conn.connection.send_packet(packet::Packet {
+            timestamp,
+            seq: 0,
+            command_type: packet::PacketType::AckSnapshot,
+            command_data: packet::PacketData::AckSnapshot(tick),
+        })?;
+        if !sent {
+            conn.snapshot_ack = Some((timestamp, tick));
+        }
+    }
+    Ok(())
 }
 
 fn sys_net_handle_error(
@@ -102,15 +173,26 @@ fn sys_check_connection(world: &mut World) {
     world.resource_scope(|world, mut connection: Mut<Connection>| {
         match connection.connection.read() {
             Ok(reads) => {
-                for (seq, state) in reads {
-                    world
-                        .get_resource_mut::<Events<events::WorldStateEvent>>()
-                        .unwrap()
-                        .send(events::WorldStateEvent {
-                            seq,
-                            client_info: connection.client_info,
-                            state,
-                        });
+                for message in reads {
+                    match message {
+                        net::ServerMessage::WorldState { seq, snapshot } => {
+                            world
+                                .get_resource_mut::<Events<events::WorldStateEvent>>()
+                                .unwrap()
+                                .send(events::WorldStateEvent {
+                                    seq,
+                                    client_info: connection.client_info,
+                                    snapshot,
+                                });
+                        }
+                        net::ServerMessage::CastRejected { spell_id, reason } => {
+                            log::info!("cast of {} rejected: {}", spell_id, reason);
+                            world.send_event(events::CastRejectedEvent { spell_id, reason });
+                        }
+                        net::ServerMessage::CombatLog(entries) => {
+                            world.send_event_batch(entries.into_iter().map(events::CombatLogEvent));
+                        }
+                    }
                 }
             }
             Err(err) => {
@@ -189,6 +271,21 @@ impl Plugin for WorldConnectionPlugin {
                         .pipe(sys_net_handle_error)
                         .run_if(resource_exists::<Connection>)
                         .in_set(SystemSets::NetSend),
+                    sys_net_send_casts
+                        .pipe(sys_net_handle_error)
+                        .run_if(resource_exists::<Connection>)
+                        .in_set(SystemSets::NetSend),
+                    sys_net_send_snapshot_ack
+                        .pipe(sys_net_handle_error)
+                        .run_if(resource_exists::<Connection>)
+                        .in_set(SystemSets::NetSend),
+                    sys_net_send_datagram
+                        .pipe(sys_net_handle_error)
+                        .run_if(resource_exists::<Connection>)
+                        .after(sys_net_send_movement)
+                        .after(sys_net_send_casts)
+                        .after(sys_net_send_snapshot_ack)
+                        .in_set(SystemSets::NetSend),
                 ),
             ),
         );
@@ -1,10 +1,18 @@
-use lib_spells::message_stream;
+use bevy::log;
+use lib_spells::{
+    datagram, message_stream,
+    net::{self, handshake},
+};
 use std::{
     fmt::Display,
+    io,
     time::{Duration, Instant},
 };
 
-const MAX_MESSAGE_SIZE: u16 = u16::MAX;
+/// Largest message we accept from the server, world states can get big
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+/// Identifies this build to servers
+const BUILD_ID: &str = concat!("spells-client ", env!("CARGO_PKG_VERSION"));
 
 pub type Result<T> = std::result::Result<T, ConnectionError>;
 
@@ -12,7 +20,11 @@ pub type Result<T> = std::result::Result<T, ConnectionError>;
 pub enum ConnectionError {
     IOError(std::io::Error),
     StreamError(message_stream::MessageStreamError),
+    DatagramError(datagram::DatagramError),
     InvalidServer,
+    /// The server speaks a protocol from before handshakes were versioned
+    OutdatedServer,
+    Rejected(handshake::Rejection),
     BadAddress(std::net::AddrParseError),
     BadData,
 }
@@ -28,9 +40,18 @@ impl Display for ConnectionError {
             Self::StreamError(err) => {
                 write!(f, "stream error: {}", err)
             }
+            Self::DatagramError(err) => {
+                write!(f, "datagram error: {}", err)
+            }
             Self::InvalidServer => {
                 write!(f, "invalid server response")
             }
+            Self::OutdatedServer => {
+                write!(f, "server is running an older version")
+            }
+            Self::Rejected(reason) => {
+                write!(f, "rejected by server: {}", reason)
+            }
             Self::BadAddress(addr_err) => {
                 write!(f, "bad address: {}", addr_err)
             }
@@ -59,30 +80,112 @@ impl From<message_stream::MessageStreamError> for ConnectionError {
     }
 }
 
+impl From<datagram::DatagramError> for ConnectionError {
+    fn from(value: datagram::DatagramError) -> Self {
+        Self::DatagramError(value)
+    }
+}
+
 impl From<std::io::Error> for ConnectionError {
     fn from(value: std::io::Error) -> Self {
         Self::IOError(value)
     }
 }
 
+/// Packets & world states going over UDP, once the server gave us a session
+#[derive(Debug)]
+struct UdpConnection {
+    socket: std::net::UdpSocket,
+    channel: datagram::Channel,
+    packets: datagram::ReliableSender,
+}
+
+impl UdpConnection {
+    fn connect(server_addr: std::net::SocketAddr, session: u64) -> Result<Self> {
+        let bind_addr = match server_addr {
+            std::net::SocketAddr::V4(_) => "0.0.0.0:0",
+            std::net::SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = std::net::UdpSocket::bind(bind_addr)?;
+        socket.connect(server_addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            channel: datagram::Channel::new(session),
+            packets: Default::default(),
+        })
+    }
+
+    /// Send every packet the server hasn't acknowledged yet
+    fn send(&mut self) -> Result<()> {
+        let bundle = self.packets.bundle(self.channel.next_sequence());
+        let datagram = self.channel.encode(&bundle)?;
+        match self.socket.send(&datagram) {
+            // lost datagrams get their packets resent anyway
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read every waiting datagram, returning the newest world states
+    fn read(&mut self) -> Result<Vec<net::ServerMessage>> {
+        let mut messages = vec![];
+        let mut buf = [0; datagram::MAX_DATAGRAM_BYTES];
+        loop {
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let received = match self.channel.decode(&buf[..len]) {
+                Ok(received) => received,
+                Err(err) => {
+                    log::debug!("dropping datagram: {}", err);
+                    continue;
+                }
+            };
+            for seq in received.acked {
+                self.packets.ack(seq);
+            }
+            // empty datagrams only carry acks
+            if let Some(payload) = received.payload.filter(|payload| !payload.is_empty()) {
+                messages.push(net::deserialize(payload)?);
+            }
+        }
+        Ok(messages)
+    }
+}
+
 #[derive(Debug)]
 pub struct Connection {
     stream: message_stream::MessageStream<std::net::TcpStream>,
+    /// Messages that came in with the handshake, after the welcome
+    unread: Vec<Vec<u8>>,
+    udp: Option<UdpConnection>,
     last_ping: Option<Instant>,
     pub last_ping_rtt: Option<Duration>,
 }
 
 impl Connection {
-    pub fn new(stream: message_stream::MessageStream<std::net::TcpStream>) -> Self {
+    pub fn new(
+        stream: message_stream::MessageStream<std::net::TcpStream>,
+        unread: Vec<Vec<u8>>,
+    ) -> Self {
         Self {
             stream,
+            unread,
+            udp: None,
             last_ping: None,
             last_ping_rtt: None,
         }
     }
 
-    pub fn read(&mut self) -> Result<Vec<(u8, lib_spells::net::WorldState)>> {
-        let messages = self.stream.try_read_messages()?;
+    pub fn read(&mut self) -> Result<Vec<lib_spells::net::ServerMessage>> {
+        // we read every frame, so this is where writes the socket couldn't take get retried
+        self.stream.flush()?;
+        let mut messages = std::mem::take(&mut self.unread);
+        messages.append(&mut self.stream.try_read_messages()?);
 
         messages
             .iter()
@@ -94,11 +197,26 @@ impl Connection {
                 }
             });
 
-        messages
+        let mut server_messages = messages
             .iter()
             .filter(|m| !message_is_ping(m))
-            .map(|m| deserialize_world_state_message(m))
-            .collect::<Result<Vec<(u8, lib_spells::net::WorldState)>>>()
+            .map(|m| lib_spells::net::deserialize(m).map_err(ConnectionError::from))
+            .collect::<Result<Vec<lib_spells::net::ServerMessage>>>()?;
+        if let Some(udp) = self.udp.as_mut() {
+            server_messages.append(&mut udp.read()?);
+        }
+        Ok(server_messages)
+    }
+
+    /// Switch packets over to UDP, letting the server know where to send world states
+    fn start_udp(&mut self, session: u64, port: u16) -> Result<()> {
+        let mut server_addr = self.stream.inner().peer_addr()?;
+        server_addr.set_port(port);
+        log::info!("sending packets over udp to {}", server_addr);
+        let mut udp = UdpConnection::connect(server_addr, session)?;
+        udp.send()?;
+        self.udp = Some(udp);
+        Ok(())
     }
 
     pub fn ping(&mut self) -> Result<bool> {
@@ -110,21 +228,32 @@ impl Connection {
         }
     }
 
-    /// Returns true if the input was actually sent
+    /// Returns true if the packet was taken, false if the connection is backed up & it should
+    /// be retried later. Over UDP packets are only queued until `send_datagram`.
     pub fn send_packet(&mut self, packet: lib_spells::net::packet::Packet) -> Result<bool> {
-        Ok(self.stream.try_write_prefixed(&packet.serialize())?)
+        match self.udp.as_mut() {
+            Some(udp) => match udp.packets.push(packet.serialize()) {
+                // the server is behind on acks, wait for it to catch up
+                Err(datagram::DatagramError::BacklogFull(pending)) => {
+                    log::debug!("holding packet back, {} still unacknowledged", pending);
+                    Ok(false)
+                }
+                pushed => {
+                    pushed?;
+                    Ok(true)
+                }
+            },
+            None => Ok(self.stream.try_write_prefixed(&packet.serialize())?),
+        }
     }
-}
 
-/// Get the first sequence byte, parse the rest as world state
-fn deserialize_world_state_message(data: &[u8]) -> Result<(u8, lib_spells::net::WorldState)> {
-    if data.is_empty() {
-        return Err(ConnectionError::BadData);
+    /// Send every queued packet the server hasn't acknowledged yet over UDP
+    pub fn send_datagram(&mut self) -> Result<()> {
+        match self.udp.as_mut() {
+            Some(udp) if udp.packets.has_pending() => udp.send(),
+            _ => Ok(()),
+        }
     }
-
-    let seq = u8::from_le_bytes(data[0..1].try_into().unwrap());
-    let state: lib_spells::net::WorldState = lib_spells::net::deserialize(&data[1..])?;
-    Ok((seq, state))
 }
 
 pub fn get_connection(
@@ -135,47 +264,76 @@ pub fn get_connection(
     raw_stream.set_nonblocking(true)?;
     raw_stream.set_nodelay(true)?;
 
-    let mut message_stream =
-        message_stream::MessageStream::create(raw_stream, MAX_MESSAGE_SIZE.into())?;
+    let mut message_stream = message_stream::MessageStream::create(raw_stream, MAX_MESSAGE_SIZE)?;
     let mut messages = vec![];
-    let mut wrote_pass = false;
 
+    // the header tells us how the server frames messages, so wait for it before writing
+    while messages.is_empty() {
+        read_messages(&mut message_stream, &mut messages)?;
+    }
+    let server_max = read_server_header(&messages[0])?;
+    message_stream.set_framing(message_stream::Framing::V2);
+    message_stream.set_max_write(server_max);
+
+    let features = handshake::Features {
+        udp: true,
+        compression: false,
+    };
+    let hello =
+        handshake::ClientHello::new(BUILD_ID.into(), message_stream.max_read() as u32, features)
+            .with_password(password.map(String::from));
+    let hello = net::serialize(&hello)?;
+
+    let mut sent_hello = false;
     loop {
-        if !wrote_pass {
-            if let Some(password) = password {
-                wrote_pass = write_data(&mut message_stream, password.as_bytes())?;
-            }
+        if !sent_hello {
+            sent_hello = write_data(&mut message_stream, &hello)?;
         }
         read_messages(&mut message_stream, &mut messages)?;
-        if let Some(client_info) = validate_server_messages(&messages)? {
-            return Ok((Connection::new(message_stream), client_info));
+        if let Some(welcome) = validate_server_messages(&messages)? {
+            log::info!(
+                "connected speaking protocol {} at {} ticks/s, session {}",
+                welcome.version,
+                welcome.tick_rate,
+                welcome.session
+            );
+            let unread = messages.split_off(2);
+            let mut connection = Connection::new(message_stream, unread);
+            if let Some(port) = welcome.udp_port.filter(|_| welcome.features.udp) {
+                connection.start_udp(welcome.session, port)?;
+            }
+            return Ok((connection, welcome.client_info));
         }
     }
 }
 
-fn validate_server_messages(messages: &[Vec<u8>]) -> Result<Option<lib_spells::net::ClientInfo>> {
-    if let Some(msg) = messages.first() {
-        if msg != lib_spells::SERVER_HEADER {
-            return Err(ConnectionError::InvalidServer);
-        }
-    } else {
-        return Ok(None);
+/// Check the server's header, returning the largest message it accepts
+fn read_server_header(header: &[u8]) -> Result<usize> {
+    if header.starts_with(lib_spells::SERVER_HEADER_PREFIX)
+        && !header.starts_with(lib_spells::SERVER_HEADER)
+    {
+        return Err(ConnectionError::OutdatedServer);
     }
+    let server_max = header
+        .strip_prefix(lib_spells::SERVER_HEADER)
+        .and_then(|max| max.try_into().ok())
+        .ok_or(ConnectionError::InvalidServer)?;
+    Ok(u32::from_le_bytes(server_max) as usize)
+}
 
-    let client_info_raw = if let Some(msg) = messages.get(1) {
+/// The server's answer to our hello, once it's arrived
+fn validate_server_messages(messages: &[Vec<u8>]) -> Result<Option<handshake::Welcome>> {
+    let handshake_raw = if let Some(msg) = messages.get(1) {
         msg
     } else {
         return Ok(None);
     };
 
-    let client_info = match lib_spells::net::deserialize(client_info_raw) {
-        Ok(ci) => ci,
-        Err(_) => {
-            return Err(ConnectionError::BadData);
-        }
-    };
-
-    Ok(Some(client_info))
+    match lib_spells::net::deserialize(handshake_raw) {
+        Ok(handshake::ServerHandshake::Welcome(welcome)) => Ok(Some(welcome)),
+        Ok(handshake::ServerHandshake::Rejected(reason)) => Err(ConnectionError::Rejected(reason)),
+        Err(_) => Err(ConnectionError::BadData),
+    }
 }
 
 fn read_messages(
@@ -1,9 +1,10 @@
 /// For friendlyness/hostility determinations
 use bevy_ecs::{system::SystemParam, prelude::*};
+use serde::{Deserialize, Serialize};
 
 pub type Faction = u8;
 
-#[derive(Debug, Default, PartialEq, Clone, Copy)]
+#[derive(Debug, Default, PartialEq, Clone, Copy, Deserialize, Serialize)]
 pub enum Hostility {
     #[default]
     Hostile,
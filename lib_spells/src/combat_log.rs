@@ -0,0 +1,64 @@
+/*! Structured record of what happened in combat. The server streams it to clients & can write it
+to disk for analysis. */
+use bevy_ecs::entity::Entity;
+use serde::{Deserialize, Serialize};
+
+use crate::shared;
+
+/// What happened between a combat log entry's origin & target. Amounts are positive.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum CombatEvent {
+    CastStart {
+        spell_id: shared::SpellID,
+    },
+    /// The cast was cancelled or interrupted before it finished
+    CastStop {
+        spell_id: shared::SpellID,
+    },
+    CastSuccess {
+        spell_id: shared::SpellID,
+    },
+    CastFail {
+        spell_id: shared::SpellID,
+        reason: shared::CastRejectedReason,
+    },
+    Damage {
+        school: shared::SpellSchool,
+        amount: i64,
+        absorbed: i64,
+        resisted: i64,
+        crit: bool,
+    },
+    /// `overheal` of `amount` went past the target's max health
+    Heal {
+        school: shared::SpellSchool,
+        amount: i64,
+        overheal: i64,
+        crit: bool,
+    },
+    /// Damage entirely soaked up by shields
+    Absorb {
+        school: shared::SpellSchool,
+        amount: i64,
+    },
+    AuraApplied {
+        aura_id: shared::AuraID,
+    },
+    AuraRefreshed {
+        aura_id: shared::AuraID,
+        stacks: u8,
+    },
+    AuraRemoved {
+        aura_id: shared::AuraID,
+    },
+    Death,
+}
+
+/// `event` from `origin` to `target`, on server tick `tick`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CombatLogEntry {
+    pub tick: u64,
+    pub origin: Entity,
+    pub target: Entity,
+    pub event: CombatEvent,
+}
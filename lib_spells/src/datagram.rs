@@ -0,0 +1,434 @@
+/*! Sequenced & acknowledged datagrams for the UDP side of a session.
+
+Every datagram carries its session, its own sequence number & which of the other end's recent
+datagrams arrived, so each end learns what got through without sending anything extra. */
+use std::collections::VecDeque;
+use std::fmt::Display;
+
+/// Session u64, sequence u16, ack u16 & ack bits u32, all little endian
+pub const HEADER_BYTES: usize = 16;
+/// Largest datagram we send, small enough to dodge IP fragmentation on most links
+pub const MAX_DATAGRAM_BYTES: usize = 1200;
+/// Largest payload that fits in a datagram
+pub const MAX_PAYLOAD_BYTES: usize = MAX_DATAGRAM_BYTES - HEADER_BYTES;
+/// How many datagrams back the ack bits reach
+const ACK_WINDOW: u16 = 32;
+/// Sent datagrams we wait on acks for before counting the oldest as lost
+const MAX_IN_FLIGHT: usize = 256;
+/// First id u32 of a reliable bundle
+const BUNDLE_HEADER_BYTES: usize = 4;
+/// Reliable messages waiting on acks before no more can be sent
+const MAX_RELIABLE_PENDING: usize = 256;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DatagramError {
+    TooShort(usize),
+    WrongSession,
+    PayloadTooLarge { size: usize, max: usize },
+    BadBundle,
+    /// Too many reliable messages are waiting on acks to take another
+    BacklogFull(usize),
+}
+
+pub type Result<T> = std::result::Result<T, DatagramError>;
+
+impl Display for DatagramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TooShort(size) => {
+                write!(f, "datagram of {} bytes is too short", size)
+            }
+            Self::WrongSession => {
+                write!(f, "datagram for another session")
+            }
+            Self::PayloadTooLarge { size, max } => {
+                write!(
+                    f,
+                    "payload of {} bytes is over the {} byte maximum",
+                    size, max
+                )
+            }
+            Self::BadBundle => {
+                write!(f, "malformed reliable bundle")
+            }
+            Self::BacklogFull(pending) => {
+                write!(f, "{} reliable messages still unacknowledged", pending)
+            }
+        }
+    }
+}
+
+/// Is sequence `a` newer than `b`, allowing for wrap around
+pub fn sequence_greater(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < u16::MAX / 2
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub session: u64,
+    pub seq: u16,
+    /// Newest sequence received from the other end
+    pub ack: u16,
+    /// Bit n set if `ack - n` was received, so no bits means nothing has been
+    pub ack_bits: u32,
+}
+
+impl Header {
+    pub fn to_bytes(self) -> [u8; HEADER_BYTES] {
+        let mut bytes = [0; HEADER_BYTES];
+        bytes[..8].copy_from_slice(&self.session.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.ack.to_le_bytes());
+        bytes[12..].copy_from_slice(&self.ack_bits.to_le_bytes());
+        bytes
+    }
+
+    /// Split a datagram into its header & payload
+    pub fn parse(datagram: &[u8]) -> Result<(Self, &[u8])> {
+        if datagram.len() < HEADER_BYTES {
+            return Err(DatagramError::TooShort(datagram.len()));
+        }
+        let (header, payload) = datagram.split_at(HEADER_BYTES);
+        let header = Self {
+            session: u64::from_le_bytes(header[..8].try_into().unwrap()),
+            seq: u16::from_le_bytes(header[8..10].try_into().unwrap()),
+            ack: u16::from_le_bytes(header[10..12].try_into().unwrap()),
+            ack_bits: u32::from_le_bytes(header[12..].try_into().unwrap()),
+        };
+        Ok((header, payload))
+    }
+}
+
+/// A decoded datagram
+#[derive(Debug, PartialEq, Eq)]
+pub struct Received<'a> {
+    /// `None` for duplicates & datagrams older than one already delivered
+    pub payload: Option<&'a [u8]>,
+    /// Our datagrams the other end says it received, that we hadn't heard about yet
+    pub acked: Vec<u16>,
+}
+
+/// One end of a session's datagram traffic. Only ever delivers datagrams newer than the last
+/// one delivered, so payloads should supersede earlier ones.
+#[derive(Debug)]
+pub struct Channel {
+    session: u64,
+    next_seq: u16,
+    /// Newest sequence received & which of the ones before it were
+    received: Option<u16>,
+    received_bits: u32,
+    /// Sent sequences not acknowledged yet, oldest first
+    in_flight: VecDeque<u16>,
+    lost: u64,
+}
+
+impl Channel {
+    pub fn new(session: u64) -> Self {
+        Self {
+            session,
+            next_seq: 0,
+            received: None,
+            received_bits: 0,
+            in_flight: VecDeque::new(),
+            lost: 0,
+        }
+    }
+
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Sequence the next encoded datagram gets
+    pub fn next_sequence(&self) -> u16 {
+        self.next_seq
+    }
+
+    /// Datagrams we gave up waiting on acks for
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// Wrap `payload` in a header, acknowledging what we've received
+    pub fn encode(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        if payload.len() > MAX_PAYLOAD_BYTES {
+            return Err(DatagramError::PayloadTooLarge {
+                size: payload.len(),
+                max: MAX_PAYLOAD_BYTES,
+            });
+        }
+        let header = Header {
+            session: self.session,
+            seq: self.next_seq,
+            ack: self.received.unwrap_or_default(),
+            ack_bits: self.received_bits,
+        };
+        self.in_flight.push_back(self.next_seq);
+        if self.in_flight.len() > MAX_IN_FLIGHT {
+            self.in_flight.pop_front();
+            self.lost += 1;
+        }
+        self.next_seq = self.next_seq.wrapping_add(1);
+        Ok([&header.to_bytes()[..], payload].concat())
+    }
+
+    /// Record a datagram from the other end & pick out its payload if it's the newest yet
+    pub fn decode<'a>(&mut self, datagram: &'a [u8]) -> Result<Received<'a>> {
+        let (header, payload) = Header::parse(datagram)?;
+        if header.session != self.session {
+            return Err(DatagramError::WrongSession);
+        }
+        let acked = self.read_acks(header.ack, header.ack_bits);
+        let is_newest = self.record_received(header.seq);
+        Ok(Received {
+            payload: is_newest.then_some(payload),
+            acked,
+        })
+    }
+
+    /// Mark `seq` received, returning true if it's newer than everything before it
+    fn record_received(&mut self, seq: u16) -> bool {
+        let newest = match self.received {
+            Some(newest) => newest,
+            None => {
+                self.received = Some(seq);
+                self.received_bits = 1;
+                return true;
+            }
+        };
+        if sequence_greater(seq, newest) {
+            let shift = seq.wrapping_sub(newest);
+            self.received_bits = match shift < ACK_WINDOW {
+                true => self.received_bits << shift,
+                false => 0,
+            } | 1;
+            self.received = Some(seq);
+            return true;
+        }
+        let age = newest.wrapping_sub(seq);
+        if age < ACK_WINDOW {
+            self.received_bits |= 1 << age;
+        }
+        false
+    }
+
+    /// Drop our in flight datagrams covered by an ack, returning them. Ones the ack window has
+    /// moved past without them are lost.
+    fn read_acks(&mut self, ack: u16, ack_bits: u32) -> Vec<u16> {
+        let mut acked = vec![];
+        let mut lost = 0;
+        self.in_flight.retain(|&seq| {
+            if ack_bits == 0 || sequence_greater(seq, ack) {
+                return true;
+            }
+            let age = ack.wrapping_sub(seq);
+            if age >= ACK_WINDOW {
+                lost += 1;
+                false
+            } else if ack_bits & (1 << age) != 0 {
+                acked.push(seq);
+                false
+            } else {
+                true
+            }
+        });
+        self.lost += lost;
+        acked
+    }
+}
+
+/// Reliable, ordered delivery of small messages over a `Channel`: every unacknowledged message is
+/// resent in each datagram until one carrying it is acknowledged.
+#[derive(Debug, Default)]
+pub struct ReliableSender {
+    next_id: u32,
+    /// Id, message & the first datagram it went out in
+    pending: VecDeque<(u32, Vec<u8>, Option<u16>)>,
+}
+
+impl ReliableSender {
+    /// Queue `message` to go out with the next bundle. Fails rather than give up on older
+    /// messages if the other end has stopped acknowledging them.
+    pub fn push(&mut self, message: Vec<u8>) -> Result<()> {
+        if message.len() > u8::MAX as usize {
+            return Err(DatagramError::PayloadTooLarge {
+                size: message.len(),
+                max: u8::MAX as usize,
+            });
+        }
+        if self.pending.len() >= MAX_RELIABLE_PENDING {
+            return Err(DatagramError::BacklogFull(self.pending.len()));
+        }
+        self.pending.push_back((self.next_id, message, None));
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(())
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Forget messages that went out in datagrams up to `seq`, now that it was acknowledged
+    pub fn ack(&mut self, seq: u16) {
+        self.pending
+            .retain(|(_, _, sent_in)| sent_in.is_none_or(|sent| sequence_greater(sent, seq)));
+    }
+
+    /// Payload for datagram `seq`: as many pending messages as fit, oldest first
+    pub fn bundle(&mut self, seq: u16) -> Vec<u8> {
+        let first_id = self.pending.front().map_or(self.next_id, |(id, ..)| *id);
+        let mut payload = first_id.to_le_bytes().to_vec();
+        for (_, message, sent_in) in self.pending.iter_mut() {
+            if payload.len() + 1 + message.len() > MAX_PAYLOAD_BYTES {
+                break;
+            }
+            payload.push(message.len() as u8);
+            payload.extend_from_slice(message);
+            sent_in.get_or_insert(seq);
+        }
+        payload
+    }
+}
+
+/// Receiving end of a `ReliableSender`
+#[derive(Debug, Default)]
+pub struct ReliableReceiver {
+    next_id: u32,
+}
+
+impl ReliableReceiver {
+    /// Messages in `bundle` we haven't seen yet, in order
+    pub fn receive<'a>(&mut self, bundle: &'a [u8]) -> Result<Vec<&'a [u8]>> {
+        if bundle.len() < BUNDLE_HEADER_BYTES {
+            return Err(DatagramError::BadBundle);
+        }
+        let (first_id, mut rest) = bundle.split_at(BUNDLE_HEADER_BYTES);
+        let mut id = u32::from_le_bytes(first_id.try_into().unwrap());
+        let mut messages = vec![];
+        while let Some((&len, after_len)) = rest.split_first() {
+            if after_len.len() < len as usize {
+                return Err(DatagramError::BadBundle);
+            }
+            let (message, after_message) = after_len.split_at(len as usize);
+            if id >= self.next_id {
+                messages.push(message);
+                self.next_id = id + 1;
+            }
+            id += 1;
+            rest = after_message;
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn test_header() {
+        let header = Header {
+            session: 0xdeadbeef,
+            seq: 7,
+            ack: u16::MAX,
+            ack_bits: 0b101,
+        };
+        let datagram = [&header.to_bytes()[..], b"payload"].concat();
+        assert_eq!(Header::parse(&datagram), Ok((header, &b"payload"[..])));
+        assert_eq!(Header::parse(&[0; 3]), Err(DatagramError::TooShort(3)));
+        assert!(sequence_greater(0, u16::MAX));
+        assert!(!sequence_greater(u16::MAX, 0));
+    }
+
+    #[test]
+    fn test_loss_and_duplication() {
+        let mut a = Channel::new(1);
+        let mut b = Channel::new(1);
+        let sent: Vec<Vec<u8>> = (0..4u8).map(|i| a.encode(&[i]).unwrap()).collect();
+
+        // 1 arrives after 3, 2 never does, 3 arrives twice
+        assert_eq!(b.decode(&sent[0]).unwrap().payload, Some(&[0][..]));
+        assert_eq!(b.decode(&sent[3]).unwrap().payload, Some(&[3][..]));
+        assert_eq!(b.decode(&sent[3]).unwrap().payload, None);
+        assert_eq!(b.decode(&sent[1]).unwrap().payload, None);
+        assert_eq!(
+            Channel::new(2).decode(&sent[0]),
+            Err(DatagramError::WrongSession)
+        );
+
+        let reply = b.encode(&[]).unwrap();
+        let mut acked = a.decode(&reply).unwrap().acked;
+        acked.sort();
+        assert_eq!(acked, vec![0, 1, 3]);
+
+        // 2 falls out of the ack window
+        for _ in 0..ACK_WINDOW {
+            b.decode(&a.encode(&[]).unwrap()).unwrap();
+        }
+        a.decode(&b.encode(&[]).unwrap()).unwrap();
+        assert_eq!(a.lost(), 1);
+    }
+
+    #[test]
+    fn test_reliable_over_loopback() {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        server.connect(client.local_addr().unwrap()).unwrap();
+
+        let mut client_channel = Channel::new(9);
+        let mut server_channel = Channel::new(9);
+        let mut sender = ReliableSender::default();
+        let mut receiver = ReliableReceiver::default();
+        let mut buf = [0; MAX_DATAGRAM_BYTES];
+        let mut delivered = vec![];
+
+        for i in 0..6u8 {
+            sender.push(vec![i]).unwrap();
+            let seq = client_channel.next_sequence();
+            let datagram = client_channel.encode(&sender.bundle(seq)).unwrap();
+            // every other datagram is lost on the way
+            if i % 2 == 1 {
+                continue;
+            }
+            client.send(&datagram).unwrap();
+            let len = server.recv(&mut buf).unwrap();
+            let received = server_channel.decode(&buf[..len]).unwrap();
+            let messages = receiver.receive(received.payload.unwrap()).unwrap();
+            delivered.extend(messages.iter().map(|m| m[0]));
+
+            // the server's reply arrives twice
+            let reply = server_channel.encode(&[]).unwrap();
+            server.send(&reply).unwrap();
+            server.send(&reply).unwrap();
+            for _ in 0..2 {
+                let len = client.recv(&mut buf).unwrap();
+                for seq in client_channel.decode(&buf[..len]).unwrap().acked {
+                    sender.ack(seq);
+                }
+            }
+        }
+
+        assert_eq!(delivered, vec![0, 1, 2, 3, 4]);
+        assert!(sender.has_pending());
+        assert_eq!(receiver.receive(&[0; 2]), Err(DatagramError::BadBundle));
+    }
+
+    #[test]
+    fn test_reliable_backlog_full() {
+        let mut sender = ReliableSender::default();
+        for i in 0..MAX_RELIABLE_PENDING {
+            sender.push(vec![i as u8]).unwrap();
+        }
+        assert_eq!(
+            sender.push(vec![0]),
+            Err(DatagramError::BacklogFull(MAX_RELIABLE_PENDING))
+        );
+
+        // the oldest message still goes out first, & room frees up once it's acked
+        let bundle = sender.bundle(0);
+        assert_eq!(&bundle[..BUNDLE_HEADER_BYTES], &0u32.to_le_bytes());
+        sender.ack(0);
+        assert!(sender.push(vec![0]).is_ok());
+    }
+}
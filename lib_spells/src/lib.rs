@@ -1,6 +1,13 @@
-pub const SERVER_HEADER: &[u8] = "SPELLSERVER 0.1\n".as_bytes();
+/// Sent by servers before anything else, followed by the largest message they accept as a u32.
+/// Messages after it are framed with `message_stream::Framing::V2`, starting with the client's
+/// `net::handshake::ClientHello`.
+pub const SERVER_HEADER: &[u8] = "SPELLSERVER 0.3\n".as_bytes();
+/// Every server header starts with this, so older servers can be told apart from strangers
+pub const SERVER_HEADER_PREFIX: &[u8] = "SPELLSERVER ".as_bytes();
 
+pub mod datagram;
 pub mod message_stream;
 pub mod alignment;
+pub mod combat_log;
 pub mod net;
 pub mod shared;
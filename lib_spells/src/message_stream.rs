@@ -1,12 +1,40 @@
 /*! Buffered, message parsing mio TCP stream wrapper */
+use std::collections::VecDeque;
 use std::fmt::Display;
 use std::io;
 
+/// Length prefix size of `Framing::V1` messages
 pub const HEADER_BYTES: usize = 2;
+/// Longest `Framing::V2` length prefix, a varint of up to a u32
+pub const MAX_VARINT_HEADER_BYTES: usize = 5;
+/// Most bytes pulled off the stream per read
+const READ_CHUNK_BYTES: usize = 4096;
+/// Default cap on bytes queued for writing before new messages are turned away
+pub const DEFAULT_MAX_QUEUED_BYTES: usize = 8 * 1024 * 1024;
+
+/// How messages are length prefixed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Framing {
+    /// u16 little endian lengths, what the server header is sent with
+    V1,
+    /// LEB128 varint lengths up to a u32
+    V2,
+}
+
+impl Framing {
+    /// Longest message this framing can carry
+    pub fn max_message_bytes(&self) -> usize {
+        match self {
+            Self::V1 => u16::MAX as usize,
+            Self::V2 => u32::MAX as usize,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum MessageStreamError {
     InvalidHeaderSize(usize),
+    MessageTooLarge { size: usize, max: usize },
     WriteMessageErr,
     IO(io::Error),
 }
@@ -19,6 +47,13 @@ impl Display for MessageStreamError {
             Self::InvalidHeaderSize(size) => {
                 write!(f, "invalid header size {}", size)
             }
+            Self::MessageTooLarge { size, max } => {
+                write!(
+                    f,
+                    "message of {} bytes is over the {} byte maximum",
+                    size, max
+                )
+            }
             Self::WriteMessageErr => {
                 write!(f, "failed to write full message")
             }
@@ -49,70 +84,114 @@ fn is_would_block(err: &io::Error) -> bool {
     err.kind() == io::ErrorKind::WouldBlock
 }
 
-fn create_header(data: &[u8]) -> [u8; 2] {
-    (data.len() as u16).to_le_bytes()
+/// Length prefix for `data`. Lengths must already fit the framing.
+fn create_header(framing: Framing, data: &[u8]) -> Vec<u8> {
+    match framing {
+        Framing::V1 => (data.len() as u16).to_le_bytes().to_vec(),
+        Framing::V2 => {
+            let mut len = data.len() as u32;
+            let mut header = Vec::with_capacity(MAX_VARINT_HEADER_BYTES);
+            loop {
+                let byte = (len & 0x7f) as u8;
+                len >>= 7;
+                if len == 0 {
+                    header.push(byte);
+                    return header;
+                }
+                header.push(byte | 0x80);
+            }
+        }
+    }
 }
 
-/// Attempt to parse 2 bytes into a message length
-fn parse_message_length(buf: &[u8; 2], max: usize) -> Result<usize> {
-    let to_read = u16::from_le_bytes(*buf) as usize;
+/// Attempt to parse a message length off the front of `buf`, returning the header's size & the
+/// length, or `None` if the header is incomplete.
+fn parse_message_length(
+    framing: Framing,
+    buf: &[u8],
+    max: usize,
+) -> Result<Option<(usize, usize)>> {
+    let (header_len, to_read) = match framing {
+        Framing::V1 => match buf.get(..HEADER_BYTES) {
+            Some(header) => (
+                HEADER_BYTES,
+                u16::from_le_bytes(header.try_into().unwrap()) as usize,
+            ),
+            None => return Ok(None),
+        },
+        Framing::V2 => {
+            let mut to_read = 0_u64;
+            let mut header_len = None;
+            for (i, byte) in buf.iter().take(MAX_VARINT_HEADER_BYTES).enumerate() {
+                to_read |= ((byte & 0x7f) as u64) << (7 * i);
+                if byte & 0x80 == 0 {
+                    header_len = Some(i + 1);
+                    break;
+                }
+            }
+            match header_len {
+                Some(header_len) => (header_len, to_read as usize),
+                // a varint that never ends
+                None if buf.len() >= MAX_VARINT_HEADER_BYTES => {
+                    return Err(MessageStreamError::InvalidHeaderSize(to_read as usize))
+                }
+                None => return Ok(None),
+            }
+        }
+    };
     if to_read < 1 || to_read > max {
         return Err(MessageStreamError::InvalidHeaderSize(to_read));
     }
-    Ok(to_read)
+    Ok(Some((header_len, to_read)))
 }
 
-/// Recursively parse header-messages from `buf`. Rejects headers for messages that are larger than `buf` + a header.
-fn parse_messages(
-    buf: &[u8],
-    start: usize,
-    len: usize,
-    mut messages: Vec<Vec<u8>>,
-) -> Result<(usize, usize, Vec<Vec<u8>>)> {
-    // assume we start from the position of the header
-    let our_bit = &buf[start..len];
-    if our_bit.len() < HEADER_BYTES {
-        return Ok((start, len, messages));
-    }
-    let message_len = parse_message_length(
-        our_bit[..HEADER_BYTES].try_into().unwrap(),
-        buf.len() - HEADER_BYTES,
-    )?;
-    let total_read_size = HEADER_BYTES + message_len;
-    if our_bit.len() < total_read_size {
-        // we didn't have enough data for the complete message
-        return Ok((start, len, messages));
-    }
-    // add a full message
-    messages.push(our_bit[HEADER_BYTES..HEADER_BYTES + message_len].to_vec());
-    let more = len - (total_read_size);
-    if more > 0 {
-        parse_messages(buf, start + total_read_size, len, messages)
-    } else {
-        Ok((start + total_read_size, len, messages))
+/// Parse every complete header-message from `buf`, rejecting headers for messages larger than
+/// `max`. Returns how many bytes were parsed & the messages.
+fn parse_messages(framing: Framing, buf: &[u8], max: usize) -> Result<(usize, Vec<Vec<u8>>)> {
+    let mut parsed = 0;
+    let mut messages = vec![];
+    while let Some((header_len, message_len)) = parse_message_length(framing, &buf[parsed..], max)?
+    {
+        let total_read_size = header_len + message_len;
+        if buf.len() - parsed < total_read_size {
+            // we didn't have enough data for the complete message
+            break;
+        }
+        messages.push(buf[parsed + header_len..parsed + total_read_size].to_vec());
+        parsed += total_read_size;
     }
+    Ok((parsed, messages))
 }
 
 #[derive(Debug)]
 pub struct MessageStream<T: io::Read + io::Write> {
     stream: T,
+    framing: Framing,
+    /// Largest message we accept
+    max_read: usize,
+    /// Largest message the other end accepts
+    max_write: usize,
 
+    /// Bytes read but not yet parsed into messages. Never more than a message & a read's worth.
     read_buffer: Vec<u8>,
-    last_read: usize,
-    msg_start: usize,
-    msg_end: usize,
+    /// Framed messages the stream hasn't taken yet, written out in order
+    write_queue: VecDeque<u8>,
+    max_queued: usize,
 }
 
 impl<T: io::Read + io::Write> MessageStream<T> {
-    /// Consume a stream as a message stream. You should set options like no_delay, non_blocking
-    /// etc before passing or after via `inner()`. Can fail.
+    /// Consume a stream as a message stream, accepting messages up to `max_message_bytes`. Starts
+    /// on `Framing::V1` until both ends agree otherwise. You should set options like no_delay,
+    /// non_blocking etc before passing or after via `inner()`. Can fail.
     pub fn create(stream: T, max_message_bytes: usize) -> Result<Self> {
         Ok(Self {
             stream,
-            read_buffer: vec![0; HEADER_BYTES + max_message_bytes],
-            last_read: 0,
-            msg_start: 0,
-            msg_end: 0,
+            framing: Framing::V1,
+            max_read: max_message_bytes,
+            max_write: Framing::V1.max_message_bytes(),
+            read_buffer: Vec::new(),
+            write_queue: VecDeque::new(),
+            max_queued: DEFAULT_MAX_QUEUED_BYTES,
         })
     }
 
@@ -124,49 +203,99 @@ impl<T: io::Read + io::Write> MessageStream<T> {
         &mut self.stream
     }
 
-    /// Try to write all of what's buffered with a length prefix. Returns true if all of the buffer
-    /// was written, false if nothing was written. Errors on partial writes.
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Switch how messages are framed, once the other end knows to do the same
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Largest message we accept, for telling the other end
+    pub fn max_read(&self) -> usize {
+        self.max_read.min(self.framing.max_message_bytes())
+    }
+
+    /// Largest message the other end said it accepts
+    pub fn set_max_write(&mut self, max_write: usize) {
+        self.max_write = max_write;
+    }
+
+    /// Cap the bytes queued for writing, past which new messages are turned away
+    pub fn set_max_queued(&mut self, max_queued: usize) {
+        self.max_queued = max_queued;
+    }
+
+    /// Bytes waiting for the stream to take them
+    pub fn queued_bytes(&self) -> usize {
+        self.write_queue.len()
+    }
+
+    /// Queue `buffer` with a length prefix & write as much of the queue as the stream takes.
+    /// Returns true if the message was queued, false if the queue is too backed up to take it.
+    /// Errors on messages larger than the other end accepts.
     pub fn try_write_prefixed(&mut self, buffer: &[u8]) -> Result<bool> {
-        // messages headers are hard set at 2 bytes (i.e. u16)
-        let header_bytes = create_header(buffer);
-        match self.stream.write_all(&[&header_bytes, buffer].concat()) {
-            Ok(_) => Ok(true),
-            Err(ref err) if is_would_block(err) => Ok(false),
-            Err(ref err) if is_interrupted(err) => self.try_write_prefixed(buffer),
-            Err(err) => Err(err.into()),
+        let max = self.max_write.min(self.framing.max_message_bytes());
+        if buffer.len() > max {
+            return Err(MessageStreamError::MessageTooLarge {
+                size: buffer.len(),
+                max,
+            });
+        }
+        let header_bytes = create_header(self.framing, buffer);
+        let queued = self.write_queue.len() + header_bytes.len() + buffer.len();
+        // a message bigger than the whole queue still goes out alone
+        if !self.write_queue.is_empty() && queued > self.max_queued {
+            self.flush()?;
+            return Ok(false);
+        }
+        self.write_queue.extend(&header_bytes);
+        self.write_queue.extend(buffer);
+        self.flush()?;
+        Ok(true)
+    }
+
+    /// Write as much of the queue as the stream takes without blocking. Call when the stream
+    /// becomes writable again.
+    pub fn flush(&mut self) -> Result<()> {
+        while !self.write_queue.is_empty() {
+            // the queue's front may wrap around, the rest goes out on the next pass
+            match self.stream.write(self.write_queue.as_slices().0) {
+                Ok(0) => return Err(MessageStreamError::WriteMessageErr),
+                Ok(n) => {
+                    self.write_queue.drain(..n);
+                }
+                Err(ref err) if is_would_block(err) => break,
+                Err(ref err) if is_interrupted(err) => continue,
+                Err(err) => return Err(err.into()),
+            }
         }
+        Ok(())
     }
 
-    /// Returns all readable messages on the stream.
+    /// Returns all readable messages on the stream, reading until it would block.
     pub fn try_read_messages(&mut self) -> Result<Vec<Vec<u8>>> {
-        let messages = Vec::with_capacity(1);
-        match self.stream.read(&mut self.read_buffer[self.last_read..]) {
-            Ok(n) if n < 1 => Err(io::ErrorKind::UnexpectedEof.into()),
-            Ok(n) => {
-                self.last_read += n;
-                let (start, end, messages) = parse_messages(
-                    &self.read_buffer,
-                    self.msg_start,
-                    self.msg_end + n,
-                    messages,
-                )?;
-
-                // buffer full
-                if self.last_read >= self.read_buffer.len() {
-                    self.msg_start = 0;
-                    self.msg_end = end - start;
-                    self.last_read = self.msg_end;
-                    let (l, r) = self.read_buffer.split_at_mut(start);
-                    l[self.msg_start..self.msg_end].copy_from_slice(r);
-                } else {
-                    self.msg_start = start;
-                    self.msg_end = end;
+        let mut messages = Vec::with_capacity(1);
+        let mut chunk = [0; READ_CHUNK_BYTES];
+        loop {
+            match self.stream.read(&mut chunk) {
+                // hand over what arrived before the other end hung up, the next read reports it
+                Ok(0) if !messages.is_empty() => return Ok(messages),
+                Ok(n) if n < 1 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    // only grows past a chunk while a message is partway through arriving, and
+                    // lengths over `max_read` are rejected
+                    self.read_buffer.extend_from_slice(&chunk[..n]);
+                    let (parsed, mut read) =
+                        parse_messages(self.framing, &self.read_buffer, self.max_read)?;
+                    self.read_buffer.drain(..parsed);
+                    messages.append(&mut read);
                 }
-                Ok(messages)
+                Err(ref io_err) if is_would_block(io_err) => return Ok(messages),
+                Err(ref io_err) if is_interrupted(io_err) => continue,
+                Err(io_err) => return Err(io_err.into()),
             }
-            Err(ref io_err) if is_would_block(io_err) => Ok(messages),
-            Err(ref io_err) if is_interrupted(io_err) => self.try_read_messages(),
-            Err(io_err) => Err(io_err.into()),
         }
     }
 }
@@ -175,100 +304,206 @@ impl<T: io::Read + io::Write> MessageStream<T> {
 mod tests {
     use super::*;
 
+    /// Reads up to `available` of `data`, then would block, or hits the end if `closed`. Writes
+    /// go to `written` until it holds `capacity` bytes.
     pub struct FakeReader {
         data: Vec<u8>,
         pos: usize,
+        available: usize,
+        closed: bool,
+        written: Vec<u8>,
+        capacity: usize,
+    }
+    impl FakeReader {
+        fn new(data: Vec<u8>, available: usize) -> Self {
+            Self {
+                data,
+                pos: 0,
+                available,
+                closed: false,
+                written: vec![],
+                capacity: usize::MAX,
+            }
+        }
     }
+
     impl std::io::Read for FakeReader {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-            let mut read = 0;
-            for i in buf.iter_mut() {
-                if self.pos >= self.data.len() {
-                    return Ok(read);
-                }
-                *i = self.data[self.pos];
-                self.pos += 1;
-                read += 1;
+            let end = self.available.min(self.data.len());
+            if self.closed && self.pos >= self.data.len() {
+                return Ok(0);
+            }
+            if self.pos >= end {
+                return Err(io::ErrorKind::WouldBlock.into());
             }
+            let read = buf.len().min(end - self.pos);
+            buf[..read].copy_from_slice(&self.data[self.pos..self.pos + read]);
+            self.pos += read;
             Ok(read)
         }
     }
 
     impl std::io::Write for FakeReader {
-        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
-            panic!();
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.capacity - self.written.len());
+            if n == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
         }
 
         fn flush(&mut self) -> io::Result<()> {
-            panic!()
+            Ok(())
         }
     }
 
+    fn frame(framing: Framing, messages: &[&[u8]]) -> Vec<u8> {
+        messages
+            .iter()
+            .flat_map(|msg| [&create_header(framing, msg)[..], msg].concat())
+            .collect()
+    }
+
     #[test]
     fn test_buffer_shift() {
-        let data = vec![3_u8, 0, 1, 2, 3, 2_u8, 0, 9, 8, 3_u8, 0, 7, 6];
-        let reader = FakeReader {
-            data: data.clone(),
-            pos: 0,
-        };
+        let data = vec![3_u8, 0, 1, 2, 3, 2_u8, 0, 9, 8, 2_u8, 0, 7, 6];
+        let reader = FakeReader::new(data.clone(), 7);
 
         let mut message_stream = MessageStream::create(reader, 3).unwrap();
         let messages = message_stream.try_read_messages().unwrap();
-        assert_eq!(messages[0], data[2..5]);
+        assert_eq!(messages, vec![data[2..5].to_vec()]);
+        // only the partial message is kept around
+        assert_eq!(message_stream.read_buffer, data[5..7]);
+
+        message_stream.inner().available = 12;
+        let messages = message_stream.try_read_messages().unwrap();
+        assert_eq!(messages, vec![data[7..9].to_vec()]);
+        assert_eq!(message_stream.read_buffer, data[9..12]);
+
+        message_stream.inner().available = data.len();
         let messages = message_stream.try_read_messages().unwrap();
-        assert_eq!(messages[0], data[7..9]);
-        message_stream.try_read_messages().unwrap();
-        // buffer should have wrapped our data to the front
-        assert_eq!(message_stream.read_buffer[0..3], data[9..12]);
-        assert_eq!(message_stream.last_read, 4);
+        assert_eq!(messages, vec![data[11..13].to_vec()]);
+        assert!(message_stream.read_buffer.is_empty());
     }
 
     #[test]
     fn test_get_message_length() {
-        {
+        for framing in [Framing::V1, Framing::V2] {
             const SIZE: usize = 10;
-            let header = create_header(&[0; SIZE]);
-            let res = parse_message_length(&header, SIZE);
-            assert!(res.is_ok());
-            assert!(res.unwrap() == SIZE);
+            let header = create_header(framing, &[0; SIZE]);
+            let res = parse_message_length(framing, &header, SIZE).unwrap();
+            assert_eq!(res, Some((header.len(), SIZE)));
+
+            let header = create_header(framing, &[0; SIZE + 1]);
+            assert!(parse_message_length(framing, &header, SIZE).is_err());
+            let incomplete = &header[..header.len() - 1];
+            assert!(parse_message_length(framing, incomplete, SIZE)
+                .unwrap()
+                .is_none());
         }
-        {
-            const SIZE: usize = 15;
-            let header = create_header(&[0; SIZE + 1]);
-            let res = parse_message_length(&header, SIZE);
-            assert!(res.is_err());
+
+        for size in [1, 127, 128, 300, u16::MAX as usize + 1, 1 << 21] {
+            let header = create_header(Framing::V2, &vec![0; size]);
+            let res = parse_message_length(Framing::V2, &header, usize::MAX).unwrap();
+            assert_eq!(res, Some((header.len(), size)));
         }
+        assert_eq!(create_header(Framing::V2, &[0; 300]), vec![0xac, 0x02]);
+        // varints go on for at most 5 bytes
+        assert!(parse_message_length(Framing::V2, &[0xff; 6], usize::MAX).is_err());
     }
 
     #[test]
     fn test_read_complete_messages() {
-        let messages = ["123".as_bytes(), "abc".as_bytes(), "zxcb".as_bytes()];
-
-        let buf = messages
-            .iter()
-            .flat_map(|msg| {
-                let header = create_header(msg);
-                [&header[..], msg].concat()
-            })
-            .collect::<Vec<u8>>();
-
-        let (start, end, received) = parse_messages(&buf, 0, buf.len(), vec![]).unwrap();
-        assert!(start == buf.len() && end == buf.len());
-        for (i, recv) in received.iter().enumerate() {
-            assert_eq!(messages[i], recv);
+        let messages: [&[u8]; 3] = [b"123", b"abc", &[7; 200]];
+        for framing in [Framing::V1, Framing::V2] {
+            let buf = frame(framing, &messages);
+            let (parsed, received) = parse_messages(framing, &buf, 200).unwrap();
+            assert_eq!(parsed, buf.len());
+            assert_eq!(received, messages);
         }
     }
 
     #[test]
     fn test_read_incomplete_messages() {
-        let buf = [2_u8, 0, 1, 2, 3_u8, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0]; // missing one byte at the end
-        let expect_start = 4; // we should get back where the next partial message begins
-        let actual_bytes = 7; // simulate padded buffer
+        let buf = [2_u8, 0, 1, 2, 3_u8, 0, 1, 2]; // missing one byte at the end
+        let expect_parsed = 4; // we should get back where the next partial message begins
 
-        let (start, end, received) = parse_messages(&buf, 0, actual_bytes, vec![]).unwrap();
-        assert_eq!(start, expect_start);
-        assert_eq!(end, actual_bytes);
+        let (parsed, received) = parse_messages(Framing::V1, &buf, 16).unwrap();
+        assert_eq!(parsed, expect_parsed);
         assert!(received.len() == 1);
         assert!(received[0] == [1, 2]);
     }
+
+    #[test]
+    fn test_large_messages() {
+        let big = vec![1_u8; 100_000];
+        let mut message_stream =
+            MessageStream::create(FakeReader::new(vec![], 0), big.len()).unwrap();
+        // v1 lengths can't carry it
+        assert!(matches!(
+            message_stream.try_write_prefixed(&big),
+            Err(MessageStreamError::MessageTooLarge { .. })
+        ));
+
+        message_stream.set_framing(Framing::V2);
+        message_stream.set_max_write(big.len());
+        assert!(message_stream.try_write_prefixed(&big).unwrap());
+        message_stream.set_max_write(big.len() - 1);
+        assert!(matches!(
+            message_stream.try_write_prefixed(&big),
+            Err(MessageStreamError::MessageTooLarge {
+                size: 100_000,
+                max: 99_999
+            })
+        ));
+
+        // read back what we wrote, a chunk at a time
+        let written = std::mem::take(&mut message_stream.inner().written);
+        message_stream.inner().data = written;
+        for available in (0..big.len()).step_by(READ_CHUNK_BYTES * 3) {
+            message_stream.inner().available = available;
+            assert!(message_stream.try_read_messages().unwrap().is_empty());
+        }
+        message_stream.inner().available = usize::MAX;
+        assert_eq!(message_stream.try_read_messages().unwrap(), vec![big]);
+    }
+
+    #[test]
+    fn test_partial_writes() {
+        let messages: [&[u8]; 3] = [b"first", &[5; 20], b"third"];
+        let mut reader = FakeReader::new(vec![], 0);
+        reader.capacity = 4;
+        let mut message_stream = MessageStream::create(reader, 64).unwrap();
+        message_stream.set_framing(Framing::V2);
+        message_stream.set_max_queued(24);
+
+        // the stream only takes part of the first message, the rest waits in the queue
+        assert!(message_stream.try_write_prefixed(messages[0]).unwrap());
+        assert_eq!(message_stream.queued_bytes(), 2);
+        assert!(message_stream.try_write_prefixed(messages[1]).unwrap());
+        // too backed up for more
+        assert!(!message_stream.try_write_prefixed(messages[2]).unwrap());
+
+        message_stream.inner().capacity = usize::MAX;
+        message_stream.flush().unwrap();
+        assert_eq!(message_stream.queued_bytes(), 0);
+        assert!(message_stream.try_write_prefixed(messages[2]).unwrap());
+
+        let written = std::mem::take(&mut message_stream.inner().written);
+        assert_eq!(written, frame(Framing::V2, &messages));
+    }
+
+    #[test]
+    fn test_read_before_close() {
+        let messages: [&[u8]; 2] = [b"rejected", b"bye"];
+        let data = frame(Framing::V2, &messages);
+        let mut reader = FakeReader::new(data.clone(), data.len());
+        reader.closed = true;
+        let mut message_stream = MessageStream::create(reader, 16).unwrap();
+        message_stream.set_framing(Framing::V2);
+
+        assert_eq!(message_stream.try_read_messages().unwrap(), messages);
+        assert!(message_stream.try_read_messages().is_err());
+    }
 }
@@ -0,0 +1,190 @@
+/*! What a client & server say to each other after the server header, before anything else.
+
+The client sends a `ClientHello`, and the server answers with a `ServerHandshake`: a `Welcome`
+once the client's player exists, or a `Rejection` before hanging up. */
+use super::ClientInfo;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+/// Protocol version this build speaks
+pub const PROTOCOL_VERSION: u16 = 3;
+/// Oldest protocol version this build still speaks
+pub const MIN_PROTOCOL_VERSION: u16 = 3;
+
+/// Optional parts of the protocol a client can ask for
+#[derive(Deserialize, Serialize, Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Features {
+    /// Packets & world states over UDP, see `datagram`
+    pub udp: bool,
+    /// Compressed messages. No server supports this yet.
+    pub compression: bool,
+}
+
+impl Features {
+    /// Features both `self` & `other` have
+    pub fn intersection(self, other: Self) -> Self {
+        Self {
+            udp: self.udp && other.udp,
+            compression: self.compression && other.compression,
+        }
+    }
+}
+
+/// First message a client sends
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClientHello {
+    /// Oldest & newest protocol versions the client speaks
+    pub min_version: u16,
+    pub max_version: u16,
+    /// Identifies the client's build in server logs
+    pub build_id: String,
+    /// Largest message the client accepts
+    pub max_message_bytes: u32,
+    pub features: Features,
+    pub password: Option<String>,
+}
+
+impl ClientHello {
+    /// Hello for this build's protocol versions
+    pub fn new(build_id: String, max_message_bytes: u32, features: Features) -> Self {
+        Self {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            build_id,
+            max_message_bytes,
+            features,
+            password: None,
+        }
+    }
+
+    pub fn with_password(mut self, password: Option<String>) -> Self {
+        self.password = password;
+        self
+    }
+
+    /// Newest version both the client & this build speak
+    pub fn negotiate_version(&self) -> Result<u16, Rejection> {
+        let version = self.max_version.min(PROTOCOL_VERSION);
+        if version < self.min_version.max(MIN_PROTOCOL_VERSION) {
+            return Err(Rejection::UnsupportedVersion {
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            });
+        }
+        Ok(version)
+    }
+}
+
+/// The server accepted the client & spawned its player
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+pub struct Welcome {
+    /// Protocol version spoken from here on
+    pub version: u16,
+    /// Server ticks per second
+    pub tick_rate: f64,
+    /// Identifies the client's session, and tags its datagrams
+    pub session: u64,
+    /// Requested features the server agreed to
+    pub features: Features,
+    /// Port to send datagrams to, if `features.udp`
+    pub udp_port: Option<u16>,
+    pub client_info: ClientInfo,
+}
+
+/// Why the server turned a client away
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// The client speaks none of the server's protocol versions
+    UnsupportedVersion {
+        min: u16,
+        max: u16,
+    },
+    BadPassword,
+    /// The client's hello couldn't be read
+    MalformedHello,
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion { min, max } if min == max => {
+                write!(f, "server only speaks protocol version {}", min)
+            }
+            Self::UnsupportedVersion { min, max } => {
+                write!(f, "server only speaks protocol versions {} to {}", min, max)
+            }
+            Self::BadPassword => {
+                write!(f, "wrong password")
+            }
+            Self::MalformedHello => {
+                write!(f, "malformed hello")
+            }
+        }
+    }
+}
+
+/// The server's answer to a `ClientHello`
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+pub enum ServerHandshake {
+    Welcome(Welcome),
+    Rejected(Rejection),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net;
+
+    #[test]
+    fn test_negotiate_version() {
+        let mut hello = ClientHello::new("test".into(), 1024, Features::default());
+        assert_eq!(hello.negotiate_version(), Ok(PROTOCOL_VERSION));
+
+        // newer clients that still speak our version get it
+        hello.max_version = PROTOCOL_VERSION + 2;
+        assert_eq!(hello.negotiate_version(), Ok(PROTOCOL_VERSION));
+
+        hello.min_version = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            hello.negotiate_version(),
+            Err(Rejection::UnsupportedVersion {
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION
+            })
+        );
+        hello.min_version = 0;
+        hello.max_version = MIN_PROTOCOL_VERSION - 1;
+        assert!(hello.negotiate_version().is_err());
+    }
+
+    #[test]
+    fn test_handshake_roundtrip() {
+        let requested = Features {
+            udp: true,
+            compression: true,
+        };
+        let supported = Features {
+            udp: true,
+            compression: false,
+        };
+        assert_eq!(
+            requested.intersection(supported),
+            Features {
+                udp: true,
+                compression: false
+            }
+        );
+
+        let hello =
+            ClientHello::new("test".into(), 1024, requested).with_password(Some("bob".into()));
+        let serialized = net::serialize(&hello).unwrap();
+        assert_eq!(net::deserialize::<ClientHello>(&serialized).unwrap(), hello);
+
+        let rejected = ServerHandshake::Rejected(Rejection::BadPassword);
+        let serialized = net::serialize(&rejected).unwrap();
+        assert_eq!(
+            net::deserialize::<ServerHandshake>(&serialized).unwrap(),
+            rejected
+        );
+    }
+}
@@ -1,19 +1,24 @@
+pub mod handshake;
 pub mod packet;
-use crate::shared;
+use crate::{combat_log, shared};
 use bevy_ecs::{entity::MapEntities, prelude::*, system::Command};
 use bevy_math::*;
 use bincode;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 pub type SerializationError = bincode::ErrorKind;
 
+/// How many past world states are kept to build & apply deltas from. Clients that last
+/// acknowledged an older state get a full snapshot.
+pub const SNAPSHOT_HISTORY: usize = 32;
+
 // we making it into the mental asylum with this one
 // this just generates our serializable entity state struct so we don't have 1000 fields and update/from implementations
 // could replace this with a proc_macro that so we can derive(stateable) impl or something too
 macro_rules! gen_state {
     ( $($t:ty, $field:ident),* ) => {
         /// State for an entity we care to replicate
-        #[derive(Default, Debug, Clone, Serialize, Deserialize)]
+        #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
         pub struct EntityState {
             $ ( pub $field: Option<$t> ),*
         }
@@ -23,6 +28,42 @@ macro_rules! gen_state {
                 $( self.$field = other.$field.or(self.$field);  )*
                 self
             }
+
+            /// Fields that changed from `baseline` to this state
+            pub fn delta(&self, baseline: &Self) -> EntityDelta {
+                EntityDelta {
+                    $( $field: (self.$field != baseline.$field).then(|| self.$field.clone()), )*
+                }
+            }
+        }
+
+        /// Changes to an entity's state. `None` fields are unchanged, `Some(None)` were removed.
+        #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub struct EntityDelta {
+            $ ( pub $field: Option<Option<$t>> ),*
+        }
+        impl EntityDelta {
+            /// Nothing changed
+            pub fn is_empty(&self) -> bool {
+                true $( && self.$field.is_none() )*
+            }
+
+            /// Apply these changes to `state`
+            pub fn apply_to(&self, state: &mut EntityState) {
+                $(
+                    if let Some(c) = &self.$field {
+                        state.$field = c.clone();
+                    }
+                )*
+            }
+        }
+        /// Every field of the state, so applying it replaces whatever was there
+        impl From<EntityState> for EntityDelta {
+            fn from(value: EntityState) -> Self {
+                Self {
+                    $( $field: Some(value.$field), )*
+                }
+            }
         }
         $ (
         impl From<$t> for EntityState {
@@ -34,21 +75,25 @@ macro_rules! gen_state {
             }
         })*
 
-        impl Command for AddEntityStateCommand {
-            /// Insert or update entity state components for the given entity
+        impl Command for ApplyEntityDeltaCommand {
+            /// Insert, update or remove the changed components of the given entity
             fn apply(self, world: &mut World) {
                 $(
-                    if let Some(c) = self.entity_state.$field {
-                        match world.get_mut::<$t>(self.entity) {
+                    match self.delta.$field {
+                        Some(Some(c)) => match world.get_mut::<$t>(self.entity) {
                             Some(mut ec) => {
                                 // important that we don't trigger change detection for velocity
                                 // etc
                                 ec.set_if_neq(c);
                             },
                             None => {
-                                world.get_entity_mut(self.entity).unwrap().insert(c.clone());
+                                world.get_entity_mut(self.entity).unwrap().insert(c);
                             }
-                        }
+                        },
+                        Some(None) if world.get::<$t>(self.entity).is_some() => {
+                            world.get_entity_mut(self.entity).unwrap().remove::<$t>();
+                        },
+                        _ => {},
                     }
                 )*
             }
@@ -78,24 +123,39 @@ macro_rules! state_map_entities {
                 )*
             }
         }
+        impl MapEntities for EntityDelta {
+            fn map_entities<M: EntityMapper>(&mut self, entity_mapper: &mut M) {
+                $(
+                    if let Some(Some(f)) = self.$field.as_mut() {
+                        f.map_entities(entity_mapper);
+                    }
+                )*
+            }
+        }
     }
 }
 
-pub struct AddEntityStateCommand {
+pub struct ApplyEntityDeltaCommand {
     pub entity: Entity,
-    pub entity_state: EntityState,
+    pub delta: EntityDelta,
 }
 
 // the actual net state for every entity
 gen_state!(
     shared::Health,
     health,
+    shared::Power,
+    power,
     shared::SpellCaster,
     spellcaster,
-    shared::Aura,
-    aura,
+    shared::Auras,
+    auras,
     shared::CastingSpell,
     casting_spell,
+    shared::ChannelingSpell,
+    channeling_spell,
+    shared::Cooldowns,
+    cooldowns,
     shared::Position,
     position,
     shared::Player,
@@ -103,13 +163,19 @@ gen_state!(
     shared::Name,
     name,
     shared::Velocity,
-    velocity
+    velocity,
+    shared::MovementSpeed,
+    movement_speed,
+    shared::Projectile,
+    projectile,
+    shared::Dead,
+    dead
 );
 
-state_map_entities!(aura);
+state_map_entities!(auras, casting_spell, channeling_spell);
 
 /// Maps a set of entities to their component state for network magic.
-#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
 pub struct WorldState {
     pub entity_state_map: HashMap<Entity, EntityState>,
 }
@@ -124,6 +190,93 @@ impl WorldState {
             self.entity_state_map.insert(key, new_state);
         }
     }
+
+    /// Snapshot of this state taken on `tick`, holding only what changed since the `baseline`
+    /// state & its tick. Without one, it's a full snapshot.
+    pub fn snapshot(&self, tick: u64, baseline: Option<(u64, &WorldState)>) -> Snapshot {
+        let (baseline_tick, baseline_map) = match baseline {
+            Some((baseline_tick, state)) => (Some(baseline_tick), &state.entity_state_map),
+            None => (None, &HashMap::new()),
+        };
+        let mut snapshot = Snapshot {
+            tick,
+            baseline: baseline_tick,
+            ..Default::default()
+        };
+        for (&entity, state) in self.entity_state_map.iter() {
+            let delta = match baseline_map.get(&entity) {
+                Some(baseline_state) => state.delta(baseline_state),
+                None => {
+                    snapshot.spawned.push(entity);
+                    state.clone().into()
+                }
+            };
+            if !delta.is_empty() {
+                snapshot.changes.insert(entity, delta);
+            }
+        }
+        snapshot.despawned = baseline_map
+            .keys()
+            .filter(|entity| !self.entity_state_map.contains_key(entity))
+            .copied()
+            .collect();
+        snapshot
+    }
+
+    /// The state `snapshot` was taken of, if this is its baseline
+    pub fn apply(&self, snapshot: &Snapshot) -> WorldState {
+        let mut state = self.clone();
+        for entity in snapshot.despawned.iter() {
+            state.entity_state_map.remove(entity);
+        }
+        for (&entity, delta) in snapshot.changes.iter() {
+            delta.apply_to(state.entity_state_map.entry(entity).or_default());
+        }
+        state
+    }
+}
+
+/// World state on a server tick, as changes from a `baseline` tick's state the client
+/// acknowledged. Full snapshots have no baseline.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub tick: u64,
+    pub baseline: Option<u64>,
+    /// Entities that didn't exist in the baseline
+    pub spawned: Vec<Entity>,
+    pub despawned: Vec<Entity>,
+    /// Changed state by entity, unchanged entities are left out
+    pub changes: HashMap<Entity, EntityDelta>,
+}
+
+/// The last `SNAPSHOT_HISTORY` world states, by tick
+#[derive(Resource, Debug, Default)]
+pub struct SnapshotHistory(VecDeque<(u64, WorldState)>);
+
+impl SnapshotHistory {
+    /// Record the state on `tick`, forgetting the oldest if full
+    pub fn push(&mut self, tick: u64, state: WorldState) {
+        if self.0.len() >= SNAPSHOT_HISTORY {
+            self.0.pop_front();
+        }
+        self.0.push_back((tick, state));
+    }
+
+    pub fn get(&self, tick: u64) -> Option<&WorldState> {
+        self.0
+            .iter()
+            .find(|(t, _)| *t == tick)
+            .map(|(_, state)| state)
+    }
+
+    /// Most recently recorded state & its tick
+    pub fn latest(&self) -> Option<(u64, &WorldState)> {
+        self.0.back().map(|(tick, state)| (*tick, state))
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
 }
 
 #[derive(Deserialize, Serialize, Eq, PartialEq, Hash, Debug, Copy, Clone)]
@@ -131,6 +284,20 @@ pub struct ClientInfo {
     pub you: Entity,
 }
 
+/// Messages sent from the server to a connected client after its `handshake::Welcome`
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub enum ServerMessage {
+    /// World state, tagged with the last input sequence the server processed for this client
+    WorldState { seq: u8, snapshot: Snapshot },
+    /// A cast this client requested was refused or stopped
+    CastRejected {
+        spell_id: shared::SpellID,
+        reason: shared::CastRejectedReason,
+    },
+    /// Everything that happened in combat since the last batch
+    CombatLog(Vec<combat_log::CombatLogEntry>),
+}
+
 pub fn serialize<T: Serialize>(data: &T) -> Result<Vec<u8>, SerializationError> {
     match bincode::serialize(data) {
         Ok(data) => Ok(data),
@@ -145,3 +312,121 @@ pub fn deserialize<'a, T: Deserialize<'a>>(data: &'a [u8]) -> Result<T, Serializ
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world_state(entities: &[(Entity, EntityState)]) -> WorldState {
+        let mut state = WorldState::default();
+        for (entity, entity_state) in entities.iter().cloned() {
+            state.update(entity, entity_state);
+        }
+        state
+    }
+
+    #[test]
+    fn test_snapshot_deltas() {
+        let (player, skele, bolt) = (
+            Entity::from_raw(1),
+            Entity::from_raw(2),
+            Entity::from_raw(3),
+        );
+        let mut casting = EntityState::from(shared::Health::new(100)).update(
+            shared::CastingSpell::new(
+                0.into(),
+                shared::SpellTarget::Entity(skele),
+                std::time::Duration::from_secs(1),
+            )
+            .into(),
+        );
+        casting.position = Some(shared::Position(Vec3::ZERO));
+        let baseline = world_state(&[
+            (player, casting.clone()),
+            (skele, shared::Health::new(25).into()),
+        ]);
+
+        // cast finishes, launching a bolt that kills the skeleton
+        let mut cast = casting.clone();
+        cast.casting_spell = None;
+        let state = world_state(&[(player, cast), (bolt, shared::Position(Vec3::ONE).into())]);
+
+        let snapshot = state.snapshot(5, Some((4, &baseline)));
+        assert_eq!(snapshot.baseline, Some(4));
+        assert_eq!(snapshot.spawned, vec![bolt]);
+        assert_eq!(snapshot.despawned, vec![skele]);
+        assert_eq!(
+            snapshot.changes[&player],
+            EntityDelta {
+                casting_spell: Some(None),
+                ..Default::default()
+            }
+        );
+        assert_eq!(baseline.apply(&snapshot), state);
+
+        // nothing changed, nothing sent
+        let snapshot = state.snapshot(6, Some((5, &state)));
+        assert!(snapshot.changes.is_empty() && snapshot.despawned.is_empty());
+
+        // full snapshots rebuild the state from nothing
+        let full = state.snapshot(6, None);
+        assert_eq!(full.baseline, None);
+        assert_eq!(full.changes.len(), 2);
+        assert_eq!(WorldState::default().apply(&full), state);
+    }
+
+    #[test]
+    fn test_map_entities() {
+        struct Offset;
+        impl EntityMapper for Offset {
+            fn map_entity(&mut self, entity: Entity) -> Entity {
+                Entity::from_raw(entity.index() + 100)
+            }
+        }
+        let (caster, target) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut delta = EntityDelta {
+            casting_spell: Some(Some(shared::CastingSpell::new(
+                0.into(),
+                target,
+                std::time::Duration::from_secs(1),
+            ))),
+            channeling_spell: Some(Some(shared::ChannelingSpell::new(
+                0.into(),
+                target,
+                std::time::Duration::from_secs(3),
+                std::time::Duration::from_secs(1),
+            ))),
+            ..Default::default()
+        };
+        let mut auras = shared::Auras::default();
+        auras.add(shared::Aura::new(
+            0.into(),
+            caster,
+            std::time::Duration::from_secs(1),
+            shared::AuraEffect::Stun,
+        ));
+        let mut state = EntityState::from(auras);
+
+        delta.map_entities(&mut Offset);
+        state.map_entities(&mut Offset);
+
+        let mapped = Entity::from_raw(102);
+        assert_eq!(
+            delta.casting_spell.unwrap().unwrap().target,
+            shared::SpellTarget::Entity(mapped)
+        );
+        assert_eq!(delta.channeling_spell.unwrap().unwrap().target, mapped);
+        let source = state.auras.unwrap().iter().next().unwrap().1.source;
+        assert_eq!(source, Entity::from_raw(101));
+    }
+
+    #[test]
+    fn test_snapshot_history() {
+        let mut history = SnapshotHistory::default();
+        for tick in 0..SNAPSHOT_HISTORY as u64 + 2 {
+            history.push(tick, WorldState::default());
+        }
+        assert!(history.get(1).is_none());
+        assert!(history.get(2).is_some());
+        assert_eq!(history.latest().unwrap().0, SNAPSHOT_HISTORY as u64 + 1);
+    }
+}
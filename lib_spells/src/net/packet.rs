@@ -1,3 +1,5 @@
+use crate::shared;
+use bevy_ecs::entity::Entity;
 use bevy_math::prelude::*;
 use std::fmt::{self, Display};
 use std::mem::size_of;
@@ -27,6 +29,8 @@ impl Packet {
         match self.command_data {
             PacketData::Noop => self.concat_with_header(&[0]),
             PacketData::Movement(dir) => self.concat_with_header(&[dir.0]),
+            PacketData::CastSpell(cast) => self.concat_with_header(&cast.to_bytes()),
+            PacketData::AckSnapshot(tick) => self.concat_with_header(&tick.to_le_bytes()),
         }
     }
 
@@ -55,6 +59,10 @@ impl Packet {
 #[repr(u8)]
 pub enum PacketType {
     Move,
+    CastSpell,
+    CancelCast,
+    /// Client applied the world state snapshot of a tick
+    AckSnapshot,
 }
 
 impl PacketType {
@@ -89,6 +97,8 @@ impl Display for InvalidPacketError {
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum PacketData {
     Movement(MovementDirection),
+    CastSpell(SpellCast),
+    AckSnapshot(u64),
     Noop,
 }
 
@@ -97,9 +107,87 @@ impl PacketData {
     fn parse(packet_type: PacketType, payload: &[u8]) -> Result<Self, InvalidPacketError> {
         match packet_type {
             PacketType::Move => Ok(PacketData::Movement(MovementDirection::try_from(payload)?)),
+            PacketType::CastSpell => Ok(PacketData::CastSpell(SpellCast::try_from(payload)?)),
+            PacketType::CancelCast => Ok(PacketData::Noop),
+            PacketType::AckSnapshot => {
+                let tick = payload
+                    .try_into()
+                    .map_err(|_| InvalidPacketError::ParseError)?;
+                Ok(PacketData::AckSnapshot(u64::from_le_bytes(tick)))
+            }
+        }
+    }
+}
+
+/// Request to cast `spell_id` at `target`. Entity targets are server entities.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SpellCast {
+    pub spell_id: shared::SpellID,
+    pub target: shared::SpellTarget,
+}
+
+const TARGET_ENTITY: u8 = 0;
+const TARGET_POINT: u8 = 1;
+const TARGET_SELF_RADIUS: u8 = 2;
+
+impl SpellCast {
+    /// u64 spell ID, then a target tag byte & the target's data
+    fn to_bytes(self) -> Vec<u8> {
+        let spell_bytes = (self.spell_id.get() as u64).to_le_bytes();
+        let target_bytes = match self.target {
+            shared::SpellTarget::Entity(entity) => {
+                [&[TARGET_ENTITY][..], &entity.to_bits().to_le_bytes()].concat()
+            }
+            shared::SpellTarget::Point(point) => [
+                &[TARGET_POINT][..],
+                &point.x.to_le_bytes(),
+                &point.y.to_le_bytes(),
+                &point.z.to_le_bytes(),
+            ]
+            .concat(),
+            shared::SpellTarget::SelfRadius => vec![TARGET_SELF_RADIUS],
+        };
+        [&spell_bytes[..], &target_bytes[..]].concat()
+    }
+}
+
+impl TryFrom<&[u8]> for SpellCast {
+    type Error = InvalidPacketError;
+    /// Produce a spell cast from a payload.
+    fn try_from(payload: &[u8]) -> Result<Self, Self::Error> {
+        if payload.len() < size_of::<u64>() + 1 {
+            return Err(InvalidPacketError::ParseError);
         }
+        let (spell_id, target) = payload.split_at(size_of::<u64>());
+        let spell_id = u64::from_le_bytes(spell_id.try_into().unwrap()) as usize;
+        let (tag, target) = target.split_first().unwrap();
+        let target = match (*tag, target.len()) {
+            (TARGET_ENTITY, 8) => {
+                let bits = u64::from_le_bytes(target.try_into().unwrap());
+                let entity =
+                    Entity::try_from_bits(bits).map_err(|_| InvalidPacketError::ParseError)?;
+                shared::SpellTarget::Entity(entity)
+            }
+            (TARGET_POINT, 12) => {
+                let mut coords = target
+                    .chunks_exact(size_of::<f32>())
+                    .map(|c| f32::from_le_bytes(c.try_into().unwrap()));
+                shared::SpellTarget::Point(Vec3::new(
+                    coords.next().unwrap(),
+                    coords.next().unwrap(),
+                    coords.next().unwrap(),
+                ))
+            }
+            (TARGET_SELF_RADIUS, 0) => shared::SpellTarget::SelfRadius,
+            _ => return Err(InvalidPacketError::ParseError),
+        };
+        Ok(SpellCast {
+            spell_id: spell_id.into(),
+            target,
+        })
     }
 }
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct MovementDirection(pub u8);
 pub const MOVE_NONE: u8 = 0b00000000;
@@ -192,6 +280,47 @@ mod tests {
         let deserialized = Packet::deserialize(&serialized).unwrap();
         assert_eq!(packet, deserialized);
         assert!(Packet::deserialize(&[0, 0, 2, 4, 0]).is_err());
+
+        let packet = Packet {
+            timestamp: Duration::from_millis(100),
+            seq: 0,
+            command_type: PacketType::AckSnapshot,
+            command_data: PacketData::AckSnapshot(u64::MAX - 3),
+        };
+        let serialized = packet.serialize();
+        assert_eq!(Packet::deserialize(&serialized).unwrap(), packet);
+        assert!(Packet::deserialize(&serialized[..serialized.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_cast_packet_serialization() {
+        let targets = [
+            shared::SpellTarget::Entity(Entity::from_raw(12)),
+            shared::SpellTarget::Point(Vec3::new(1.5, -2.0, 30.25)),
+            shared::SpellTarget::SelfRadius,
+        ];
+        for target in targets {
+            let packet = Packet {
+                timestamp: Duration::from_millis(100),
+                seq: 0,
+                command_type: PacketType::CastSpell,
+                command_data: PacketData::CastSpell(SpellCast {
+                    spell_id: 2.into(),
+                    target,
+                }),
+            };
+            let deserialized = Packet::deserialize(&packet.serialize()).unwrap();
+            assert_eq!(packet, deserialized);
+        }
+
+        let packet = Packet {
+            timestamp: Duration::from_millis(100),
+            seq: 0,
+            command_type: PacketType::CancelCast,
+            command_data: PacketData::Noop,
+        };
+        let deserialized = Packet::deserialize(&packet.serialize()).unwrap();
+        assert_eq!(packet, deserialized);
     }
 
     #[test]
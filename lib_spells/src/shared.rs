@@ -1,4 +1,5 @@
 use core::fmt;
+use std::collections::HashMap;
 use std::time::Duration;
 use bincode;
 use serde::{self, Deserialize, Serialize};
@@ -8,31 +9,272 @@ use bevy_math::prelude::*;
 
 pub type SerializationError = bincode::ErrorKind;
 
-/// Entity can be harmed and healed
+/// Entity can be harmed and healed, up to `max`
 #[derive(Deserialize, Serialize, Component, Debug, PartialEq, Copy, Clone)]
-pub struct Health(pub i64);
+pub struct Health {
+    pub current: i64,
+    pub max: i64,
+}
+
+impl Health {
+    /// Full health
+    pub fn new(max: i64) -> Self {
+        Self { current: max, max }
+    }
+
+    /// Apply a health effect, negative to deal damage. Healing stops at max health, returning
+    /// how much of it was overhealing.
+    pub fn apply(&mut self, effect: i64) -> i64 {
+        if effect <= 0 {
+            self.current += effect;
+            return 0;
+        }
+        let healed = effect.min((self.max - self.current).max(0));
+        self.current += healed;
+        effect - healed
+    }
+}
+
+/// Resource spent to cast spells, e.g. mana or energy
+#[derive(Deserialize, Serialize, Component, Debug, PartialEq, Copy, Clone)]
+pub struct Power {
+    pub current: f32,
+    pub max: f32,
+    pub regen_per_sec: f32,
+}
+
+impl Power {
+    /// Full power
+    pub fn new(max: f32, regen_per_sec: f32) -> Self {
+        Self {
+            current: max,
+            max,
+            regen_per_sec,
+        }
+    }
+
+    pub fn can_afford(&self, cost: f32) -> bool {
+        self.current >= cost
+    }
+
+    /// Regenerate power over `delta`, up to max
+    pub fn regen(&mut self, delta: Duration) {
+        self.current = (self.current + self.regen_per_sec * delta.as_secs_f32()).min(self.max);
+    }
+}
+
+/// Most auras a unit can have at once
+pub const MAX_AURA_SLOTS: usize = 40;
+
+/// What an aura does while it's up
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub enum AuraEffect {
+    /// Applies its health effect every time the timer repeats
+    TickingHP(Timer),
+    /// Absorbs this much damage
+    Shield(i64),
+    /// Can't move or cast
+    Stun,
+    /// Can't cast
+    Silence,
+    /// Can't move
+    Root,
+    /// Movement speed changed by the aura's percentage
+    MoveSpeed,
+    /// Damage taken changed by the aura's percentage
+    DamageTaken,
+    /// Damage done changed by the aura's percentage
+    DamageDone,
+}
 
-/// Represents one aura belonging to the parent of this entity
-#[derive(Deserialize, Serialize, Component, Debug, Clone, PartialEq)]
+impl AuraEffect {
+    pub fn aura_type(&self) -> AuraType {
+        match self {
+            Self::TickingHP(_) => AuraType::TickingHP,
+            Self::Shield(_) => AuraType::Shield,
+            Self::Stun => AuraType::Stun,
+            Self::Silence => AuraType::Silence,
+            Self::Root => AuraType::Root,
+            Self::MoveSpeed => AuraType::MoveSpeed,
+            Self::DamageTaken => AuraType::DamageTaken,
+            Self::DamageDone => AuraType::DamageDone,
+        }
+    }
+}
+
+/// One aura instance held in a unit's `Auras`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Aura {
     pub id: AuraID,
     pub duration: Timer,
-    pub owner: Entity,
+    pub effect: AuraEffect,
+    /// Effects are multiplied by stacks
+    pub stacks: u8,
+    /// Whoever applied the aura. Kept after they're gone so its effects can still be credited.
+    pub source: Entity,
 }
 
-impl MapEntities for Aura {
-    fn map_entities<M: EntityMapper>(&mut self, entity_mapper: &mut M) {
-        let new_entity = entity_mapper.map_entity(self.owner);
-        self.owner = new_entity;
+impl Aura {
+    pub fn new(id: AuraID, source: Entity, duration: Duration, effect: AuraEffect) -> Self {
+        Self {
+            id,
+            duration: Timer::new(duration, bevy_time::TimerMode::Once),
+            effect,
+            stacks: 1,
+            source,
+        }
     }
-}
 
-impl Aura {
     pub fn get_remaining_time(&self) -> Duration {
         self.duration.duration() - self.duration.elapsed()
     }
 }
 
+/// Index of an aura in a unit's `Auras`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuraSlot(pub u8);
+
+/// Every aura on a unit, by slot
+#[derive(Deserialize, Serialize, Component, Debug, Clone, PartialEq, Default)]
+pub struct Auras {
+    slots: Vec<Option<Aura>>,
+}
+
+impl MapEntities for Auras {
+    fn map_entities<M: EntityMapper>(&mut self, entity_mapper: &mut M) {
+        for aura in self.slots.iter_mut().flatten() {
+            aura.source = entity_mapper.map_entity(aura.source);
+        }
+    }
+}
+
+impl Auras {
+    /// Put `aura` in the first free slot, returning `None` if they're all taken
+    pub fn add(&mut self, aura: Aura) -> Option<AuraSlot> {
+        let index = match self.slots.iter().position(Option::is_none) {
+            Some(index) => index,
+            None if self.slots.len() < MAX_AURA_SLOTS => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+            None => return None,
+        };
+        self.slots[index] = Some(aura);
+        Some(AuraSlot(index as u8))
+    }
+
+    pub fn get(&self, slot: AuraSlot) -> Option<&Aura> {
+        self.slots.get(slot.0 as usize).and_then(Option::as_ref)
+    }
+
+    pub fn get_mut(&mut self, slot: AuraSlot) -> Option<&mut Aura> {
+        self.slots.get_mut(slot.0 as usize).and_then(Option::as_mut)
+    }
+
+    pub fn remove(&mut self, slot: AuraSlot) -> Option<Aura> {
+        let aura = self.slots.get_mut(slot.0 as usize).and_then(Option::take);
+        while self.slots.last().is_some_and(Option::is_none) {
+            self.slots.pop();
+        }
+        aura
+    }
+
+    /// First slot holding an instance of `id`
+    pub fn find(&self, id: AuraID) -> Option<AuraSlot> {
+        self.iter()
+            .find(|(_, aura)| aura.id == id)
+            .map(|(slot, _)| slot)
+    }
+
+    /// Advance every aura's duration & effect timers by `delta`
+    pub fn tick(&mut self, delta: Duration) {
+        for aura in self.slots.iter_mut().flatten() {
+            aura.duration.tick(delta);
+            if let AuraEffect::TickingHP(timer) = &mut aura.effect {
+                timer.tick(delta);
+            }
+        }
+    }
+
+    /// Drop & return every aura that has run out
+    pub fn remove_expired(&mut self) -> Vec<Aura> {
+        let expired: Vec<AuraSlot> = self
+            .iter()
+            .filter(|(_, aura)| aura.duration.finished())
+            .map(|(slot, _)| slot)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|slot| self.remove(slot))
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (AuraSlot, &Aura)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, aura)| Some((AuraSlot(index as u8), aura.as_ref()?)))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (AuraSlot, &mut Aura)> {
+        self.slots
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, aura)| Some((AuraSlot(index as u8), aura.as_mut()?)))
+    }
+
+    pub fn iter_of_type(&self, aura_type: AuraType) -> impl Iterator<Item = (AuraSlot, &Aura)> {
+        self.iter()
+            .filter(move |(_, aura)| aura.effect.aura_type() == aura_type)
+    }
+
+    pub fn iter_of_type_mut(
+        &mut self,
+        aura_type: AuraType,
+    ) -> impl Iterator<Item = (AuraSlot, &mut Aura)> {
+        self.iter_mut()
+            .filter(move |(_, aura)| aura.effect.aura_type() == aura_type)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn has_type(&self, aura_type: AuraType) -> bool {
+        self.iter_of_type(aura_type).next().is_some()
+    }
+
+    /// Percentage to scale a stat by, 100 plus every `aura_type` modifier's percentage scaled by
+    /// stacks. Never below 0, however far modifiers stack. Percentages live in aura data, so
+    /// `percent_of` looks them up by ID.
+    pub fn percent_factor(&self, aura_type: AuraType, percent_of: impl Fn(AuraID) -> i64) -> i64 {
+        let total = self
+            .iter_of_type(aura_type)
+            .map(|(_, aura)| percent_of(aura.id) * aura.stacks as i64)
+            .sum::<i64>();
+        (100 + total).max(0)
+    }
+
+    /// Multiplier for movement speed, zero if the unit can't move
+    pub fn movement_multiplier(&self, percent_of: impl Fn(AuraID) -> i64) -> f32 {
+        if self.has_type(AuraType::Stun) || self.has_type(AuraType::Root) {
+            return 0.0;
+        }
+        self.percent_factor(AuraType::MoveSpeed, percent_of) as f32 / 100.0
+    }
+
+    /// Why the unit can't cast right now, if it can't
+    pub fn cast_blocker(&self) -> Option<CastRejectedReason> {
+        if self.has_type(AuraType::Stun) {
+            Some(CastRejectedReason::Stunned)
+        } else if self.has_type(AuraType::Silence) {
+            Some(CastRejectedReason::Silenced)
+        } else {
+            None
+        }
+    }
+}
+
 /// Used to look up an aura in the aura list resource
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AuraID(usize);
@@ -56,13 +298,20 @@ impl fmt::Display for AuraID {
 }
 
 /// Possible aura types
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AuraType {
     TickingHP,
     Shield,
+    Stun,
+    Silence,
+    Root,
+    MoveSpeed,
+    DamageTaken,
+    DamageDone,
 }
 
 /// We can use this to look up complex data about a spell
-#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct SpellID(usize);
 
 impl SpellID {
@@ -83,6 +332,69 @@ impl fmt::Display for SpellID {
     }
 }
 
+/// Kind of magic (or not) a spell uses
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SpellSchool {
+    #[default]
+    Physical,
+    Fire,
+    Frost,
+    Arcane,
+    Nature,
+    Holy,
+    Shadow,
+}
+
+/// How much of each school's damage the unit shrugs off, as a percentage. Capped at 100%.
+#[derive(Debug, Component, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Resistances(pub HashMap<SpellSchool, i64>);
+
+impl Resistances {
+    pub fn with(mut self, school: SpellSchool, percent: i64) -> Self {
+        self.0.insert(school, percent);
+        self
+    }
+
+    /// Percentage of `school` damage resisted
+    pub fn get(&self, school: SpellSchool) -> i64 {
+        self.0.get(&school).copied().unwrap_or(0).clamp(0, 100)
+    }
+}
+
+/// What a spell is aimed at
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SpellTarget {
+    Entity(Entity),
+    /// A point on the ground
+    Point(Vec3),
+    /// Centred on the caster
+    SelfRadius,
+}
+
+impl SpellTarget {
+    /// The targeted entity, if this targets one
+    pub fn entity(self) -> Option<Entity> {
+        match self {
+            Self::Entity(entity) => Some(entity),
+            _ => None,
+        }
+    }
+}
+
+impl MapEntities for SpellTarget {
+    fn map_entities<M: EntityMapper>(&mut self, entity_mapper: &mut M) {
+        if let Self::Entity(entity) = self {
+            *entity = entity_mapper.map_entity(*entity);
+        }
+    }
+}
+
+impl From<Entity> for SpellTarget {
+    fn from(value: Entity) -> Self {
+        Self::Entity(value)
+    }
+}
+
 /// Unit can cast spells
 #[derive(Debug, Component, Copy, PartialEq, Clone, Serialize, Deserialize)]
 pub struct SpellCaster;
@@ -91,18 +403,172 @@ pub struct SpellCaster;
 #[derive(Debug, Component, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CastingSpell {
     pub spell_id: SpellID,
-    pub target: Entity,
+    pub target: SpellTarget,
     pub cast_timer: Timer,
 }
 
+impl MapEntities for CastingSpell {
+    fn map_entities<M: EntityMapper>(&mut self, entity_mapper: &mut M) {
+        self.target.map_entities(entity_mapper);
+    }
+}
+
 impl CastingSpell {
-    pub fn new(spell_id: SpellID, target: Entity, cast_time: Duration) -> CastingSpell {
+    pub fn new(
+        spell_id: SpellID,
+        target: impl Into<SpellTarget>,
+        cast_time: Duration,
+    ) -> CastingSpell {
         CastingSpell {
             spell_id,
-            target,
+            target: target.into(),
             cast_timer: Timer::new(cast_time, bevy_time::TimerMode::Once),
         }
     }
+
+    /// Set the cast back by `by`, adding to the time left. Can't go back before the start.
+    pub fn push_back(&mut self, by: Duration) {
+        let elapsed = self.cast_timer.elapsed().saturating_sub(by);
+        self.cast_timer.set_elapsed(elapsed);
+    }
+}
+
+/// Spells the unit can't cast yet. The global cooldown blocks every spell, school lockouts block
+/// every spell of that school.
+#[derive(Debug, Component, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Cooldowns {
+    pub global: Option<Timer>,
+    pub spells: HashMap<SpellID, Timer>,
+    pub schools: HashMap<SpellSchool, Timer>,
+}
+
+impl Cooldowns {
+    /// Start the global cooldown, unless it's zero
+    pub fn start_global(&mut self, duration: Duration) {
+        if !duration.is_zero() {
+            self.global = Some(Timer::new(duration, bevy_time::TimerMode::Once));
+        }
+    }
+
+    /// Start the cooldown for `spell_id`, unless it's zero
+    pub fn start(&mut self, spell_id: SpellID, duration: Duration) {
+        if !duration.is_zero() {
+            self.spells
+                .insert(spell_id, Timer::new(duration, bevy_time::TimerMode::Once));
+        }
+    }
+
+    /// Lock out every spell of `school`, keeping any longer lockout already running
+    pub fn lock_school(&mut self, school: SpellSchool, duration: Duration) {
+        if self
+            .schools
+            .get(&school)
+            .is_some_and(|t| t.remaining() >= duration)
+        {
+            return;
+        }
+        self.schools
+            .insert(school, Timer::new(duration, bevy_time::TimerMode::Once));
+    }
+
+    /// Advance every cooldown, dropping the ones that finished
+    pub fn tick(&mut self, delta: Duration) {
+        if self.global.as_mut().is_some_and(|t| t.tick(delta).finished()) {
+            self.global = None;
+        }
+        self.spells.retain(|_, t| !t.tick(delta).finished());
+        self.schools.retain(|_, t| !t.tick(delta).finished());
+    }
+
+    /// Time until `spell_id` of `school` can be cast, counting the global cooldown
+    pub fn remaining(&self, spell_id: SpellID, school: SpellSchool) -> Duration {
+        [
+            self.global.as_ref(),
+            self.spells.get(&spell_id),
+            self.schools.get(&school),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|t| t.remaining())
+        .max()
+        .unwrap_or_default()
+    }
+
+    pub fn is_ready(&self, spell_id: SpellID, school: SpellSchool) -> bool {
+        self.remaining(spell_id, school).is_zero()
+    }
+}
+
+/// Unit is channelling a spell, applying its effects to the target every tick until the
+/// channel runs out
+#[derive(Debug, Component, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelingSpell {
+    pub spell_id: SpellID,
+    pub target: Entity,
+    pub duration: Timer,
+    pub tick: Timer,
+}
+
+impl MapEntities for ChannelingSpell {
+    fn map_entities<M: EntityMapper>(&mut self, entity_mapper: &mut M) {
+        self.target = entity_mapper.map_entity(self.target);
+    }
+}
+
+impl ChannelingSpell {
+    pub fn new(
+        spell_id: SpellID,
+        target: Entity,
+        duration: Duration,
+        tick_interval: Duration,
+    ) -> ChannelingSpell {
+        ChannelingSpell {
+            spell_id,
+            target,
+            duration: Timer::new(duration, bevy_time::TimerMode::Once),
+            tick: Timer::new(tick_interval, bevy_time::TimerMode::Repeating),
+        }
+    }
+
+    /// Advance the channel, returning how many ticks of the spell should be applied
+    pub fn advance(&mut self, delta: Duration) -> u32 {
+        // don't tick past the end of the channel
+        let delta = delta.min(self.duration.remaining());
+        self.duration.tick(delta);
+        self.tick.tick(delta).times_finished_this_tick()
+    }
+}
+
+/// Why the server refused or stopped a cast
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CastRejectedReason {
+    InvalidTarget,
+    OutOfRange,
+    NotEnoughResource,
+    OnCooldown,
+    AlreadyCasting,
+    UnknownSpell,
+    TargetDead,
+    Stunned,
+    Silenced,
+    Dead,
+}
+
+impl fmt::Display for CastRejectedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidTarget => write!(f, "Invalid target"),
+            Self::OutOfRange => write!(f, "Out of range"),
+            Self::NotEnoughResource => write!(f, "Not enough resource"),
+            Self::OnCooldown => write!(f, "Not ready yet"),
+            Self::AlreadyCasting => write!(f, "Already casting"),
+            Self::UnknownSpell => write!(f, "Unknown spell"),
+            Self::TargetDead => write!(f, "Target is dead"),
+            Self::Stunned => write!(f, "Can't do that while stunned"),
+            Self::Silenced => write!(f, "Can't cast while silenced"),
+            Self::Dead => write!(f, "You are dead"),
+        }
+    }
 }
 
 /// Unit exists in world space.
@@ -113,9 +579,43 @@ pub struct Position(pub Vec3);
 #[derive(Debug, Default, PartialEq, Copy, Component, Clone, Serialize, Deserialize)]
 pub struct Velocity(pub Vec3);
 
+/// Unit's movement speed multiplier from its auras, so clients can predict movement without
+/// knowing aura data
+#[derive(Debug, PartialEq, Copy, Component, Clone, Serialize, Deserialize)]
+pub struct MovementSpeed(pub f32);
+
+impl Default for MovementSpeed {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+/// A spell in flight, carried along by its `Velocity`
+#[derive(Deserialize, Serialize, PartialEq, Copy, Clone, Component, Debug)]
+pub struct Projectile {
+    pub spell_id: SpellID,
+    pub speed: f32,
+}
+
 #[derive(Deserialize, Serialize, PartialEq, Default, Copy, Clone, Component, Debug)]
 pub struct Player;
 
+/// Unit is dead & can't move or cast until it respawns at `spawn_point`
+#[derive(Deserialize, Serialize, Clone, PartialEq, Component, Debug)]
+pub struct Dead {
+    pub respawn_timer: Timer,
+    pub spawn_point: Vec3,
+}
+
+impl Dead {
+    pub fn new(respawn_in: Duration, spawn_point: Vec3) -> Self {
+        Self {
+            respawn_timer: Timer::new(respawn_in, bevy_time::TimerMode::Once),
+            spawn_point,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, PartialEq, Component, Debug)]
 pub struct Name(pub String);
 
@@ -1,29 +1,111 @@
 use bevy::prelude::*;
 use lib_spells::shared;
+use serde::Deserialize;
 use std::time::Duration;
 
+/// What happens when an aura is applied to a unit that already has it
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum AuraStacking {
+    /// Reapplying restarts the aura
+    #[default]
+    Refresh,
+    /// One instance per unit, reapplying does nothing while it's up
+    UniquePerTarget,
+    /// One instance per caster on each unit, reapplying restarts the caster's own
+    UniquePerCaster,
+    /// Reapplying adds a stack up to `max` & restarts the aura. Effects scale with stacks.
+    Stack { max: u8 },
+    /// Reapplying restarts the aura, carrying over up to 30% of its duration from what was left
+    Pandemic,
+}
+
+/// Which dispels can remove an aura
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum DispelType {
+    Magic,
+    Curse,
+    Poison,
+    /// Can't be dispelled
+    #[default]
+    None,
+}
+
 /// Complex info about a status effect
 pub struct AuraData {
+    pub key: String,
     pub name: String,
     pub base_multiplier: i64,
     pub duration: Duration,
     pub status_type: shared::AuraType,
+    /// School of the aura's ticks
+    pub school: shared::SpellSchool,
+    pub stacking: AuraStacking,
+    pub dispel_type: DispelType,
+    /// Bad for whoever has it, e.g. a damage over time rather than a shield
+    pub harmful: bool,
+    /// Stripped from units when they die
+    pub remove_on_death: bool,
+    /// Holds the ID of an aura that was removed, so no other aura takes it
+    pub removed: bool,
 }
 
 impl AuraData {
     pub fn new(
+        key: String,
         name: String,
         base_multiplier: i64,
         duration: Duration,
         status_type: shared::AuraType,
     ) -> AuraData {
         AuraData {
+            key,
             name,
             base_multiplier,
             duration,
             status_type,
+            school: shared::SpellSchool::default(),
+            stacking: AuraStacking::default(),
+            dispel_type: DispelType::default(),
+            harmful: false,
+            remove_on_death: true,
+            removed: false,
         }
     }
+
+    /// Placeholder for a removed aura
+    pub fn tombstone(key: String) -> AuraData {
+        AuraData {
+            removed: true,
+            ..AuraData::new(
+                key,
+                String::new(),
+                0,
+                Duration::ZERO,
+                shared::AuraType::Shield,
+            )
+        }
+    }
+
+    pub fn with_stacking(mut self, stacking: AuraStacking) -> Self {
+        self.stacking = stacking;
+        self
+    }
+
+    pub fn with_school(mut self, school: shared::SpellSchool) -> Self {
+        self.school = school;
+        self
+    }
+
+    pub fn with_remove_on_death(mut self, remove_on_death: bool) -> Self {
+        self.remove_on_death = remove_on_death;
+        self
+    }
+
+    pub fn with_dispel(mut self, dispel_type: DispelType, harmful: bool) -> Self {
+        self.dispel_type = dispel_type;
+        self.harmful = harmful;
+        self
+    }
 }
 
 /// Maps aura IDs to aura data.
@@ -32,23 +114,12 @@ pub struct AurasAsset(pub Vec<AuraData>);
 
 impl AurasAsset {
     pub fn lookup(&self, aura_id: shared::AuraID) -> Option<&AuraData> {
-        self.0.get(aura_id.get())
+        self.0.get(aura_id.get()).filter(|a| !a.removed)
     }
-}
 
-pub(super) fn get_auras_resource() -> AurasAsset {
-    AurasAsset(vec![
-        AuraData::new(
-            "Immolated".into(),
-            -5,
-            Duration::from_secs(10),
-            shared::AuraType::TickingHP,
-        ),
-        AuraData::new(
-            "Arcane Shield".into(),
-            100,
-            Duration::from_secs(7),
-            shared::AuraType::Shield,
-        ),
-    ])
+    /// How much a modifier aura changes its stat by, as a percentage. Read when the aura's
+    /// effect is used rather than stored on it, so reloaded definitions take effect right away.
+    pub fn percent(&self, aura_id: shared::AuraID) -> i64 {
+        self.lookup(aura_id).map_or(0, |aura| aura.base_multiplier)
+    }
 }
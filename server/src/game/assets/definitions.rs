@@ -0,0 +1,411 @@
+/*! On-disk spell & aura definitions. These are loaded as assets and built into `SpellsAsset` &
+`AurasAsset`, with keys resolved to IDs. */
+use bevy::{
+    asset::{io::Reader, ron, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    reflect::TypePath,
+    utils::BoxedFuture,
+};
+use lib_spells::{alignment, shared};
+use serde::Deserialize;
+use std::{collections::HashMap, fmt::Display, time::Duration};
+
+use super::{
+    AreaOfEffect, AuraData, AuraStacking, AurasAsset, Dispel, DispelType, SpellData, SpellsAsset,
+    DEFAULT_CRIT_CHANCE, DEFAULT_GLOBAL_COOLDOWN, DEFAULT_SPELL_RANGE,
+};
+
+/// An aura as written in a definitions file
+#[derive(Deserialize, Debug, Clone)]
+pub struct AuraDefinition {
+    pub key: String,
+    pub name: String,
+    pub base_multiplier: i64,
+    pub duration_ms: u64,
+    pub aura_type: shared::AuraType,
+    #[serde(default)]
+    pub school: shared::SpellSchool,
+    #[serde(default)]
+    pub stacking: AuraStacking,
+    #[serde(default)]
+    pub dispel_type: DispelType,
+    #[serde(default)]
+    pub harmful: bool,
+    #[serde(default = "default_remove_on_death")]
+    pub remove_on_death: bool,
+}
+
+fn default_remove_on_death() -> bool {
+    true
+}
+
+/// A spell as written in a definitions file. Auras are referenced by key.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpellDefinition {
+    pub key: String,
+    pub name: String,
+    #[serde(default)]
+    pub cast_ms: u64,
+    #[serde(default)]
+    pub hostility: alignment::Hostility,
+    #[serde(default)]
+    pub target_health: Option<i64>,
+    #[serde(default)]
+    pub target_aura: Option<String>,
+    #[serde(default)]
+    pub range: Option<f32>,
+    #[serde(default)]
+    pub min_range: Option<f32>,
+    #[serde(default)]
+    pub cooldown_ms: u64,
+    #[serde(default)]
+    pub gcd_ms: Option<u64>,
+    #[serde(default)]
+    pub cost: f32,
+    #[serde(default)]
+    pub school: shared::SpellSchool,
+    #[serde(default)]
+    pub crit_chance: Option<f32>,
+    #[serde(default)]
+    pub interrupt_on_move: bool,
+    #[serde(default)]
+    pub pushback_on_damage: bool,
+    #[serde(default)]
+    pub interrupt_lockout_ms: Option<u64>,
+    #[serde(default)]
+    pub channel_ms: Option<u64>,
+    #[serde(default = "default_channel_tick_ms")]
+    pub channel_tick_ms: u64,
+    #[serde(default)]
+    pub area: Option<AreaOfEffect>,
+    #[serde(default)]
+    pub projectile_speed: Option<f32>,
+    #[serde(default)]
+    pub dispel: Option<Dispel>,
+}
+
+fn default_channel_tick_ms() -> u64 {
+    1000
+}
+
+/// One file of aura and/or spell definitions
+#[derive(Asset, TypePath, Deserialize, Debug, Default)]
+pub struct DefinitionsFile {
+    #[serde(default)]
+    pub auras: Vec<AuraDefinition>,
+    #[serde(default)]
+    pub spells: Vec<SpellDefinition>,
+}
+
+/// Every spell & aura key loaded so far, in ID order. A key keeps its ID across reloads, even
+/// while it's removed, so clients casting by ID keep casting the same spells.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct DefinitionIds {
+    spells: Vec<String>,
+    auras: Vec<String>,
+}
+
+/// ID of `key` in `keys`, appending it if it's new
+fn key_id(keys: &mut Vec<String>, key: &str) -> usize {
+    match keys.iter().position(|k| k == key) {
+        Some(id) => id,
+        None => {
+            keys.push(key.to_string());
+            keys.len() - 1
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DefinitionError {
+    DuplicateAura(String),
+    DuplicateSpell(String),
+    UnknownAura { spell: String, aura: String },
+}
+
+impl std::error::Error for DefinitionError {}
+
+impl Display for DefinitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DuplicateAura(key) => {
+                write!(f, "aura \"{}\" is defined more than once", key)
+            }
+            Self::DuplicateSpell(key) => {
+                write!(f, "spell \"{}\" is defined more than once", key)
+            }
+            Self::UnknownAura { spell, aura } => {
+                write!(
+                    f,
+                    "spell \"{}\" references unknown aura \"{}\"",
+                    spell, aura
+                )
+            }
+        }
+    }
+}
+
+/// Build validated spell & aura lists out of a set of definition files, along with the IDs to
+/// build from next time. Keys keep their IDs from `ids` & new keys are appended. Removed keys
+/// leave a tombstone, so no ID is ever reused.
+pub fn build_assets<'a>(
+    files: impl IntoIterator<Item = &'a DefinitionsFile>,
+    ids: &DefinitionIds,
+) -> Result<(SpellsAsset, AurasAsset, DefinitionIds), DefinitionError> {
+    let files: Vec<&DefinitionsFile> = files.into_iter().collect();
+    let mut ids = ids.clone();
+
+    let mut aura_ids: HashMap<&str, shared::AuraID> = HashMap::default();
+    let mut auras: Vec<Option<AuraData>> = vec![];
+    for def in files.iter().flat_map(|f| f.auras.iter()) {
+        let id = key_id(&mut ids.auras, &def.key);
+        if aura_ids.insert(&def.key, id.into()).is_some() {
+            return Err(DefinitionError::DuplicateAura(def.key.clone()));
+        }
+        auras.resize_with(auras.len().max(id + 1), || None);
+        auras[id] = Some(
+            AuraData::new(
+                def.key.clone(),
+                def.name.clone(),
+                def.base_multiplier,
+                Duration::from_millis(def.duration_ms),
+                def.aura_type,
+            )
+            .with_school(def.school)
+            .with_stacking(def.stacking)
+            .with_dispel(def.dispel_type, def.harmful)
+            .with_remove_on_death(def.remove_on_death),
+        );
+    }
+
+    let mut spell_keys: HashMap<&str, shared::SpellID> = HashMap::default();
+    let mut spells: Vec<Option<SpellData>> = vec![];
+    for def in files.iter().flat_map(|f| f.spells.iter()) {
+        let id = key_id(&mut ids.spells, &def.key);
+        if spell_keys.insert(&def.key, id.into()).is_some() {
+            return Err(DefinitionError::DuplicateSpell(def.key.clone()));
+        }
+        let mut spell = SpellData::new(def.name.clone(), def.cast_ms)
+            .with_range(def.range.unwrap_or(DEFAULT_SPELL_RANGE), def.min_range)
+            .with_cooldown(
+                Duration::from_millis(def.cooldown_ms),
+                def.gcd_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(DEFAULT_GLOBAL_COOLDOWN),
+            )
+            .with_cost(def.cost)
+            .with_school(def.school)
+            .with_crit_chance(def.crit_chance.unwrap_or(DEFAULT_CRIT_CHANCE));
+        spell.key = def.key.clone();
+        spell.interrupt_on_move = def.interrupt_on_move;
+        spell.pushback_on_damage = def.pushback_on_damage;
+        if let Some(lockout_ms) = def.interrupt_lockout_ms {
+            spell = spell.with_interrupt_lockout(Duration::from_millis(lockout_ms));
+        }
+        if let Some(channel_ms) = def.channel_ms {
+            spell = spell.with_channel(
+                Duration::from_millis(channel_ms),
+                Duration::from_millis(def.channel_tick_ms),
+            );
+        }
+        if let Some(area) = def.area {
+            spell = spell.with_area(area);
+        }
+        if let Some(speed) = def.projectile_speed {
+            spell = spell.with_projectile(speed);
+        }
+        if let Some(dispel) = def.dispel {
+            spell = spell.with_dispel(dispel);
+        }
+        if def.hostility == alignment::Hostility::Friendly {
+            spell = spell.mark_friendly();
+        }
+        if let Some(hp) = def.target_health {
+            spell = spell.with_target_hp(hp);
+        }
+        if let Some(aura_key) = &def.target_aura {
+            match aura_ids.get(aura_key.as_str()) {
+                Some(&aura_id) => spell = spell.with_target_aura(aura_id),
+                None => {
+                    return Err(DefinitionError::UnknownAura {
+                        spell: def.key.clone(),
+                        aura: aura_key.clone(),
+                    })
+                }
+            }
+        }
+        spells.resize_with(spells.len().max(id + 1), || None);
+        spells[id] = Some(spell);
+    }
+
+    spells.resize_with(ids.spells.len(), || None);
+    let spells = spells
+        .into_iter()
+        .zip(&ids.spells)
+        .map(|(spell, key)| spell.unwrap_or_else(|| SpellData::tombstone(key.clone())))
+        .collect();
+    auras.resize_with(ids.auras.len(), || None);
+    let auras = auras
+        .into_iter()
+        .zip(&ids.auras)
+        .map(|(aura, key)| aura.unwrap_or_else(|| AuraData::tombstone(key.clone())))
+        .collect();
+    Ok((SpellsAsset(spells), AurasAsset(auras), ids))
+}
+
+#[derive(Debug)]
+pub enum DefinitionsLoaderError {
+    IO(std::io::Error),
+    Ron(ron::error::SpannedError),
+    Json(serde_json::Error),
+}
+
+impl std::error::Error for DefinitionsLoaderError {}
+
+impl Display for DefinitionsLoaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IO(err) => {
+                write!(f, "io error: {}", err)
+            }
+            Self::Ron(err) => {
+                write!(f, "ron error: {}", err)
+            }
+            Self::Json(err) => {
+                write!(f, "json error: {}", err)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for DefinitionsLoaderError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+
+impl From<ron::error::SpannedError> for DefinitionsLoaderError {
+    fn from(value: ron::error::SpannedError) -> Self {
+        Self::Ron(value)
+    }
+}
+
+impl From<serde_json::Error> for DefinitionsLoaderError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Json(value)
+    }
+}
+
+/// Parses `.ron` and `.json` definition files
+#[derive(Default)]
+pub struct DefinitionsLoader;
+
+impl AssetLoader for DefinitionsLoader {
+    type Asset = DefinitionsFile;
+    type Settings = ();
+    type Error = DefinitionsLoaderError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+        Box::pin(async move {
+            let mut bytes = vec![];
+            reader.read_to_end(&mut bytes).await?;
+            let is_json = load_context
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == "json");
+            if is_json {
+                Ok(serde_json::from_slice(&bytes)?)
+            } else {
+                Ok(ron::de::from_bytes(&bytes)?)
+            }
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ron", "json"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_DEFINITIONS: &str = r#"(
+        auras: [
+            (key: "burning", name: "Burning", base_multiplier: -5, duration_ms: 10000, aura_type: TickingHP, dispel_type: Magic, harmful: true),
+        ],
+        spells: [
+            (key: "fire", name: "Fire", cast_ms: 1500, target_health: Some(-50), target_aura: Some("burning"), range: Some(30.0), min_range: Some(5.0)),
+            (key: "heal", name: "Heal", hostility: Friendly, target_health: Some(40), cooldown_ms: 8000, gcd_ms: Some(0), dispel: Some((harmful: true, max_auras: 1))),
+        ],
+    )"#;
+
+    #[test]
+    fn test_build_definitions() {
+        let file: DefinitionsFile = ron::de::from_str(TEST_DEFINITIONS).unwrap();
+        let (spells, auras, _) = build_assets([&file], &DefinitionIds::default()).unwrap();
+
+        let fire = spells.get_spell_data(0.into()).unwrap();
+        assert_eq!(fire.key, "fire");
+        assert_eq!(fire.cast_time, Duration::from_millis(1500));
+        assert_eq!(fire.target_aura_effect, Some(0.into()));
+        assert_eq!((fire.range, fire.min_range), (30.0, Some(5.0)));
+        let heal = spells.get_spell_data(1.into()).unwrap();
+        assert_eq!(heal.hostility, alignment::Hostility::Friendly);
+        assert_eq!(heal.range, DEFAULT_SPELL_RANGE);
+        assert_eq!(heal.cooldown, Duration::from_secs(8));
+        assert_eq!(heal.global_cooldown, Duration::ZERO);
+        assert_eq!(fire.global_cooldown, DEFAULT_GLOBAL_COOLDOWN);
+        let burning = auras.lookup(0.into()).unwrap();
+        assert_eq!(burning.key, "burning");
+        assert!(heal.dispel.unwrap().removes(burning));
+        assert!(fire.dispel.is_none());
+    }
+
+    #[test]
+    fn test_unknown_aura_reference() {
+        let mut file: DefinitionsFile = ron::de::from_str(TEST_DEFINITIONS).unwrap();
+        file.auras.clear();
+        assert!(matches!(
+            build_assets([&file], &DefinitionIds::default()),
+            Err(DefinitionError::UnknownAura { .. })
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let file: DefinitionsFile = ron::de::from_str(TEST_DEFINITIONS).unwrap();
+        assert!(matches!(
+            build_assets([&file, &file], &DefinitionIds::default()),
+            Err(DefinitionError::DuplicateAura(_))
+        ));
+    }
+
+    #[test]
+    fn test_stable_ids() {
+        let mut file: DefinitionsFile = ron::de::from_str(TEST_DEFINITIONS).unwrap();
+        let (_, _, ids) = build_assets([&file], &DefinitionIds::default()).unwrap();
+
+        // fire is gone & frost is new, heal keeps its ID
+        let fire = file.spells.remove(0);
+        let mut frost = fire.clone();
+        frost.key = "frost".into();
+        file.spells.push(frost);
+        let (spells, _, ids) = build_assets([&file], &ids).unwrap();
+        assert!(spells.get_spell_data(0.into()).is_none());
+        assert_eq!(spells.get_spell_data(1.into()).unwrap().key, "heal");
+        assert_eq!(spells.get_spell_data(2.into()).unwrap().key, "frost");
+
+        // a removed key coming back gets its old ID
+        file.spells.push(fire);
+        let (spells, auras, _) = build_assets([&file], &ids).unwrap();
+        assert_eq!(spells.get_spell_data(0.into()).unwrap().key, "fire");
+        assert_eq!(spells.0.len(), 3);
+        assert_eq!(auras.lookup(0.into()).unwrap().key, "burning");
+    }
+}
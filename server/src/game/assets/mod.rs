@@ -1,14 +1,378 @@
 mod auras;
+mod definitions;
 mod spells;
-use bevy::app::Plugin;
+use bevy::{app::AppExit, asset::LoadState, ecs::system::Command, log, prelude::*};
+use lib_spells::shared;
 
 pub use crate::game::assets::auras::*;
 pub use crate::game::assets::spells::*;
 
+/// Definition files loaded at startup, relative to the asset folder
+const DEFINITION_FILES: [&str; 2] = ["data/auras.ron", "data/spells.ron"];
+
+#[derive(Resource, Debug)]
+struct DefinitionHandles(Vec<Handle<definitions::DefinitionsFile>>);
+
+/// Spell & aura data is ready to be used
+pub fn definitions_loaded(
+    spells: Option<Res<SpellsAsset>>,
+    auras: Option<Res<AurasAsset>>,
+) -> bool {
+    spells.is_some() && auras.is_some()
+}
+
+fn sys_load_definitions(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let handles = DEFINITION_FILES
+        .iter()
+        .map(|path| asset_server.load(*path))
+        .collect();
+    commands.insert_resource(DefinitionHandles(handles));
+}
+
+/// Once every definition file is loaded, validate them and insert the spell & aura resources.
+/// Exits if the data is bad.
+fn sys_build_definitions(
+    mut commands: Commands,
+    handles: Res<DefinitionHandles>,
+    files: Res<Assets<definitions::DefinitionsFile>>,
+    asset_server: Res<AssetServer>,
+    mut exit_ev_w: EventWriter<AppExit>,
+) {
+    let mut loaded = vec![];
+    for handle in handles.0.iter() {
+        if asset_server.get_load_state(handle) == Some(LoadState::Failed) {
+            log::error!("failed to load definitions {:?}", handle.path());
+            exit_ev_w.send(AppExit);
+            return;
+        }
+        match files.get(handle) {
+            Some(file) => loaded.push(file),
+            None => return,
+        }
+    }
+
+    match definitions::build_assets(loaded, &definitions::DefinitionIds::default()) {
+        Ok((spells, auras, ids)) => {
+            log::info!("loaded {} spells, {} auras", spells.0.len(), auras.0.len());
+            for (id, aura) in auras.0.iter().enumerate() {
+                log::debug!("{} = {}", aura.key, shared::AuraID::from(id));
+            }
+            for (id, spell) in spells.0.iter().enumerate() {
+                log::debug!("{} = {}", spell.key, shared::SpellID::from(id));
+            }
+            commands.insert_resource(spells);
+            commands.insert_resource(auras);
+            commands.insert_resource(ids);
+        }
+        Err(err) => {
+            log::error!("invalid definitions: {}", err);
+            exit_ev_w.send(AppExit);
+        }
+    }
+}
+
+/// Rebuild definitions when any file changes on disk. Bad data is logged and the current
+/// definitions are kept.
+fn sys_reload_definitions(
+    mut commands: Commands,
+    mut asset_ev_r: EventReader<AssetEvent<definitions::DefinitionsFile>>,
+    handles: Res<DefinitionHandles>,
+    files: Res<Assets<definitions::DefinitionsFile>>,
+    ids: Res<definitions::DefinitionIds>,
+) {
+    let modified = asset_ev_r.read().any(|ev| match ev {
+        AssetEvent::Modified { id } => handles.0.iter().any(|h| h.id() == *id),
+        _ => false,
+    });
+    if !modified {
+        return;
+    }
+
+    let loaded: Option<Vec<&definitions::DefinitionsFile>> =
+        handles.0.iter().map(|h| files.get(h)).collect();
+    let loaded = match loaded {
+        Some(loaded) => loaded,
+        None => return,
+    };
+
+    match definitions::build_assets(loaded, &ids) {
+        Ok((spells, auras, ids)) => {
+            log::info!(
+                "reloaded {} spells, {} auras",
+                spells.0.len(),
+                auras.0.len()
+            );
+            commands.insert_resource(ids);
+            commands.add(SwapDefinitionsCommand { spells, auras });
+        }
+        Err(err) => {
+            log::error!("invalid definitions, keeping current data: {}", err);
+        }
+    }
+}
+
+/// Replace the spell & aura resources. IDs are stable across reloads, so casts, channels,
+/// projectiles, cooldowns & auras in the world keep theirs, and are cancelled if their
+/// definition was removed.
+pub struct SwapDefinitionsCommand {
+    pub spells: SpellsAsset,
+    pub auras: AurasAsset,
+}
+
+/// Cancel every `C` whose spell no longer exists
+fn cancel_removed_spells<C: Component>(
+    world: &mut World,
+    spells: &SpellsAsset,
+    what: &str,
+    spell_id: fn(&C) -> shared::SpellID,
+    cancel: fn(EntityWorldMut),
+) {
+    let mut query = world.query::<(Entity, &C)>();
+    let removed: Vec<(Entity, shared::SpellID)> = query
+        .iter(world)
+        .map(|(entity, component)| (entity, spell_id(component)))
+        .filter(|(_, id)| spells.get_spell_data(*id).is_none())
+        .collect();
+    for (entity, spell_id) in removed {
+        log::info!(
+            "{:?} {} cancelled, spell {} was removed",
+            entity,
+            what,
+            spell_id
+        );
+        cancel(world.entity_mut(entity));
+    }
+}
+
+impl Command for SwapDefinitionsCommand {
+    fn apply(self, world: &mut World) {
+        cancel_removed_spells::<shared::CastingSpell>(
+            world,
+            &self.spells,
+            "cast",
+            |casting| casting.spell_id,
+            |mut entity| {
+                entity.remove::<shared::CastingSpell>();
+            },
+        );
+        cancel_removed_spells::<shared::ChannelingSpell>(
+            world,
+            &self.spells,
+            "channel",
+            |channel| channel.spell_id,
+            |mut entity| {
+                entity.remove::<shared::ChannelingSpell>();
+            },
+        );
+        cancel_removed_spells::<shared::Projectile>(
+            world,
+            &self.spells,
+            "projectile",
+            |projectile| projectile.spell_id,
+            |entity| entity.despawn(),
+        );
+
+        let mut cooldowns_query = world.query::<&mut shared::Cooldowns>();
+        for mut cooldowns in cooldowns_query.iter_mut(world) {
+            // removed spells' cooldowns go with them
+            let spells = &self.spells;
+            cooldowns
+                .spells
+                .retain(|id, _| spells.get_spell_data(*id).is_some());
+        }
+
+        let mut auras_query = world.query::<(Entity, &mut shared::Auras)>();
+        for (entity, mut auras) in auras_query.iter_mut(world) {
+            let removed: Vec<_> = auras
+                .iter_mut()
+                .filter(|(_, aura)| self.auras.lookup(aura.id).is_none())
+                .map(|(slot, _)| slot)
+                .collect();
+            for slot in removed {
+                if let Some(aura) = auras.remove(slot) {
+                    log::info!("{:?} aura {} removed, it no longer exists", entity, aura.id);
+                }
+            }
+        }
+
+        world.insert_resource(self.spells);
+        world.insert_resource(self.auras);
+    }
+}
+
 pub struct AssetsPlugin;
 impl Plugin for AssetsPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
-        app.insert_resource(spells::get_spell_list_resource());
-        app.insert_resource(auras::get_auras_resource());
+        app.init_asset::<definitions::DefinitionsFile>();
+        app.init_asset_loader::<definitions::DefinitionsLoader>();
+        app.add_systems(Startup, sys_load_definitions);
+        app.add_systems(
+            Update,
+            (
+                sys_build_definitions.run_if(not(definitions_loaded)),
+                sys_reload_definitions.run_if(definitions_loaded),
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::app;
+    use std::time::Duration;
+
+    fn spells(keys: &[&str]) -> SpellsAsset {
+        SpellsAsset(
+            keys.iter()
+                .map(|key| {
+                    let mut spell = SpellData::new(key.to_string(), 0);
+                    spell.key = key.to_string();
+                    spell
+                })
+                .collect(),
+        )
+    }
+
+    fn auras(keys: &[&str]) -> AurasAsset {
+        AurasAsset(
+            keys.iter()
+                .map(|key| {
+                    AuraData::new(
+                        key.to_string(),
+                        key.to_string(),
+                        0,
+                        Duration::from_secs(1),
+                        shared::AuraType::Shield,
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_swap_definitions() {
+        let mut app = app::App::new();
+        app.insert_resource(spells(&["fire", "frost", "heal"]));
+        app.insert_resource(auras(&["burning", "chilled"]));
+
+        let casting_heal = app
+            .world
+            .spawn(shared::CastingSpell::new(
+                2.into(),
+                Entity::PLACEHOLDER,
+                Duration::from_secs(1),
+            ))
+            .id();
+        let casting_frost = app
+            .world
+            .spawn(shared::CastingSpell::new(
+                1.into(),
+                Entity::PLACEHOLDER,
+                Duration::from_secs(1),
+            ))
+            .id();
+        let mut target_auras = shared::Auras::default();
+        let burning = target_auras
+            .add(shared::Aura::new(
+                0.into(),
+                Entity::PLACEHOLDER,
+                Duration::from_secs(1),
+                shared::AuraEffect::Shield(5),
+            ))
+            .unwrap();
+        let chilled = target_auras
+            .add(shared::Aura::new(
+                1.into(),
+                Entity::PLACEHOLDER,
+                Duration::from_secs(1),
+                shared::AuraEffect::Shield(5),
+            ))
+            .unwrap();
+        let aura_target = app.world.spawn(target_auras).id();
+        let channeling_heal = app
+            .world
+            .spawn(shared::ChannelingSpell::new(
+                2.into(),
+                Entity::PLACEHOLDER,
+                Duration::from_secs(3),
+                Duration::from_secs(1),
+            ))
+            .id();
+        let channeling_frost = app
+            .world
+            .spawn(shared::ChannelingSpell::new(
+                1.into(),
+                Entity::PLACEHOLDER,
+                Duration::from_secs(3),
+                Duration::from_secs(1),
+            ))
+            .id();
+        let heal_projectile = app
+            .world
+            .spawn(shared::Projectile {
+                spell_id: 2.into(),
+                speed: 10.0,
+            })
+            .id();
+        let frost_projectile = app
+            .world
+            .spawn(shared::Projectile {
+                spell_id: 1.into(),
+                speed: 10.0,
+            })
+            .id();
+        let mut cooldowns = shared::Cooldowns::default();
+        for spell_id in 0..3 {
+            cooldowns.start(spell_id.into(), Duration::from_secs(10 + spell_id as u64));
+        }
+        let on_cooldown = app.world.spawn(cooldowns).id();
+
+        // frost & burning are gone, heal & chilled keep their IDs
+        let mut new_spells = spells(&["fire", "frost", "heal"]);
+        new_spells.0[1] = SpellData::tombstone("frost".into());
+        let mut new_auras = auras(&["burning", "chilled"]);
+        new_auras.0[0] = AuraData::tombstone("burning".into());
+        SwapDefinitionsCommand {
+            spells: new_spells,
+            auras: new_auras,
+        }
+        .apply(&mut app.world);
+
+        let heal = app.world.get::<shared::CastingSpell>(casting_heal).unwrap();
+        assert_eq!(heal.spell_id, 2.into());
+        assert!(app
+            .world
+            .get::<shared::CastingSpell>(casting_frost)
+            .is_none());
+        let target_auras = app.world.get::<shared::Auras>(aura_target).unwrap();
+        assert_eq!(target_auras.get(chilled).unwrap().id, 1.into());
+        assert!(target_auras.get(burning).is_none());
+        let channel = app
+            .world
+            .get::<shared::ChannelingSpell>(channeling_heal)
+            .unwrap();
+        assert_eq!(channel.spell_id, 2.into());
+        assert!(app
+            .world
+            .get::<shared::ChannelingSpell>(channeling_frost)
+            .is_none());
+        let projectile = app
+            .world
+            .get::<shared::Projectile>(heal_projectile)
+            .unwrap();
+        assert_eq!(projectile.spell_id, 2.into());
+        assert!(app.world.get_entity(frost_projectile).is_none());
+        let cooldowns = app.world.get::<shared::Cooldowns>(on_cooldown).unwrap();
+        assert_eq!(cooldowns.spells.len(), 2);
+        assert_eq!(
+            cooldowns.spells[&0.into()].remaining(),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            cooldowns.spells[&2.into()].remaining(),
+            Duration::from_secs(12)
+        );
+        assert!(!cooldowns.spells.contains_key(&1.into()));
     }
 }
@@ -1,16 +1,94 @@
 use bevy::ecs::system::Resource;
+use serde::Deserialize;
 use std::time::Duration;
 
 use lib_spells::{alignment, shared};
 
+use super::{AuraData, DispelType};
+
+/// Max range of spells that don't set one
+pub const DEFAULT_SPELL_RANGE: f32 = 40.0;
+/// Global cooldown triggered by spells that don't set one
+pub const DEFAULT_GLOBAL_COOLDOWN: Duration = Duration::from_millis(1500);
+/// Chance of critting for spells that don't set one
+pub const DEFAULT_CRIT_CHANCE: f32 = 0.05;
+
+/// Shape of an area spell. Cones point from the caster at the target with a full width of
+/// `angle` degrees.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum AreaShape {
+    Sphere { radius: f32 },
+    Cone { radius: f32, angle: f32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct AreaOfEffect {
+    pub shape: AreaShape,
+    /// Hit at most this many units, closest first
+    #[serde(default)]
+    pub max_targets: Option<usize>,
+}
+
+/// Auras a spell removes from each unit it hits, in slot order
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Dispel {
+    /// Remove harmful auras rather than helpful ones
+    pub harmful: bool,
+    /// Only remove auras of this type, any dispellable aura if unset
+    #[serde(default)]
+    pub dispel_type: Option<DispelType>,
+    pub max_auras: usize,
+    /// The caster takes the removed auras for itself
+    #[serde(default)]
+    pub steal: bool,
+}
+
+impl Dispel {
+    /// Can this dispel remove `aura_data`
+    pub fn removes(&self, aura_data: &AuraData) -> bool {
+        aura_data.dispel_type != DispelType::None
+            && aura_data.harmful == self.harmful
+            && self
+                .dispel_type
+                .is_none_or(|dispel_type| dispel_type == aura_data.dispel_type)
+    }
+}
+
 /// Database of spells data by `SpellID`
 #[derive(Default, Debug)]
 pub struct SpellData {
+    pub key: String,
     pub name: String,
     pub cast_time: Duration,
     pub hostility: alignment::Hostility,
     pub target_health_effect: Option<i64>,
     pub target_aura_effect: Option<shared::AuraID>,
+    pub range: f32,
+    pub min_range: Option<f32>,
+    pub cooldown: Duration,
+    pub global_cooldown: Duration,
+    pub cost: f32,
+    pub school: shared::SpellSchool,
+    /// Chance from 0 to 1 of the health effect critting
+    pub crit_chance: f32,
+    /// Moving cancels the cast
+    pub interrupt_on_move: bool,
+    /// Taking damage sets the cast back
+    pub pushback_on_damage: bool,
+    /// Landing this spell interrupts the target's cast & locks out its school
+    pub interrupt_lockout: Option<Duration>,
+    /// Once cast, channel the spell for this long instead of applying it once
+    pub channel_duration: Option<Duration>,
+    /// Time between applications while channelling
+    pub channel_tick: Duration,
+    /// Hit every valid unit in an area instead of a single target
+    pub area: Option<AreaOfEffect>,
+    /// Fly at the target at this speed instead of landing instantly
+    pub projectile_speed: Option<f32>,
+    /// Remove auras from the units hit
+    pub dispel: Option<Dispel>,
+    /// Holds the ID of a spell that was removed, so no other spell takes it
+    pub removed: bool,
 }
 
 impl SpellData {
@@ -18,6 +96,18 @@ impl SpellData {
         Self {
             name,
             cast_time: Duration::from_millis(cast_ms),
+            range: DEFAULT_SPELL_RANGE,
+            global_cooldown: DEFAULT_GLOBAL_COOLDOWN,
+            crit_chance: DEFAULT_CRIT_CHANCE,
+            ..Default::default()
+        }
+    }
+
+    /// Placeholder for a removed spell
+    pub fn tombstone(key: String) -> Self {
+        Self {
+            key,
+            removed: true,
             ..Default::default()
         }
     }
@@ -32,6 +122,64 @@ impl SpellData {
         self
     }
 
+    pub fn with_range(mut self, range: f32, min_range: Option<f32>) -> Self {
+        self.range = range;
+        self.min_range = min_range;
+        self
+    }
+
+    pub fn with_cooldown(mut self, cooldown: Duration, global_cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self.global_cooldown = global_cooldown;
+        self
+    }
+
+    pub fn with_cost(mut self, cost: f32) -> Self {
+        self.cost = cost;
+        self
+    }
+
+    pub fn with_school(mut self, school: shared::SpellSchool) -> Self {
+        self.school = school;
+        self
+    }
+
+    pub fn with_crit_chance(mut self, crit_chance: f32) -> Self {
+        self.crit_chance = crit_chance;
+        self
+    }
+
+    pub fn with_interrupt_lockout(mut self, lockout: Duration) -> Self {
+        self.interrupt_lockout = Some(lockout);
+        self
+    }
+
+    pub fn with_channel(mut self, duration: Duration, tick: Duration) -> Self {
+        self.channel_duration = Some(duration);
+        self.channel_tick = tick;
+        self
+    }
+
+    pub fn with_area(mut self, area: AreaOfEffect) -> Self {
+        self.area = Some(area);
+        self
+    }
+
+    pub fn with_projectile(mut self, speed: f32) -> Self {
+        self.projectile_speed = Some(speed);
+        self
+    }
+
+    pub fn with_dispel(mut self, dispel: Dispel) -> Self {
+        self.dispel = Some(dispel);
+        self
+    }
+
+    /// Is `distance` between the minimum & maximum range
+    pub fn in_range(&self, distance: f32) -> bool {
+        distance <= self.range && self.min_range.is_none_or(|min| distance >= min)
+    }
+
     pub fn mark_friendly(mut self) -> Self {
         self.hostility = alignment::Hostility::Friendly;
         self
@@ -43,18 +191,6 @@ pub struct SpellsAsset(pub Vec<SpellData>);
 
 impl SpellsAsset {
     pub fn get_spell_data(&self, id: shared::SpellID) -> Option<&SpellData> {
-        self.0.get(id.get())
+        self.0.get(id.get()).filter(|s| !s.removed)
     }
 }
-
-pub(super) fn get_spell_list_resource() -> SpellsAsset {
-    SpellsAsset(vec![
-        SpellData::new("Fire Ball".into(), 5500)
-            .with_target_hp(-50)
-            .with_target_aura(0.into()),
-        SpellData::new("Grand Heal".into(), 5500).with_target_hp(40),
-        SpellData::new("Arcane Barrier".into(), 0)
-            .with_target_aura(1.into())
-            .mark_friendly(),
-    ])
-}
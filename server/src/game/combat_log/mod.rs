@@ -0,0 +1,213 @@
+/*! Records the cast lifecycle into the combat log, and writes the log to disk as newline
+delimited JSON when asked to. */
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use bevy::{log, prelude::*};
+use lib_spells::{combat_log, shared};
+
+use crate::game::{events, ServerSets, ServerTick};
+
+/// Combat log file, one JSON entry per line
+#[derive(Resource)]
+pub struct CombatLogFile(BufWriter<File>);
+
+impl CombatLogFile {
+    /// Create or truncate the file at `path`
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self(BufWriter::new(File::create(path)?)))
+    }
+
+    fn write(&mut self, entries: &[combat_log::CombatLogEntry]) -> io::Result<()> {
+        for entry in entries {
+            serde_json::to_writer(&mut self.0, entry)?;
+            self.0.write_all(b"\n")?;
+        }
+        self.0.flush()
+    }
+}
+
+/// Log casts starting, succeeding & failing
+fn sys_log_casts(
+    started_query: Query<(Entity, &shared::CastingSpell), Added<shared::CastingSpell>>,
+    mut spell_app_ev_r: EventReader<events::SpellApplicationEvent>,
+    mut rejected_ev_r: EventReader<events::CastRejectedEvent>,
+    mut combat_log_ev_w: EventWriter<events::CombatLogEvent>,
+) {
+    for (caster, casting) in started_query.iter() {
+        combat_log_ev_w.send(events::CombatLogEvent {
+            origin: caster,
+            target: casting.target.entity().unwrap_or(caster),
+            event: combat_log::CombatEvent::CastStart {
+                spell_id: casting.spell_id,
+            },
+        });
+    }
+    for ev in spell_app_ev_r.read() {
+        combat_log_ev_w.send(events::CombatLogEvent {
+            origin: ev.origin,
+            target: ev.target.entity().unwrap_or(ev.origin),
+            event: combat_log::CombatEvent::CastSuccess {
+                spell_id: ev.spell_id,
+            },
+        });
+    }
+    for ev in rejected_ev_r.read() {
+        combat_log_ev_w.send(events::CombatLogEvent {
+            origin: ev.caster,
+            target: ev.caster,
+            event: combat_log::CombatEvent::CastFail {
+                spell_id: ev.spell_id,
+                reason: ev.reason,
+            },
+        });
+    }
+}
+
+/// Append this tick's entries to the combat log file. Stops logging if the file can't be written.
+fn sys_write_combat_log(
+    mut commands: Commands,
+    tick: Res<ServerTick>,
+    mut file: ResMut<CombatLogFile>,
+    mut combat_log_ev_r: EventReader<events::CombatLogEvent>,
+) {
+    let entries: Vec<combat_log::CombatLogEntry> =
+        combat_log_ev_r.read().map(|ev| ev.entry(tick.0)).collect();
+    if entries.is_empty() {
+        return;
+    }
+    if let Err(err) = file.write(&entries) {
+        log::error!("combat log write failed, no longer logging: {}", err);
+        commands.remove_resource::<CombatLogFile>();
+    }
+}
+
+pub struct CombatLogPlugin;
+
+impl Plugin for CombatLogPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app.add_systems(
+            FixedUpdate,
+            (
+                // between the sets, so every cast event of the tick is in before it's sent out
+                sys_log_casts
+                    .after(ServerSets::EffectApplication)
+                    .before(ServerSets::NetworkSend),
+                sys_write_combat_log
+                    .run_if(resource_exists::<CombatLogFile>)
+                    .in_set(ServerSets::NetworkSend),
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sys_log_casts, sys_write_combat_log, CombatLogFile};
+    use crate::game::{events, ServerTick};
+    use bevy::{app, ecs::event::Events, prelude::*};
+    use lib_spells::{combat_log, shared};
+    use std::time::Duration;
+
+    #[test]
+    fn test_log_casts() {
+        let mut app = app::App::new();
+        app.add_event::<events::SpellApplicationEvent>();
+        app.add_event::<events::CastRejectedEvent>();
+        app.add_event::<events::CombatLogEvent>();
+        app.add_systems(Update, sys_log_casts);
+        let target = app.world.spawn_empty().id();
+        let caster = app
+            .world
+            .spawn(shared::CastingSpell::new(
+                0.into(),
+                shared::SpellTarget::Entity(target),
+                Duration::from_secs(1),
+            ))
+            .id();
+        app.world.send_event(events::SpellApplicationEvent {
+            origin: caster,
+            target: shared::SpellTarget::SelfRadius,
+            spell_id: 1.into(),
+        });
+        app.world.send_event(events::CastRejectedEvent {
+            caster,
+            spell_id: 2.into(),
+            reason: shared::CastRejectedReason::OnCooldown,
+        });
+
+        app.update();
+        // the cast only starts once
+        app.update();
+
+        let log: Vec<(Entity, combat_log::CombatEvent)> = app
+            .world
+            .resource_mut::<Events<events::CombatLogEvent>>()
+            .drain()
+            .map(|ev| (ev.target, ev.event))
+            .collect();
+        assert_eq!(
+            log,
+            vec![
+                (
+                    target,
+                    combat_log::CombatEvent::CastStart { spell_id: 0.into() }
+                ),
+                (
+                    caster,
+                    combat_log::CombatEvent::CastSuccess { spell_id: 1.into() }
+                ),
+                (
+                    caster,
+                    combat_log::CombatEvent::CastFail {
+                        spell_id: 2.into(),
+                        reason: shared::CastRejectedReason::OnCooldown
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_combat_log() {
+        // unique to this run, so concurrent test runs don't write over each other
+        let path = std::env::temp_dir().join(format!(
+            "spells_test_combat_log_{}_{}.jsonl",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let mut app = app::App::new();
+        app.add_event::<events::CombatLogEvent>();
+        app.insert_resource(ServerTick(7));
+        app.insert_resource(CombatLogFile::create(&path).unwrap());
+        app.add_systems(Update, sys_write_combat_log);
+        for event in [
+            combat_log::CombatEvent::Death,
+            combat_log::CombatEvent::AuraRemoved { aura_id: 3.into() },
+        ] {
+            app.world.send_event(events::CombatLogEvent {
+                origin: Entity::PLACEHOLDER,
+                target: Entity::PLACEHOLDER,
+                event,
+            });
+        }
+
+        app.update();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let entries: Vec<combat_log::CombatLogEntry> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| entry.tick == 7));
+        assert_eq!(entries[0].event, combat_log::CombatEvent::Death);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,93 +1,386 @@
 use std::time::Duration;
 
 use crate::game::{assets, events};
-use bevy::prelude::*;
+use bevy::{log, prelude::*, utils::HashMap};
 
 use super::ServerSets;
-use lib_spells::shared;
-
-// todo: rewrite this shit
-// why are we even having auras as child entities? well you know why but
-// just use a hashmap to represent slots: Aura: HashMap<i8, Option<(AuraID, Timer)>>
-// use a trait like Aura { get_aura(slot), remove_aura(slot), tick_aura(slot) }
-// then write a proc macro to derive(aura) that implements the hashmap and functions
-// badabing badaboom
-// then you can write individual queries over all TickingHP auras and so on
+use lib_spells::{combat_log, shared};
 
 const TICK_RATE: Duration = Duration::from_millis(1000);
 
-/// The parent entity is shielded
-#[derive(Component)]
-pub struct ShieldAura(pub i64);
+/// A fresh instance of `aura_data` applied by `source`
+fn new_aura(aura_id: shared::AuraID, source: Entity, aura_data: &assets::AuraData) -> shared::Aura {
+    let effect = match aura_data.status_type {
+        shared::AuraType::TickingHP => {
+            shared::AuraEffect::TickingHP(Timer::new(TICK_RATE, TimerMode::Repeating))
+        }
+        shared::AuraType::Shield => shared::AuraEffect::Shield(aura_data.base_multiplier),
+        shared::AuraType::Stun => shared::AuraEffect::Stun,
+        shared::AuraType::Silence => shared::AuraEffect::Silence,
+        shared::AuraType::Root => shared::AuraEffect::Root,
+        shared::AuraType::MoveSpeed => shared::AuraEffect::MoveSpeed,
+        shared::AuraType::DamageTaken => shared::AuraEffect::DamageTaken,
+        shared::AuraType::DamageDone => shared::AuraEffect::DamageDone,
+    };
+    shared::Aura::new(aura_id, source, aura_data.duration, effect)
+}
 
-impl ShieldAura {
-    pub fn new(base_multiplier: i64) -> Self {
-        Self(base_multiplier)
-    }
+/// How much of an aura's duration pandemic reapplication can carry over
+const PANDEMIC_CARRY_OVER: f32 = 0.3;
+
+/// What applying an aura did
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AuraApplication {
+    Added(shared::AuraSlot),
+    /// The unit already had it & it was refreshed or stacked
+    Refreshed(shared::AuraSlot),
+    /// The unit already had it & it's unique
+    Unchanged(shared::AuraSlot),
 }
 
-/// The parent entity is ticking health
-#[derive(Component)]
-pub struct TickingEffectAura(pub Timer);
+impl AuraApplication {
+    fn slot(self) -> shared::AuraSlot {
+        match self {
+            Self::Added(slot) | Self::Refreshed(slot) | Self::Unchanged(slot) => slot,
+        }
+    }
+}
 
-impl TickingEffectAura {
-    pub fn new() -> Self {
-        TickingEffectAura(Timer::new(TICK_RATE, TimerMode::Repeating))
+/// Add `aura` to `auras`, following its stacking rules when the unit already has it. Returns
+/// `None` if there was no free slot.
+fn apply_aura(
+    auras: &mut shared::Auras,
+    aura: shared::Aura,
+    aura_data: &assets::AuraData,
+) -> Option<AuraApplication> {
+    let existing = match aura_data.stacking {
+        assets::AuraStacking::UniquePerCaster => auras
+            .iter()
+            .find(|(_, current)| current.id == aura.id && current.source == aura.source)
+            .map(|(slot, _)| slot),
+        _ => auras.find(aura.id),
+    };
+    let slot = match existing {
+        Some(slot) => slot,
+        None => return auras.add(aura).map(AuraApplication::Added),
+    };
+    let current = auras.get_mut(slot)?;
+    match aura_data.stacking {
+        assets::AuraStacking::UniquePerTarget => return Some(AuraApplication::Unchanged(slot)),
+        assets::AuraStacking::Refresh | assets::AuraStacking::UniquePerCaster => {
+            current.duration = aura.duration;
+            if let shared::AuraEffect::Shield(_) = aura.effect {
+                current.effect =
+                    shared::AuraEffect::Shield(aura_data.base_multiplier * current.stacks as i64);
+            }
+        }
+        assets::AuraStacking::Stack { max } => {
+            if current.stacks < max {
+                current.stacks += 1;
+                if let shared::AuraEffect::Shield(absorb) = &mut current.effect {
+                    *absorb += aura_data.base_multiplier;
+                }
+            }
+            current.duration = aura.duration;
+        }
+        assets::AuraStacking::Pandemic => {
+            let carried = current
+                .get_remaining_time()
+                .min(aura_data.duration.mul_f32(PANDEMIC_CARRY_OVER));
+            current.duration = Timer::new(aura_data.duration + carried, TimerMode::Once);
+        }
     }
+    current.source = aura.source;
+    Some(AuraApplication::Refreshed(slot))
+}
+
+/// Log what applying an aura to `target` did
+fn log_aura_application(
+    combat_log_ev_w: &mut EventWriter<events::CombatLogEvent>,
+    auras: &shared::Auras,
+    source: Entity,
+    target: Entity,
+    application: Option<AuraApplication>,
+) {
+    let aura = application.and_then(|application| auras.get(application.slot()));
+    let (aura_id, stacks) = match aura {
+        Some(aura) => (aura.id, aura.stacks),
+        None => {
+            log::debug!("{:?} has no free aura slots", target);
+            return;
+        }
+    };
+    let event = match application {
+        Some(AuraApplication::Added(_)) => combat_log::CombatEvent::AuraApplied { aura_id },
+        Some(AuraApplication::Refreshed(_)) => {
+            combat_log::CombatEvent::AuraRefreshed { aura_id, stacks }
+        }
+        _ => return,
+    };
+    combat_log_ev_w.send(events::CombatLogEvent {
+        origin: source,
+        target,
+        event,
+    });
 }
+
 /// Process an add aura event
 fn sys_add_aura_ev(
     mut ev_r: EventReader<events::AddAuraEvent>,
     mut commands: Commands,
     auras_asset: Res<assets::AurasAsset>,
+    mut auras_query: Query<&mut shared::Auras>,
+    dead_query: Query<(), With<shared::Dead>>,
+    mut combat_log_ev_w: EventWriter<events::CombatLogEvent>,
 ) {
+    // units getting their first auras this tick
+    let mut new_auras: HashMap<Entity, shared::Auras> = HashMap::default();
     for ev in ev_r.read() {
-        // look up status
-        if let Some(aura_data) = auras_asset.lookup(ev.aura_id) {
-            // spawn base aura
-            let base_entity = commands
-                .spawn(shared::Aura {
-                    id: ev.aura_id,
-                    duration: Timer::new(aura_data.duration, TimerMode::Once),
-                    owner: ev.target_entity,
-                })
-                .id();
-
-            // add aura types
-            match aura_data.status_type {
-                shared::AuraType::TickingHP => commands
-                    .entity(base_entity)
-                    .insert(TickingEffectAura::new()),
-                shared::AuraType::Shield => commands
-                    .entity(base_entity)
-                    .insert(ShieldAura::new(aura_data.base_multiplier)),
-            };
-
-            // parent
-            commands.entity(ev.target_entity).add_child(base_entity);
+        // corpses don't take auras, they could outlast the respawn
+        if dead_query.contains(ev.target_entity) {
+            continue;
         }
+        let aura_data = match auras_asset.lookup(ev.aura_id) {
+            Some(aura_data) => aura_data,
+            None => continue,
+        };
+        let aura = new_aura(ev.aura_id, ev.source, aura_data);
+        let mut target_auras = auras_query.get_mut(ev.target_entity);
+        let auras = match target_auras.as_deref_mut() {
+            Ok(auras) => auras,
+            Err(_) => new_auras.entry(ev.target_entity).or_default(),
+        };
+        let application = apply_aura(auras, aura, aura_data);
+        log_aura_application(
+            &mut combat_log_ev_w,
+            auras,
+            ev.source,
+            ev.target_entity,
+            application,
+        );
     }
+    for (entity, auras) in new_auras {
+        if let Some(mut target) = commands.get_entity(entity) {
+            target.insert(auras);
+        }
+    }
+}
+
+/// Remove the auras `dispel` matches, up to its limit
+fn dispel_auras(
+    auras: &mut shared::Auras,
+    dispel: &assets::Dispel,
+    auras_asset: &assets::AurasAsset,
+) -> Vec<shared::Aura> {
+    let slots: Vec<shared::AuraSlot> = auras
+        .iter()
+        .filter(|(_, aura)| {
+            auras_asset
+                .lookup(aura.id)
+                .is_some_and(|aura_data| dispel.removes(aura_data))
+        })
+        .map(|(slot, _)| slot)
+        .take(dispel.max_auras)
+        .collect();
+    slots
+        .into_iter()
+        .filter_map(|slot| auras.remove(slot))
+        .collect()
 }
 
-/// Process a remove aura event
+/// Process remove aura events, dropping one instance of the aura, then dispels. Stolen auras
+/// move to the origin with their remaining duration & stacks.
 fn sys_remove_aura_ev(
     mut ev_r: EventReader<events::RemoveAuraEvent>,
+    mut dispel_ev_r: EventReader<events::DispelEvent>,
     mut commands: Commands,
-    child_query: Query<&Children>,
-    status_effect_query: Query<&shared::Aura>,
+    auras_asset: Res<assets::AurasAsset>,
+    mut auras_query: Query<&mut shared::Auras>,
+    dead_query: Query<(), With<shared::Dead>>,
+    mut combat_log_ev_w: EventWriter<events::CombatLogEvent>,
 ) {
-    'event_processing: for ev in ev_r.read() {
-        // find children of entity
-        if let Ok(children) = child_query.get(ev.target_entity) {
-            for &child in children.iter() {
-                // for each child grab status
-                if let Ok(status) = status_effect_query.get(child) {
-                    if status.id == ev.aura_id {
-                        // drop one instance of this status
-                        commands.entity(child).despawn_recursive();
-                        continue 'event_processing;
-                    }
-                }
+    for ev in ev_r.read() {
+        if let Ok(mut auras) = auras_query.get_mut(ev.target_entity) {
+            if let Some(aura) = auras.find(ev.aura_id).and_then(|slot| auras.remove(slot)) {
+                combat_log_ev_w.send(events::CombatLogEvent {
+                    origin: ev.target_entity,
+                    target: ev.target_entity,
+                    event: combat_log::CombatEvent::AuraRemoved { aura_id: aura.id },
+                });
+            }
+        }
+    }
+
+    // thieves getting their first auras this tick
+    let mut new_auras: HashMap<Entity, shared::Auras> = HashMap::default();
+    for ev in dispel_ev_r.read() {
+        if dead_query.contains(ev.target_entity) {
+            continue;
+        }
+        let dispel = ev.dispel;
+        let removed = match auras_query.get_mut(ev.target_entity) {
+            Ok(mut auras) => dispel_auras(&mut auras, &dispel, &auras_asset),
+            Err(_) => continue,
+        };
+        for aura in removed.iter() {
+            log::debug!(
+                "{:?} aura {} dispelled by {:?}",
+                ev.target_entity,
+                aura.id,
+                ev.origin
+            );
+            combat_log_ev_w.send(events::CombatLogEvent {
+                origin: ev.origin,
+                target: ev.target_entity,
+                event: combat_log::CombatEvent::AuraRemoved { aura_id: aura.id },
+            });
+        }
+        if !dispel.steal || ev.origin == ev.target_entity {
+            continue;
+        }
+        for mut aura in removed {
+            let aura_data = match auras_asset.lookup(aura.id) {
+                Some(aura_data) => aura_data,
+                None => continue,
+            };
+            aura.source = ev.origin;
+            let mut thief_auras = auras_query.get_mut(ev.origin);
+            let auras = match thief_auras.as_deref_mut() {
+                Ok(auras) => auras,
+                Err(_) => new_auras.entry(ev.origin).or_default(),
+            };
+            let application = apply_aura(auras, aura, aura_data);
+            log_aura_application(
+                &mut combat_log_ev_w,
+                auras,
+                ev.origin,
+                ev.origin,
+                application,
+            );
+        }
+    }
+    for (entity, auras) in new_auras {
+        if let Some(mut thief) = commands.get_entity(entity) {
+            thief.insert(auras);
+        }
+    }
+}
+
+type CrowdControlledQuery = (
+    Entity,
+    &'static shared::Auras,
+    AnyOf<(
+        &'static shared::CastingSpell,
+        &'static shared::ChannelingSpell,
+    )>,
+);
+
+/// Stop the casts & channels of units that were just stunned or silenced
+fn sys_crowd_control_casts(
+    mut commands: Commands,
+    query: Query<CrowdControlledQuery, Changed<shared::Auras>>,
+    mut rejected_ev_w: EventWriter<events::CastRejectedEvent>,
+) {
+    for (entity, auras, (casting, channeling)) in query.iter() {
+        let reason = match auras.cast_blocker() {
+            Some(reason) => reason,
+            None => continue,
+        };
+        let spell_id = match (casting, channeling) {
+            (Some(casting), _) => casting.spell_id,
+            (None, Some(channeling)) => channeling.spell_id,
+            (None, None) => continue,
+        };
+        log::info!("{:?} cast of {} stopped: {}", entity, spell_id, reason);
+        rejected_ev_w.send(events::CastRejectedEvent {
+            caster: entity,
+            spell_id,
+            reason,
+        });
+        commands
+            .entity(entity)
+            .remove::<(shared::CastingSpell, shared::ChannelingSpell)>();
+    }
+}
+
+type InterruptibleQuery = (
+    AnyOf<(
+        &'static shared::CastingSpell,
+        &'static shared::ChannelingSpell,
+    )>,
+    Option<&'static mut shared::Cooldowns>,
+);
+
+/// Cancel interrupted casts & channels, locking out the school of the spell being cast
+fn sys_interrupt_ev(
+    mut ev_r: EventReader<events::InterruptEvent>,
+    mut commands: Commands,
+    spells_asset: Res<assets::SpellsAsset>,
+    mut casting_query: Query<InterruptibleQuery, Without<shared::Dead>>,
+    mut combat_log_ev_w: EventWriter<events::CombatLogEvent>,
+) {
+    for ev in ev_r.read() {
+        let ((casting, channeling), cooldowns) = match casting_query.get_mut(ev.target) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        let spell_id = match (casting, channeling) {
+            (Some(casting), _) => casting.spell_id,
+            (None, Some(channeling)) => channeling.spell_id,
+            (None, None) => continue,
+        };
+        let school = spells_asset
+            .get_spell_data(spell_id)
+            .map(|spell| spell.school)
+            .unwrap_or_default();
+        log::info!(
+            "{:?} cast of {} interrupted, {:?} locked for {:?}",
+            ev.target,
+            spell_id,
+            school,
+            ev.lockout
+        );
+        combat_log_ev_w.send(events::CombatLogEvent {
+            origin: ev.origin,
+            target: ev.target,
+            event: combat_log::CombatEvent::CastStop { spell_id },
+        });
+        match cooldowns {
+            Some(mut cooldowns) => cooldowns.lock_school(school, ev.lockout),
+            None => {
+                let mut cooldowns = shared::Cooldowns::default();
+                cooldowns.lock_school(school, ev.lockout);
+                commands.entity(ev.target).insert(cooldowns);
+            }
+        }
+        commands
+            .entity(ev.target)
+            .remove::<(shared::CastingSpell, shared::ChannelingSpell)>();
+    }
+}
+
+/// Keep each unit's movement speed in step with its auras & the current aura data. Units that
+/// can't move stop right away, rather than on their next movement packet.
+fn sys_update_movement_speed(
+    mut commands: Commands,
+    auras_asset: Res<assets::AurasAsset>,
+    mut query: Query<(
+        Entity,
+        &shared::Auras,
+        Option<&mut shared::MovementSpeed>,
+        Option<&mut shared::Velocity>,
+    )>,
+) {
+    for (entity, auras, movement_speed, velocity) in query.iter_mut() {
+        let speed = auras.movement_multiplier(|id| auras_asset.percent(id));
+        if let Some(mut velocity) = velocity.filter(|vel| speed == 0.0 && vel.0 != Vec3::ZERO) {
+            velocity.0 = Vec3::ZERO;
+        }
+        match movement_speed {
+            Some(mut movement_speed) if movement_speed.0 != speed => movement_speed.0 = speed,
+            Some(_) => {}
+            None => {
+                commands.entity(entity).insert(shared::MovementSpeed(speed));
             }
         }
     }
@@ -99,7 +392,408 @@ impl Plugin for EffectApplicationPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.add_systems(
             FixedUpdate,
-            (sys_add_aura_ev, sys_remove_aura_ev).in_set(ServerSets::EffectApplication),
+            (
+                (
+                    (sys_add_aura_ev, sys_remove_aura_ev),
+                    (sys_crowd_control_casts, sys_update_movement_speed),
+                )
+                    .chain(),
+                sys_interrupt_ev,
+            )
+                .in_set(ServerSets::EffectApplication),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_aura, new_aura, sys_add_aura_ev, sys_crowd_control_casts, sys_remove_aura_ev,
+        sys_update_movement_speed,
+    };
+    use crate::game::{
+        assets, events,
+        test_helpers::{aura_data, with_aura},
+    };
+    use bevy::{app, ecs::event::Events, prelude::*};
+    use lib_spells::shared;
+    use std::time::Duration;
+
+    #[test]
+    fn test_aura_slots() {
+        let mut app = app::App::new();
+        app.insert_resource(assets::AurasAsset(vec![
+            aura_data("shield", 20, shared::AuraType::Shield),
+            aura_data("burn", -2, shared::AuraType::TickingHP),
+        ]));
+        app.add_event::<events::AddAuraEvent>();
+        app.add_event::<events::RemoveAuraEvent>();
+        app.add_event::<events::DispelEvent>();
+        app.add_event::<events::CombatLogEvent>();
+        app.add_systems(Update, (sys_add_aura_ev, sys_remove_aura_ev).chain());
+        let target = app.world.spawn_empty().id();
+        for aura_id in [0, 1, 0] {
+            app.world.send_event(events::AddAuraEvent {
+                aura_id: aura_id.into(),
+                source: target,
+                target_entity: target,
+            });
+        }
+
+        app.update();
+
+        // the second shield refreshed the first
+        let auras = app.world.get::<shared::Auras>(target).unwrap();
+        assert_eq!(auras.iter().count(), 2);
+        assert_eq!(auras.iter_of_type(shared::AuraType::Shield).count(), 1);
+        assert_eq!(
+            auras.get(shared::AuraSlot(1)).unwrap().effect.aura_type(),
+            shared::AuraType::TickingHP
+        );
+
+        app.world.send_event(events::RemoveAuraEvent {
+            aura_id: 0.into(),
+            target_entity: target,
+        });
+        app.update();
+
+        // the shield went, the burn keeps its slot
+        let auras = app.world.get::<shared::Auras>(target).unwrap();
+        assert!(auras.get(shared::AuraSlot(0)).is_none());
+        assert_eq!(auras.get(shared::AuraSlot(1)).unwrap().id, 1.into());
+    }
+
+    #[test]
+    fn test_dispel_and_steal() {
+        let mut app = app::App::new();
+        let aura = |key: &str, dispel_type, harmful| {
+            aura_data(key, 20, shared::AuraType::Shield).with_dispel(dispel_type, harmful)
+        };
+        app.insert_resource(assets::AurasAsset(vec![
+            aura("burn", assets::DispelType::Magic, true),
+            aura("hex", assets::DispelType::Curse, true),
+            aura("barrier", assets::DispelType::Magic, false),
+            aura("enrage", assets::DispelType::None, false),
+            aura("chill", assets::DispelType::Magic, true),
+        ]));
+        app.add_event::<events::AddAuraEvent>();
+        app.add_event::<events::RemoveAuraEvent>();
+        app.add_event::<events::DispelEvent>();
+        app.add_event::<events::CombatLogEvent>();
+        app.add_systems(Update, (sys_add_aura_ev, sys_remove_aura_ev).chain());
+        let target = app.world.spawn_empty().id();
+        let thief = app.world.spawn_empty().id();
+        for aura_id in 0..5 {
+            app.world.send_event(events::AddAuraEvent {
+                aura_id: aura_id.into(),
+                source: target,
+                target_entity: target,
+            });
+        }
+        app.update();
+
+        for (origin, dispel) in [
+            (
+                target,
+                assets::Dispel {
+                    harmful: true,
+                    dispel_type: Some(assets::DispelType::Magic),
+                    max_auras: 1,
+                    steal: false,
+                },
+            ),
+            (
+                thief,
+                assets::Dispel {
+                    harmful: false,
+                    dispel_type: None,
+                    max_auras: 1,
+                    steal: true,
+                },
+            ),
+        ] {
+            app.world.send_event(events::DispelEvent {
+                origin,
+                dispel,
+                target_entity: target,
+            });
+        }
+        app.update();
+
+        // one magic debuff went, the undispellable buff stayed & the barrier was stolen
+        let ids = |entity| -> Vec<shared::AuraID> {
+            app.world
+                .get::<shared::Auras>(entity)
+                .unwrap()
+                .iter()
+                .map(|(_, aura)| aura.id)
+                .collect()
+        };
+        assert_eq!(ids(target), vec![1.into(), 3.into(), 4.into()]);
+        assert_eq!(ids(thief), vec![2.into()]);
+        let (_, stolen) = app
+            .world
+            .get::<shared::Auras>(thief)
+            .unwrap()
+            .iter()
+            .next()
+            .unwrap();
+        assert_eq!(stolen.source, thief);
+    }
+
+    #[test]
+    fn test_dead_units_left_alone() {
+        let mut app = app::App::new();
+        app.insert_resource(assets::AurasAsset(vec![aura_data(
+            "scorched",
+            -2,
+            shared::AuraType::TickingHP,
+        )
+        .with_dispel(assets::DispelType::Magic, true)
+        .with_remove_on_death(false)]));
+        app.add_event::<events::AddAuraEvent>();
+        app.add_event::<events::RemoveAuraEvent>();
+        app.add_event::<events::DispelEvent>();
+        app.add_event::<events::CombatLogEvent>();
+        app.add_systems(Update, (sys_add_aura_ev, sys_remove_aura_ev).chain());
+        let corpse = app.world.spawn_empty().id();
+        app.world.send_event(events::AddAuraEvent {
+            aura_id: 0.into(),
+            source: corpse,
+            target_entity: corpse,
+        });
+        app.update();
+
+        app.world
+            .entity_mut(corpse)
+            .insert(shared::Dead::new(Duration::from_secs(5), Vec3::ZERO));
+        app.world.send_event(events::AddAuraEvent {
+            aura_id: 0.into(),
+            source: corpse,
+            target_entity: corpse,
+        });
+        app.world.send_event(events::DispelEvent {
+            origin: corpse,
+            dispel: assets::Dispel {
+                harmful: true,
+                dispel_type: None,
+                max_auras: 1,
+                steal: false,
+            },
+            target_entity: corpse,
+        });
+        app.world
+            .resource_mut::<Events<events::CombatLogEvent>>()
+            .clear();
+        app.update();
+
+        // neither refreshed nor dispelled
+        let auras = app.world.get::<shared::Auras>(corpse).unwrap();
+        assert_eq!(auras.iter().count(), 1);
+        assert!(app
+            .world
+            .resource::<Events<events::CombatLogEvent>>()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_crowd_control_stops_casts() {
+        let mut app = app::App::new();
+        app.add_event::<events::CastRejectedEvent>();
+        app.add_systems(Update, sys_crowd_control_casts);
+        let casting = || shared::CastingSpell::new(0.into(), Entity::PLACEHOLDER, Duration::MAX);
+        let stunned = app
+            .world
+            .spawn((casting(), with_aura(0, shared::AuraEffect::Stun)))
+            .id();
+        let silenced = app
+            .world
+            .spawn((
+                shared::ChannelingSpell::new(
+                    0.into(),
+                    Entity::PLACEHOLDER,
+                    Duration::from_secs(5),
+                    Duration::from_secs(1),
+                ),
+                with_aura(0, shared::AuraEffect::Silence),
+            ))
+            .id();
+        let rooted = app
+            .world
+            .spawn((casting(), with_aura(0, shared::AuraEffect::Root)))
+            .id();
+
+        app.update();
+
+        assert!(app.world.get::<shared::CastingSpell>(stunned).is_none());
+        assert!(app.world.get::<shared::ChannelingSpell>(silenced).is_none());
+        assert!(app.world.get::<shared::CastingSpell>(rooted).is_some());
+        let reasons: Vec<shared::CastRejectedReason> = app
+            .world
+            .resource_mut::<Events<events::CastRejectedEvent>>()
+            .drain()
+            .map(|ev| ev.reason)
+            .collect();
+        assert_eq!(
+            reasons.len(),
+            2,
+            "only the stun & silence stop casts: {:?}",
+            reasons
+        );
+    }
+
+    #[test]
+    fn test_movement_speed_follows_aura_data() {
+        let mut app = app::App::new();
+        app.insert_resource(assets::AurasAsset(vec![
+            aura_data("slow", -40, shared::AuraType::MoveSpeed),
+            aura_data("root", 0, shared::AuraType::Root),
+        ]));
+        app.add_systems(Update, sys_update_movement_speed);
+        let slowed = app
+            .world
+            .spawn(with_aura(0, shared::AuraEffect::MoveSpeed))
+            .id();
+        let rooted = app
+            .world
+            .spawn((
+                with_aura(1, shared::AuraEffect::Root),
+                shared::Velocity(Vec3::X),
+            ))
+            .id();
+        let speed =
+            |app: &app::App, entity| app.world.get::<shared::MovementSpeed>(entity).unwrap().0;
+
+        app.update();
+
+        assert_eq!(speed(&app, slowed), 0.6);
+        assert_eq!(speed(&app, rooted), 0.0);
+        // units already on the move stop when rooted
+        assert_eq!(
+            app.world.get::<shared::Velocity>(rooted).unwrap().0,
+            Vec3::ZERO
+        );
+
+        // reloaded data applies to the auras already up
+        app.world.resource_mut::<assets::AurasAsset>().0[0].base_multiplier = -20;
+        app.update();
+
+        assert_eq!(speed(&app, slowed), 0.8);
+    }
+
+    #[test]
+    fn test_stacked_slows_stop_at_zero() {
+        let mut app = app::App::new();
+        let chill = || {
+            aura_data("chill", -40, shared::AuraType::MoveSpeed)
+                .with_stacking(assets::AuraStacking::Stack { max: 3 })
+        };
+        app.insert_resource(assets::AurasAsset(vec![chill()]));
+        app.add_systems(Update, sys_update_movement_speed);
+        let mut auras = shared::Auras::default();
+        for _ in 0..3 {
+            apply_aura(
+                &mut auras,
+                new_aura(0.into(), Entity::PLACEHOLDER, &chill()),
+                &chill(),
+            );
+        }
+        let chilled = app.world.spawn((auras, shared::Velocity(Vec3::X))).id();
+
+        app.update();
+
+        // -120% leaves the unit standing still, not walking backwards
+        assert_eq!(
+            app.world.get::<shared::MovementSpeed>(chilled).unwrap().0,
+            0.0
+        );
+        assert_eq!(
+            app.world.get::<shared::Velocity>(chilled).unwrap().0,
+            Vec3::ZERO
+        );
+    }
+
+    /// test aura stacking rules, applying a 10s shield of 20 to one unit from the given casters
+    /// in order. Expects the number of instances, and the stacks & absorb of the first.
+    macro_rules! aura_stacking {
+        ($name:ident, $stacking:expr, $casters:expr, $instances:expr, $stacks:expr, $absorb:expr) => {
+            #[test]
+            fn $name() {
+                let aura_data =
+                    aura_data("shield", 20, shared::AuraType::Shield).with_stacking($stacking);
+                let mut auras = shared::Auras::default();
+                for caster in $casters {
+                    let aura = new_aura(0.into(), Entity::from_raw(caster), &aura_data);
+                    apply_aura(&mut auras, aura, &aura_data);
+                    // take a hit between applications
+                    for (_, shield) in auras.iter_mut() {
+                        if let shared::AuraEffect::Shield(absorb) = &mut shield.effect {
+                            *absorb -= 5;
+                        }
+                    }
+                }
+
+                assert_eq!(auras.iter().count(), $instances);
+                let (_, first) = auras.iter().next().unwrap();
+                assert_eq!(first.stacks, $stacks);
+                assert_eq!(first.effect, shared::AuraEffect::Shield($absorb));
+            }
+        };
+    }
+
+    aura_stacking!(refresh, assets::AuraStacking::Refresh, [1, 2], 1, 1, 15);
+    aura_stacking!(
+        unique,
+        assets::AuraStacking::UniquePerTarget,
+        [1, 2],
+        1,
+        1,
+        10
+    );
+    aura_stacking!(
+        unique_per_caster,
+        assets::AuraStacking::UniquePerCaster,
+        [1, 2, 1],
+        2,
+        1,
+        15
+    );
+    aura_stacking!(
+        stacks_to_max,
+        assets::AuraStacking::Stack { max: 2 },
+        [1, 1, 1],
+        1,
+        2,
+        25
+    );
+
+    #[test]
+    fn test_pandemic_carries_over() {
+        let aura_data = aura_data("burn", -2, shared::AuraType::TickingHP)
+            .with_stacking(assets::AuraStacking::Pandemic);
+        let reapply = |auras: &mut shared::Auras| {
+            apply_aura(
+                auras,
+                new_aura(0.into(), Entity::PLACEHOLDER, &aura_data),
+                &aura_data,
+            )
+        };
+        let mut auras = shared::Auras::default();
+        let slot = reapply(&mut auras).unwrap().slot();
+
+        // 8s left carries over 3s, then 12s left carries over 3s
+        auras.tick(Duration::from_secs(2));
+        reapply(&mut auras);
+        assert_eq!(
+            auras.get(slot).unwrap().get_remaining_time(),
+            Duration::from_secs(13)
+        );
+        auras.tick(Duration::from_secs(1));
+        reapply(&mut auras);
+        assert_eq!(
+            auras.get(slot).unwrap().get_remaining_time(),
+            Duration::from_secs(13)
         );
+        assert_eq!(auras.iter().count(), 1);
     }
 }
@@ -4,17 +4,14 @@ use bevy::prelude::*;
 use lib_spells::shared;
 
 use crate::game::{
-    assets, effect_application,
+    assets,
     events::{self, EffectQueueEvent},
 };
 
 /// Tick each aura by delta.
-pub(super) fn sys_tick_ticking_auras(
-    mut ticking_query: Query<&mut effect_application::TickingEffectAura>,
-    time: Res<Time>,
-) {
-    for mut ticking in ticking_query.iter_mut() {
-        ticking.0.tick(time.delta());
+pub(super) fn sys_tick_auras(mut auras_query: Query<&mut shared::Auras>, time: Res<Time>) {
+    for mut auras in auras_query.iter_mut() {
+        auras.tick(time.delta());
     }
 }
 
@@ -22,19 +19,75 @@ pub(super) fn sys_tick_ticking_auras(
 pub(super) fn sys_apply_aura_tick(
     auras_asset: Res<assets::AurasAsset>,
     mut effect_ev_w: ResMut<Events<events::EffectQueueEvent>>,
-    ticking_query: Query<(
-        &Parent,
-        &shared::Aura,
-        &effect_application::TickingEffectAura,
-    )>,
+    auras_query: Query<(Entity, &shared::Auras)>,
 ) {
-    for (parent, aura, _) in ticking_query.iter().filter(|t| t.2 .0.finished()) {
-        if let Some(aura_data) = auras_asset.lookup(aura.id) {
-            effect_ev_w.send(EffectQueueEvent {
-                aura_effect: None,
-                health_effect: Some(aura_data.base_multiplier),
-                target: parent.get(),
-            });
+    for (target, auras) in auras_query.iter() {
+        for (_, aura) in auras.iter_of_type(shared::AuraType::TickingHP) {
+            let ticked = match &aura.effect {
+                shared::AuraEffect::TickingHP(timer) => timer.times_finished_this_tick(),
+                _ => 0,
+            };
+            if let Some(aura_data) = auras_asset.lookup(aura.id) {
+                for _ in 0..ticked {
+                    effect_ev_w.send(EffectQueueEvent {
+                        origin: aura.source,
+                        aura_effect: None,
+                        school: aura_data.school,
+                        health_effect: Some(aura_data.base_multiplier * aura.stacks as i64),
+                        crit_chance: 0.0,
+                        target,
+                    });
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{sys_apply_aura_tick, sys_tick_auras};
+    use crate::game::{assets, events};
+    use bevy::{app, ecs::event::Events, prelude::*};
+    use lib_spells::shared;
+    use std::time::Duration;
+
+    #[test]
+    fn test_tick_credits_departed_caster() {
+        let mut app = app::App::new();
+        app.insert_resource(assets::AurasAsset(vec![assets::AuraData::new(
+            "burn".into(),
+            "Burn".into(),
+            -2,
+            Duration::from_secs(10),
+            shared::AuraType::TickingHP,
+        )]));
+        app.init_resource::<Events<events::EffectQueueEvent>>();
+        app.init_resource::<Time>();
+        app.add_systems(Update, (sys_tick_auras, sys_apply_aura_tick).chain());
+        let caster = app.world.spawn_empty().id();
+        let mut auras = shared::Auras::default();
+        auras.add(shared::Aura::new(
+            0.into(),
+            caster,
+            Duration::from_secs(10),
+            shared::AuraEffect::TickingHP(Timer::from_seconds(1.0, TimerMode::Repeating)),
+        ));
+        let target = app.world.spawn(auras).id();
+        app.world.despawn(caster);
+        app.world
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_secs(1));
+
+        app.update();
+
+        let tick = app
+            .world
+            .resource_mut::<Events<events::EffectQueueEvent>>()
+            .drain()
+            .next()
+            .unwrap();
+        assert_eq!(tick.origin, caster);
+        assert_eq!(tick.target, target);
+        assert_eq!(tick.health_effect, Some(-2));
+    }
+}
@@ -1,5 +1,6 @@
 mod spells;
 mod auras;
+mod projectiles;
 use bevy::prelude::*;
 
 use super::ServerSets;
@@ -11,18 +12,27 @@ impl Plugin for EffectCreationPlugin {
         app.add_systems(
             FixedUpdate,
             (
+                spells::sys_tick_cooldowns,
                 spells::sys_validate_cast_targets,
                 spells::sys_tick_casts,
+                spells::sys_validate_cast_range,
+                spells::sys_start_global_cooldowns,
                 spells::sys_dispatch_finished_casts,
+                spells::sys_start_cooldowns,
                 spells::sys_remove_finished_casts,
                 spells::sys_spell_application_ev,
+                projectiles::sys_move_projectiles,
+                spells::sys_land_spells,
+                spells::sys_tick_channels,
             )
                 .chain()
                 .in_set(ServerSets::EffectCreation),
         );
-        app.add_systems(FixedUpdate, (
-            auras::sys_apply_aura_tick,
-            auras::sys_tick_ticking_auras,
-        ));
+        app.add_systems(
+            FixedUpdate,
+            (auras::sys_tick_auras, auras::sys_apply_aura_tick)
+                .chain()
+                .in_set(ServerSets::EffectCreation),
+        );
     }
 }
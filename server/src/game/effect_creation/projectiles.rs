@@ -0,0 +1,198 @@
+use bevy::{log, prelude::*};
+use lib_spells::shared;
+
+use crate::game::events;
+
+/// Where a projectile came from & what it's flying at. Unit targets are homed in on.
+#[derive(Component, Debug)]
+pub(super) struct ProjectileFlight {
+    origin: Entity,
+    target: shared::SpellTarget,
+}
+
+/// Launch the spell in `ev` from `from`
+pub(super) fn spawn_projectile(
+    commands: &mut Commands,
+    ev: &events::SpellApplicationEvent,
+    from: Vec3,
+    speed: f32,
+) {
+    commands.spawn((
+        shared::Projectile {
+            spell_id: ev.spell_id,
+            speed,
+        },
+        ProjectileFlight {
+            origin: ev.origin,
+            target: ev.target,
+        },
+        shared::Position(from),
+        shared::Velocity::default(),
+    ));
+}
+
+/// Fly projectiles towards their targets, landing their spells on arrival. Projectiles whose
+/// target is gone are dropped.
+pub(super) fn sys_move_projectiles(
+    time: Res<Time>,
+    mut commands: Commands,
+    mut query: Query<(
+        Entity,
+        &shared::Projectile,
+        &ProjectileFlight,
+        &mut shared::Position,
+        &mut shared::Velocity,
+    )>,
+    targets: Query<&shared::Position, Without<shared::Projectile>>,
+    mut landed_ev_w: EventWriter<events::SpellLandedEvent>,
+) {
+    for (entity, projectile, flight, mut pos, mut vel) in query.iter_mut() {
+        let target_pos = match flight.target {
+            shared::SpellTarget::Entity(target) => targets.get(target).ok().map(|pos| pos.0),
+            shared::SpellTarget::Point(point) => Some(point),
+            shared::SpellTarget::SelfRadius => targets.get(flight.origin).ok().map(|pos| pos.0),
+        };
+        let target_pos = match target_pos {
+            Some(target_pos) => target_pos,
+            None => {
+                log::debug!(
+                    "{:?} projectile {} lost its target",
+                    entity,
+                    projectile.spell_id
+                );
+                commands.entity(entity).despawn();
+                continue;
+            }
+        };
+
+        let step = projectile.speed * time.delta_seconds();
+        if pos.0.distance(target_pos) <= step {
+            landed_ev_w.send(events::SpellLandedEvent {
+                origin: flight.origin,
+                target: flight.target,
+                spell_id: projectile.spell_id,
+            });
+            commands.entity(entity).despawn();
+            continue;
+        }
+        let new_vel = (target_pos - pos.0).normalize_or_zero() * projectile.speed;
+        // only touch velocity when it changes, clients extrapolate from the last change
+        if vel.0 != new_vel {
+            vel.0 = new_vel;
+        }
+        pos.0 += new_vel * time.delta_seconds();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spawn_projectile, sys_move_projectiles};
+    use crate::game::events;
+    use bevy::{
+        app,
+        ecs::{event::Events, system::CommandQueue},
+        math::Vec3,
+        prelude::*,
+    };
+    use lib_spells::shared;
+    use std::time::Duration;
+
+    fn projectile_app() -> app::App {
+        let mut app = app::App::new();
+        app.add_event::<events::SpellLandedEvent>();
+        app.init_resource::<Time>();
+        app.add_systems(Update, sys_move_projectiles);
+        app
+    }
+
+    fn launch(app: &mut app::App, origin: Entity, target: Entity) {
+        let ev = events::SpellApplicationEvent {
+            origin,
+            target: target.into(),
+            spell_id: 0.into(),
+        };
+        let mut queue = CommandQueue::default();
+        spawn_projectile(
+            &mut Commands::new(&mut queue, &app.world),
+            &ev,
+            Vec3::ZERO,
+            10.0,
+        );
+        queue.apply(&mut app.world);
+    }
+
+    fn advance(app: &mut app::App, secs: u64) {
+        app.world
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_secs(secs));
+        app.update();
+    }
+
+    #[test]
+    fn test_projectile_lands_on_arrival() {
+        let mut app = projectile_app();
+        let caster = app.world.spawn(shared::Position(Vec3::ZERO)).id();
+        let target = app
+            .world
+            .spawn(shared::Position(Vec3::new(15.0, 0.0, 0.0)))
+            .id();
+        launch(&mut app, caster, target);
+
+        // half way there after one second
+        advance(&mut app, 1);
+        assert!(app
+            .world
+            .resource::<Events<events::SpellLandedEvent>>()
+            .is_empty());
+        let (pos, vel) = app
+            .world
+            .query_filtered::<(&shared::Position, &shared::Velocity), With<shared::Projectile>>()
+            .single(&app.world);
+        assert_eq!(pos.0, Vec3::new(10.0, 0.0, 0.0));
+        assert_eq!(vel.0, Vec3::new(10.0, 0.0, 0.0));
+
+        advance(&mut app, 1);
+        let landed = app
+            .world
+            .resource_mut::<Events<events::SpellLandedEvent>>()
+            .drain()
+            .next()
+            .unwrap();
+        assert_eq!(landed.origin, caster);
+        assert_eq!(landed.target, shared::SpellTarget::Entity(target));
+        assert_eq!(
+            app.world
+                .query::<&shared::Projectile>()
+                .iter(&app.world)
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_projectile_dropped_with_target() {
+        let mut app = projectile_app();
+        let caster = app.world.spawn(shared::Position(Vec3::ZERO)).id();
+        let target = app
+            .world
+            .spawn(shared::Position(Vec3::new(50.0, 0.0, 0.0)))
+            .id();
+        launch(&mut app, caster, target);
+        advance(&mut app, 1);
+        app.world.despawn(target);
+
+        advance(&mut app, 1);
+
+        assert!(app
+            .world
+            .resource::<Events<events::SpellLandedEvent>>()
+            .is_empty());
+        assert_eq!(
+            app.world
+                .query::<&shared::Projectile>()
+                .iter(&app.world)
+                .count(),
+            0
+        );
+    }
+}
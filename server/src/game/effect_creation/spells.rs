@@ -1,72 +1,190 @@
-use bevy::{log, prelude::*};
+use bevy::{ecs::system::SystemParam, log, prelude::*};
 use lib_spells::{alignment, shared};
 
-use crate::game::{assets, events};
+use super::projectiles;
+use crate::game::{assets, events, spatial};
 
-// Remove invalid targets on casts
+/// The target must still exist & have health left
+fn check_target_alive(
+    target: Entity,
+    health_query: &Query<Option<&shared::Health>>,
+) -> Result<(), shared::CastRejectedReason> {
+    match health_query.get(target) {
+        Ok(Some(health)) if health.current <= 0 => Err(shared::CastRejectedReason::TargetDead),
+        Ok(_) => Ok(()),
+        Err(_) => Err(shared::CastRejectedReason::TargetDead),
+    }
+}
+
+/// The target's faction must suit the spell's hostility. Friendly spells can always self-cast.
+fn check_target_faction(
+    caster: Entity,
+    target: Entity,
+    spell: &assets::SpellData,
+    faction_member: Option<&alignment::FactionMember>,
+    faction_checker: &alignment::FactionChecker,
+) -> Result<(), shared::CastRejectedReason> {
+    let is_selfcast = caster == target;
+    // allow self friendly
+    if is_selfcast && spell.hostility == alignment::Hostility::Friendly {
+        return Ok(());
+    }
+
+    // check factions (default is OK)
+    let caster_faction = match faction_member {
+        Some(member) => member.0,
+        None => alignment::Faction::default(),
+    };
+    let target_faction = faction_checker
+        .get_entity_faction(target)
+        .unwrap_or_default();
+    if !is_selfcast && alignment::is_valid_target(spell.hostility, caster_faction, target_faction) {
+        return Ok(());
+    }
+    // disallow all else
+    Err(shared::CastRejectedReason::InvalidTarget)
+}
+
+/// Run every cast through validation, cancelling & reporting the ones that fail. Area spells
+/// only need a living target, each unit they hit is checked as the spell lands.
 pub(super) fn sys_validate_cast_targets(
-    mut query: Query<(
+    query: Query<(
         Entity,
-        &mut shared::CastingSpell,
+        &shared::CastingSpell,
         Option<&alignment::FactionMember>,
     )>,
+    health_query: Query<Option<&shared::Health>>,
     spell_list: Res<assets::SpellsAsset>,
     faction_checker: alignment::FactionChecker,
+    mut rejected_ev_w: EventWriter<events::CastRejectedEvent>,
     mut commands: Commands,
 ) {
-    for (entity, casting, faction_member) in query.iter_mut() {
-        let spell = spell_list.get_spell_data(casting.spell_id).unwrap();
-        let is_selfcast = entity == casting.target;
-        // allow self friendly
-        if is_selfcast && spell.hostility == alignment::Hostility::Friendly {
-            continue;
+    for (entity, casting, faction_member) in query.iter() {
+        let result = match (spell_list.get_spell_data(casting.spell_id), casting.target) {
+            (None, _) => Err(shared::CastRejectedReason::UnknownSpell),
+            (Some(spell), shared::SpellTarget::Entity(target)) => {
+                check_target_alive(target, &health_query).and_then(|_| match spell.area {
+                    Some(_) => Ok(()),
+                    None => check_target_faction(
+                        entity,
+                        target,
+                        spell,
+                        faction_member,
+                        &faction_checker,
+                    ),
+                })
+            }
+            // single target spells cast around ourselves land on ourselves
+            (Some(spell), shared::SpellTarget::SelfRadius) if spell.area.is_none() => {
+                check_target_faction(entity, entity, spell, faction_member, &faction_checker)
+            }
+            (Some(_), _) => Ok(()),
+        };
+        if let Err(reason) = result {
+            reject_cast(&mut commands, &mut rejected_ev_w, entity, casting, reason);
         }
+    }
+}
 
-        // check factions (default is OK)
-        let caster_faction = match faction_member {
-            Some(member) => member.0,
-            None => alignment::Faction::default(),
-        };
-        let target_faction = faction_checker
-            .get_entity_faction(casting.target)
-            .unwrap_or_default();
-        if !is_selfcast
-            && alignment::is_valid_target(spell.hostility, caster_faction, target_faction)
-        {
+/// Casts must start & finish with the target in range. Self casts are always in range.
+pub(super) fn sys_validate_cast_range(
+    query: Query<(Entity, Ref<shared::CastingSpell>, Option<&shared::Position>)>,
+    positions: Query<&shared::Position>,
+    spell_list: Res<assets::SpellsAsset>,
+    mut rejected_ev_w: EventWriter<events::CastRejectedEvent>,
+    mut commands: Commands,
+) {
+    for (entity, casting, caster_pos) in query.iter() {
+        if !(casting.is_added() || casting.cast_timer.finished()) {
             continue;
         }
-        // disallow all else
-        log::info!(
-            "{:?} invalid target {:?} for spell {}",
-            entity,
-            casting.target,
-            casting.spell_id
-        );
-        commands
-            .entity(entity)
-            .remove::<shared::CastingSpell>();
+        let target_pos = match casting.target {
+            shared::SpellTarget::Entity(target) if target != entity => {
+                positions.get(target).ok().map(|pos| pos.0)
+            }
+            shared::SpellTarget::Point(point) => Some(point),
+            _ => None,
+        };
+        // without positions there's nothing to measure
+        let (caster_pos, target_pos) = match (caster_pos, target_pos) {
+            (Some(caster_pos), Some(target_pos)) => (caster_pos, target_pos),
+            _ => continue,
+        };
+        let spell = match spell_list.get_spell_data(casting.spell_id) {
+            Some(spell) => spell,
+            None => continue,
+        };
+        if !spell.in_range(caster_pos.0.distance(target_pos)) {
+            reject_cast(
+                &mut commands,
+                &mut rejected_ev_w,
+                entity,
+                &casting,
+                shared::CastRejectedReason::OutOfRange,
+            );
+        }
     }
 }
 
-/// Dispatch `SpellApplicationEvent` for finished casts
+/// Cancel `caster`'s cast & report why
+fn reject_cast(
+    commands: &mut Commands,
+    rejected_ev_w: &mut EventWriter<events::CastRejectedEvent>,
+    caster: Entity,
+    casting: &shared::CastingSpell,
+    reason: shared::CastRejectedReason,
+) {
+    log::info!(
+        "{:?} cast of {} at {:?} rejected: {}",
+        caster,
+        casting.spell_id,
+        casting.target,
+        reason
+    );
+    rejected_ev_w.send(events::CastRejectedEvent {
+        caster,
+        spell_id: casting.spell_id,
+        reason,
+    });
+    commands.entity(caster).remove::<shared::CastingSpell>();
+}
+
+/// Dispatch `SpellApplicationEvent` for finished casts, spending the caster's power. Casts the
+/// caster can no longer afford are rejected.
 pub(super) fn sys_dispatch_finished_casts(
-    query: Query<(Entity, &shared::CastingSpell)>,
+    mut query: Query<(Entity, &shared::CastingSpell, Option<&mut shared::Power>)>,
+    spell_list: Res<assets::SpellsAsset>,
     mut spell_app_ev_w: EventWriter<events::SpellApplicationEvent>,
+    mut rejected_ev_w: EventWriter<events::CastRejectedEvent>,
+    mut commands: Commands,
 ) {
-    let events: Vec<events::SpellApplicationEvent> = query
-        .iter()
-        .filter_map(|(caster, cast)| {
-            cast.cast_timer
-                .finished()
-                .then_some(events::SpellApplicationEvent {
-                    origin: caster,
-                    spell_id: cast.spell_id,
-                    target: cast.target,
-                })
-        })
-        .collect();
-
-    spell_app_ev_w.send_batch(events);
+    for (caster, cast, power) in query.iter_mut() {
+        if !cast.cast_timer.finished() {
+            continue;
+        }
+        let cost = spell_list
+            .get_spell_data(cast.spell_id)
+            .map(|spell| spell.cost)
+            .unwrap_or_default();
+        if let Some(mut power) = power {
+            if !power.can_afford(cost) {
+                reject_cast(
+                    &mut commands,
+                    &mut rejected_ev_w,
+                    caster,
+                    cast,
+                    shared::CastRejectedReason::NotEnoughResource,
+                );
+                continue;
+            }
+            power.current -= cost;
+        }
+        spell_app_ev_w.send(events::SpellApplicationEvent {
+            origin: caster,
+            spell_id: cast.spell_id,
+            target: cast.target,
+        });
+    }
 }
 
 pub(super) fn sys_remove_finished_casts(
@@ -74,9 +192,42 @@ pub(super) fn sys_remove_finished_casts(
     query: Query<(Entity, &shared::CastingSpell)>,
 ) {
     for (entity, _) in query.iter().filter(|(_, cast)| cast.cast_timer.finished()) {
-        commands
-            .entity(entity)
-            .remove::<shared::CastingSpell>();
+        commands.entity(entity).remove::<shared::CastingSpell>();
+    }
+}
+
+/// Start the global cooldown for casts that just started & passed validation, so rejected casts
+/// don't use it up
+pub(super) fn sys_start_global_cooldowns(
+    spell_list: Res<assets::SpellsAsset>,
+    mut query: Query<(&shared::CastingSpell, &mut shared::Cooldowns), Added<shared::CastingSpell>>,
+) {
+    for (casting, mut cooldowns) in query.iter_mut() {
+        if let Some(spell) = spell_list.get_spell_data(casting.spell_id) {
+            cooldowns.start_global(spell.global_cooldown);
+        }
+    }
+}
+
+/// Start the spell cooldown for casters as their spells land
+pub(super) fn sys_start_cooldowns(
+    spell_list: Res<assets::SpellsAsset>,
+    mut spell_ev_r: EventReader<events::SpellApplicationEvent>,
+    mut query: Query<&mut shared::Cooldowns>,
+) {
+    for ev in spell_ev_r.read() {
+        if let (Ok(mut cooldowns), Some(spell)) = (
+            query.get_mut(ev.origin),
+            spell_list.get_spell_data(ev.spell_id),
+        ) {
+            cooldowns.start(ev.spell_id, spell.cooldown);
+        }
+    }
+}
+
+pub(super) fn sys_tick_cooldowns(time: Res<Time>, mut query: Query<&mut shared::Cooldowns>) {
+    for mut cooldowns in query.iter_mut() {
+        cooldowns.tick(time.delta());
     }
 }
 
@@ -87,34 +238,254 @@ pub(super) fn sys_tick_casts(time: Res<Time>, mut query: Query<&mut shared::Cast
     }
 }
 
-/// Read spell application events and create effects
+/// Where units are, & which of them are corpses no spell can hit
+#[derive(SystemParam)]
+pub(super) struct UnitPositions<'w, 's> {
+    index: Res<'w, spatial::SpatialIndex>,
+    positions: Query<'w, 's, &'static shared::Position>,
+    dead: Query<'w, 's, (), With<shared::Dead>>,
+}
+
+/// Every unit `spell`'s area hits when cast by `caster` at `target`, closest to the centre first.
+/// Sphere areas centre on the target, cones spread out from the caster towards it.
+fn area_targets(
+    caster: Entity,
+    target: shared::SpellTarget,
+    spell: &assets::SpellData,
+    area: &assets::AreaOfEffect,
+    units: &UnitPositions,
+    faction_checker: &alignment::FactionChecker,
+) -> Vec<Entity> {
+    let (index, positions, dead) = (&units.index, &units.positions, &units.dead);
+    let caster_pos = positions.get(caster).ok().map(|pos| pos.0);
+    let center = match target {
+        shared::SpellTarget::Entity(target) => positions.get(target).ok().map(|pos| pos.0),
+        shared::SpellTarget::Point(point) => Some(point),
+        shared::SpellTarget::SelfRadius => caster_pos,
+    };
+    let center = match center {
+        Some(center) => center,
+        None => return Vec::new(),
+    };
+    let caster_faction = faction_checker
+        .get_entity_faction(caster)
+        .unwrap_or_default();
+    let is_valid = |entity: Entity| {
+        if dead.contains(entity) {
+            return false;
+        }
+        if entity == caster {
+            return spell.hostility == alignment::Hostility::Friendly;
+        }
+        let faction = faction_checker
+            .get_entity_faction(entity)
+            .unwrap_or_default();
+        alignment::is_valid_target(spell.hostility, caster_faction, faction)
+    };
+
+    let mut hits: Vec<(Entity, f32)> = match area.shape {
+        assets::AreaShape::Sphere { radius } => index
+            .within(center, radius)
+            .filter(|(entity, _)| is_valid(*entity))
+            .map(|(entity, pos)| (entity, pos.distance(center)))
+            .collect(),
+        assets::AreaShape::Cone { radius, angle } => {
+            let origin = caster_pos.unwrap_or(center);
+            // a cone cast on ourselves has no direction, so hits all around us
+            let facing = (center - origin).normalize_or_zero();
+            let half_angle = angle.to_radians() / 2.0;
+            index
+                .within(origin, radius)
+                .filter(|(entity, _)| is_valid(*entity))
+                .filter(|(_, pos)| {
+                    let to = *pos - origin;
+                    facing == Vec3::ZERO
+                        || to == Vec3::ZERO
+                        || facing.angle_between(to) <= half_angle
+                })
+                .map(|(entity, pos)| (entity, pos.distance(origin)))
+                .collect()
+        }
+    };
+    hits.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+    if let Some(max_targets) = area.max_targets {
+        hits.truncate(max_targets);
+    }
+    hits.into_iter().map(|(entity, _)| entity).collect()
+}
+
+/// Read spell application events. Channelled spells start channelling, projectiles are launched
+/// & everything else lands straight away.
 pub(super) fn sys_spell_application_ev(
+    mut commands: Commands,
     spell_list: Res<assets::SpellsAsset>,
-    mut effect_ev_w: EventWriter<events::EffectQueueEvent>,
+    positions: Query<&shared::Position>,
+    mut landed_ev_w: EventWriter<events::SpellLandedEvent>,
     mut spell_ev_r: EventReader<events::SpellApplicationEvent>,
 ) {
     for ev in spell_ev_r.read() {
-        if let Some(spell_data) = spell_list.get_spell_data(ev.spell_id) {
-            let ev = events::EffectQueueEvent {
-                target: ev.target,
+        let spell_data = match spell_list.get_spell_data(ev.spell_id) {
+            Some(spell_data) => spell_data,
+            None => {
+                log::warn!("no spell {}", ev.spell_id);
+                continue;
+            }
+        };
+        if let Some(duration) = spell_data.channel_duration {
+            match (ev.target.entity(), commands.get_entity(ev.origin)) {
+                (Some(target), Some(mut caster)) => {
+                    caster.insert(shared::ChannelingSpell::new(
+                        ev.spell_id,
+                        target,
+                        duration,
+                        spell_data.channel_tick,
+                    ));
+                }
+                (None, _) => log::warn!("channel {} needs a unit target", ev.spell_id),
+                _ => {}
+            }
+            continue;
+        }
+        // projectiles need somewhere to fly from & to, self casts land straight away
+        let is_selfcast = ev
+            .target
+            .entity()
+            .map_or(ev.target == shared::SpellTarget::SelfRadius, |target| {
+                target == ev.origin
+            });
+        if let (Some(speed), Ok(origin_pos), false) = (
+            spell_data.projectile_speed,
+            positions.get(ev.origin),
+            is_selfcast,
+        ) {
+            projectiles::spawn_projectile(&mut commands, ev, origin_pos.0, speed);
+            continue;
+        }
+        landed_ev_w.send(events::SpellLandedEvent {
+            origin: ev.origin,
+            target: ev.target,
+            spell_id: ev.spell_id,
+        });
+    }
+}
+
+/// Everything a landed spell can do to the units it hits
+#[derive(SystemParam)]
+pub(super) struct LandedSpellWriters<'w> {
+    effects: EventWriter<'w, events::EffectQueueEvent>,
+    interrupts: EventWriter<'w, events::InterruptEvent>,
+    dispels: EventWriter<'w, events::DispelEvent>,
+}
+
+/// Create effects for every unit hit by landed spells
+pub(super) fn sys_land_spells(
+    spell_list: Res<assets::SpellsAsset>,
+    units: UnitPositions,
+    faction_checker: alignment::FactionChecker,
+    mut writers: LandedSpellWriters,
+    mut landed_ev_r: EventReader<events::SpellLandedEvent>,
+) {
+    for ev in landed_ev_r.read() {
+        let spell_data = match spell_list.get_spell_data(ev.spell_id) {
+            Some(spell_data) => spell_data,
+            None => continue,
+        };
+        let targets = match (&spell_data.area, ev.target) {
+            (Some(area), _) => area_targets(
+                ev.origin,
+                ev.target,
+                spell_data,
+                area,
+                &units,
+                &faction_checker,
+            ),
+            (None, shared::SpellTarget::Entity(target)) => vec![target],
+            (None, shared::SpellTarget::SelfRadius) => vec![ev.origin],
+            (None, shared::SpellTarget::Point(_)) => {
+                log::warn!("spell {} cast at a point has no area", ev.spell_id);
+                continue;
+            }
+        };
+        // spells aimed at a unit that died before they landed hit nothing
+        for target in targets.into_iter().filter(|t| !units.dead.contains(*t)) {
+            if let Some(lockout) = spell_data.interrupt_lockout {
+                writers.interrupts.send(events::InterruptEvent {
+                    origin: ev.origin,
+                    target,
+                    lockout,
+                });
+            }
+            if let Some(dispel) = spell_data.dispel {
+                writers.dispels.send(events::DispelEvent {
+                    origin: ev.origin,
+                    dispel,
+                    target_entity: target,
+                });
+            }
+            writers.effects.send(events::EffectQueueEvent {
+                origin: ev.origin,
+                target,
+                school: spell_data.school,
                 health_effect: spell_data.target_health_effect,
+                crit_chance: spell_data.crit_chance,
                 aura_effect: spell_data.target_aura_effect,
-            };
-            effect_ev_w.send(ev);
-        } else {
-            log::warn!("no spell {}", ev.spell_id);
+            });
+        }
+    }
+}
+
+/// Apply channelled spells each tick. Channels end when they run out or the target dies.
+pub(super) fn sys_tick_channels(
+    time: Res<Time>,
+    mut commands: Commands,
+    spell_list: Res<assets::SpellsAsset>,
+    mut query: Query<(Entity, &mut shared::ChannelingSpell)>,
+    health_query: Query<Option<&shared::Health>>,
+    mut effect_ev_w: EventWriter<events::EffectQueueEvent>,
+) {
+    for (entity, mut channel) in query.iter_mut() {
+        if check_target_alive(channel.target, &health_query).is_err() {
+            log::info!("{:?} channel of {} ended, target died", entity, channel.spell_id);
+            commands.entity(entity).remove::<shared::ChannelingSpell>();
+            continue;
+        }
+        let ticks = channel.advance(time.delta());
+        if let Some(spell_data) = spell_list.get_spell_data(channel.spell_id) {
+            for _ in 0..ticks {
+                effect_ev_w.send(events::EffectQueueEvent {
+                    origin: entity,
+                    target: channel.target,
+                    school: spell_data.school,
+                    health_effect: spell_data.target_health_effect,
+                    crit_chance: spell_data.crit_chance,
+                    aura_effect: spell_data.target_aura_effect,
+                });
+            }
+        }
+        if channel.duration.finished() {
+            commands.entity(entity).remove::<shared::ChannelingSpell>();
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{assets, sys_validate_cast_targets};
+    use super::{
+        assets, events, spatial, sys_dispatch_finished_casts, sys_land_spells, sys_start_cooldowns,
+        sys_start_global_cooldowns, sys_tick_channels, sys_validate_cast_range,
+        sys_validate_cast_targets,
+    };
     use bevy::{
         app::{self, Update},
         time::Timer,
     };
+    use bevy::{
+        ecs::event::Events,
+        math::Vec3,
+        prelude::{Entity, IntoSystemConfigs},
+    };
     use lib_spells::{alignment, shared};
+    use std::time::Duration;
 
     /// test spell target validation
     macro_rules! target_validation {
@@ -128,6 +499,7 @@ mod tests {
                         assets::SpellData::new("friendly".into(), 0).mark_friendly(),
                     ],
                 });
+                app.add_event::<events::CastRejectedEvent>();
                 app.add_systems(Update, sys_validate_cast_targets);
                 let target = app.world.spawn(alignment::FactionMember($c)).id();
                 // caster
@@ -138,7 +510,7 @@ mod tests {
                         shared::CastingSpell {
                             cast_timer: Timer::from_seconds(1.0, bevy::time::TimerMode::Once),
                             spell_id: $s,
-                            target,
+                            target: target.into(),
                         },
                     ))
                     .id();
@@ -148,6 +520,10 @@ mod tests {
 
                 let still_casting = app.world.get::<shared::CastingSpell>(caster);
                 assert_eq!($e, still_casting.is_some());
+                let rejected = app
+                    .world
+                    .resource::<bevy::ecs::event::Events<events::CastRejectedEvent>>();
+                assert_eq!($e, rejected.is_empty());
             }
         };
     }
@@ -161,4 +537,314 @@ mod tests {
     target_validation!(friendly_target_no_faction, 1.into(), 0b001, 0b000, false);
     target_validation!(friendly_caster_no_faction, 1.into(), 0b000, 0b001, false);
     target_validation!(friendly_no_factions, 1.into(), 0b000, 0b000, false);
+
+    #[test]
+    fn test_dead_target_rejected() {
+        let mut app = app::App::new();
+        app.insert_resource(assets::SpellsAsset(vec![assets::SpellData::new(
+            "hostile".into(),
+            0,
+        )]));
+        app.add_event::<events::CastRejectedEvent>();
+        app.add_systems(Update, sys_validate_cast_targets);
+        let target = app
+            .world
+            .spawn(shared::Health {
+                current: 0,
+                max: 100,
+            })
+            .id();
+        let caster = app
+            .world
+            .spawn(shared::CastingSpell::new(
+                0.into(),
+                target,
+                std::time::Duration::from_secs(1),
+            ))
+            .id();
+
+        app.update();
+
+        assert!(app.world.get::<shared::CastingSpell>(caster).is_none());
+        let rejected = app
+            .world
+            .resource_mut::<bevy::ecs::event::Events<events::CastRejectedEvent>>()
+            .drain()
+            .next()
+            .unwrap();
+        assert_eq!(rejected.caster, caster);
+        assert_eq!(rejected.reason, shared::CastRejectedReason::TargetDead);
+    }
+
+    #[test]
+    fn test_landed_spell_starts_cooldown() {
+        let mut app = app::App::new();
+        app.insert_resource(assets::SpellsAsset(vec![
+            assets::SpellData::new("nuke".into(), 0).with_cooldown(
+                std::time::Duration::from_secs(10),
+                std::time::Duration::ZERO,
+            ),
+            assets::SpellData::new("other".into(), 0),
+        ]));
+        app.add_event::<events::SpellApplicationEvent>();
+        app.add_systems(Update, sys_start_cooldowns);
+        let caster = app.world.spawn(shared::Cooldowns::default()).id();
+        app.world.send_event(events::SpellApplicationEvent {
+            origin: caster,
+            target: caster.into(),
+            spell_id: 0.into(),
+        });
+
+        app.update();
+
+        let cooldowns = app.world.get::<shared::Cooldowns>(caster).unwrap();
+        assert!(!cooldowns.is_ready(0.into(), shared::SpellSchool::default()));
+        assert!(cooldowns.is_ready(1.into(), shared::SpellSchool::default()));
+    }
+
+    #[test]
+    fn test_rejected_cast_keeps_global_cooldown() {
+        let mut app = app::App::new();
+        app.insert_resource(assets::SpellsAsset(vec![assets::SpellData::new(
+            "ranged".into(),
+            0,
+        )
+        .with_range(30.0, None)]));
+        app.add_event::<events::CastRejectedEvent>();
+        app.add_systems(
+            Update,
+            (sys_validate_cast_range, sys_start_global_cooldowns).chain(),
+        );
+        let target = app
+            .world
+            .spawn(shared::Position(Vec3::new(20.0, 0.0, 0.0)))
+            .id();
+        let cast_from = |app: &mut app::App, pos| {
+            app.world
+                .spawn((
+                    shared::Position(pos),
+                    shared::Cooldowns::default(),
+                    shared::CastingSpell::new(0.into(), target, Duration::from_secs(1)),
+                ))
+                .id()
+        };
+        let in_range = cast_from(&mut app, Vec3::ZERO);
+        let too_far = cast_from(&mut app, Vec3::new(-20.0, 0.0, 0.0));
+
+        app.update();
+
+        let on_gcd = |app: &app::App, caster| {
+            !app.world
+                .get::<shared::Cooldowns>(caster)
+                .unwrap()
+                .is_ready(1.into(), shared::SpellSchool::default())
+        };
+        assert!(on_gcd(&app, in_range));
+        assert!(app.world.get::<shared::CastingSpell>(too_far).is_none());
+        assert!(!on_gcd(&app, too_far));
+    }
+
+    #[test]
+    fn test_finished_cast_spends_power() {
+        let mut app = app::App::new();
+        app.insert_resource(assets::SpellsAsset(vec![assets::SpellData::new(
+            "nuke".into(),
+            0,
+        )
+        .with_cost(60.0)]));
+        app.add_event::<events::SpellApplicationEvent>();
+        app.add_event::<events::CastRejectedEvent>();
+        app.add_systems(Update, sys_dispatch_finished_casts);
+        let mut finished = shared::CastingSpell::new(0.into(), Entity::PLACEHOLDER, Duration::ZERO);
+        finished.cast_timer.tick(Duration::ZERO);
+        let caster = app
+            .world
+            .spawn((shared::Power::new(100.0, 0.0), finished.clone()))
+            .id();
+        let broke_caster = app
+            .world
+            .spawn((
+                shared::Power {
+                    current: 59.0,
+                    max: 100.0,
+                    regen_per_sec: 0.0,
+                },
+                finished,
+            ))
+            .id();
+
+        app.update();
+
+        assert_eq!(
+            app.world.get::<shared::Power>(caster).unwrap().current,
+            40.0
+        );
+        assert_eq!(
+            app.world
+                .get::<shared::Power>(broke_caster)
+                .unwrap()
+                .current,
+            59.0
+        );
+        let applied = app
+            .world
+            .resource::<Events<events::SpellApplicationEvent>>();
+        assert_eq!(applied.len(), 1);
+        let rejected = app.world.resource::<Events<events::CastRejectedEvent>>();
+        assert_eq!(rejected.len(), 1);
+    }
+
+    #[test]
+    fn test_channel_ticks() {
+        let mut app = app::App::new();
+        app.insert_resource(assets::SpellsAsset(vec![assets::SpellData::new(
+            "drain".into(),
+            0,
+        )
+        .with_target_hp(-2)]));
+        app.init_resource::<Events<events::EffectQueueEvent>>();
+        app.init_resource::<bevy::time::Time>();
+        app.add_systems(Update, sys_tick_channels);
+        let target = app.world.spawn(shared::Health::new(10)).id();
+        let caster = app
+            .world
+            .spawn(shared::ChannelingSpell::new(
+                0.into(),
+                target,
+                Duration::from_secs(3),
+                Duration::from_secs(1),
+            ))
+            .id();
+        // overshoot the end of the channel, it should still only tick 3 times
+        app.world
+            .resource_mut::<bevy::time::Time>()
+            .advance_by(Duration::from_secs(5));
+
+        app.update();
+
+        assert_eq!(
+            app.world
+                .resource::<Events<events::EffectQueueEvent>>()
+                .len(),
+            3
+        );
+        assert!(app.world.get::<shared::ChannelingSpell>(caster).is_none());
+    }
+
+    #[test]
+    fn test_area_spell_hits_nearby_enemies() {
+        let mut app = app::App::new();
+        app.insert_resource(assets::SpellsAsset(vec![assets::SpellData::new(
+            "nova".into(),
+            0,
+        )
+        .with_target_hp(-5)
+        .with_area(assets::AreaOfEffect {
+            shape: assets::AreaShape::Sphere { radius: 10.0 },
+            max_targets: Some(2),
+        })]));
+        app.init_resource::<spatial::SpatialIndex>();
+        app.add_event::<events::SpellLandedEvent>();
+        app.add_event::<events::EffectQueueEvent>();
+        app.add_event::<events::InterruptEvent>();
+        app.add_event::<events::DispelEvent>();
+        app.add_systems(
+            Update,
+            (spatial::sys_rebuild_spatial_index, sys_land_spells).chain(),
+        );
+        let caster = app
+            .world
+            .spawn((shared::Position(Vec3::ZERO), alignment::FactionMember(0b01)))
+            .id();
+        // the projectile that carried the spell here, right on top of the caster
+        app.world.spawn((
+            shared::Position(Vec3::new(0.5, 0.0, 0.0)),
+            shared::Projectile {
+                spell_id: 0.into(),
+                speed: 10.0,
+            },
+        ));
+        let units = [
+            (Vec3::new(2.0, 0.0, 0.0), 0b10),
+            (Vec3::new(0.0, 0.0, 5.0), 0b10),
+            (Vec3::new(8.0, 0.0, 0.0), 0b10),
+            (Vec3::new(1.0, 0.0, 1.0), 0b01),
+            (Vec3::new(20.0, 0.0, 0.0), 0b10),
+        ]
+        .map(|(pos, faction)| {
+            let unit = app
+                .world
+                .spawn((shared::Position(pos), alignment::FactionMember(faction)))
+                .id();
+            (unit, pos)
+        });
+        // a corpse closer than anyone
+        app.world.spawn((
+            shared::Position(Vec3::new(1.0, 0.0, 0.0)),
+            alignment::FactionMember(0b10),
+            shared::Dead::new(Duration::from_secs(5), Vec3::ZERO),
+        ));
+        app.world.send_event(events::SpellLandedEvent {
+            origin: caster,
+            target: shared::SpellTarget::SelfRadius,
+            spell_id: 0.into(),
+        });
+
+        app.update();
+
+        // the ally, the caster, the projectile, the corpse & units out of range are skipped, then
+        // the closest two are hit
+        let hit: Vec<Entity> = app
+            .world
+            .resource_mut::<Events<events::EffectQueueEvent>>()
+            .drain()
+            .map(|ev| ev.target)
+            .collect();
+        assert_eq!(hit, vec![units[0].0, units[1].0]);
+    }
+
+    /// test spell range validation, spell 0 has a range of 10..30
+    macro_rules! range_validation {
+        ($name:ident, $c:expr, $t:expr, $e:expr) => {
+            #[test]
+            fn $name() {
+                let mut app = app::App::new();
+                app.insert_resource(assets::SpellsAsset(vec![assets::SpellData::new(
+                    "ranged".into(),
+                    0,
+                )
+                .with_range(30.0, Some(10.0))]));
+                app.add_event::<events::CastRejectedEvent>();
+                app.add_systems(Update, sys_validate_cast_range);
+                let target = app.world.spawn(shared::Position($t)).id();
+                let caster = app
+                    .world
+                    .spawn((
+                        shared::Position($c),
+                        shared::CastingSpell::new(
+                            0.into(),
+                            target,
+                            std::time::Duration::from_secs(1),
+                        ),
+                    ))
+                    .id();
+
+                app.update();
+
+                let still_casting = app.world.get::<shared::CastingSpell>(caster);
+                assert_eq!($e, still_casting.is_some());
+            }
+        };
+    }
+
+    range_validation!(in_range, Vec3::ZERO, Vec3::new(20.0, 0.0, 0.0), true);
+    range_validation!(at_max_range, Vec3::ZERO, Vec3::new(0.0, 0.0, 30.0), true);
+    range_validation!(too_far, Vec3::ZERO, Vec3::new(30.0, 1.0, 0.0), false);
+    range_validation!(too_close, Vec3::ZERO, Vec3::new(5.0, 0.0, 0.0), false);
+    range_validation!(
+        at_min_range_offset,
+        Vec3::splat(100.0),
+        Vec3::new(110.0, 100.0, 100.0),
+        true
+    );
 }
@@ -1,80 +1,211 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use bevy::{ecs::system::SystemParam, log, prelude::*};
-use lib_spells::shared;
+use lib_spells::{combat_log, shared};
 
-use crate::game::{effect_application, events};
+use crate::game::{assets, events};
 
 use super::ServerSets;
 
+/// How far each hit sets back a cast that suffers pushback
+const CAST_PUSHBACK: Duration = Duration::from_millis(500);
+/// How much stronger critical effects are, as a percentage
+const CRIT_BONUS: i64 = 100;
+
+/// Drives crit rolls. Seeded so fights can be replayed & processing stays testable.
+#[derive(Resource)]
+pub struct CombatRng(fastrand::Rng);
+
+impl CombatRng {
+    pub fn seeded(seed: u64) -> Self {
+        Self(fastrand::Rng::with_seed(seed))
+    }
+
+    /// Roll against a `chance` from 0 to 1
+    fn roll(&mut self, chance: f32) -> bool {
+        chance > 0.0 && self.0.f32() < chance
+    }
+}
+
+/// Whoever last took health off a unit, so its death can be credited to them
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastDamagedBy(pub Entity);
+
 #[derive(SystemParam)]
-struct ShieldQuery<'w, 's> {
-    query_children: Query<'w, 's, &'static Children>,
-    query_shields: Query<'w, 's, &'static mut effect_application::ShieldAura>,
+pub(crate) struct AuraQuery<'w, 's> {
+    query_auras: Query<'w, 's, &'static mut shared::Auras>,
+    auras_asset: Res<'w, assets::AurasAsset>,
 }
 
-impl<'w, 's> ShieldQuery<'w, 's> {
+impl<'w, 's> AuraQuery<'w, 's> {
+    /// Scale damage by the attacker's damage done & the target's damage taken modifiers
+    fn modify_damage(&self, origin: Entity, target: Entity, damage: i64) -> i64 {
+        let factor = |entity, aura_type| {
+            self.query_auras.get(entity).map_or(100, |auras| {
+                auras.percent_factor(aura_type, |id| self.auras_asset.percent(id))
+            })
+        };
+        damage
+            * factor(origin, shared::AuraType::DamageDone)
+            * factor(target, shared::AuraType::DamageTaken)
+            / 10_000
+    }
+
     /// Returns the current absorb value of a given entity based off its auras.
     fn get_total_entity_shielding(&self, entity: Entity) -> Option<i64> {
-        self.query_children
+        self.query_auras
             .get(entity)
-            .iter()
-            .flat_map(|&e| self.query_shields.iter_many(e))
-            .map(|s| s.0)
+            .ok()?
+            .iter_of_type(shared::AuraType::Shield)
+            .filter_map(|(_, aura)| match aura.effect {
+                shared::AuraEffect::Shield(absorb) => Some(absorb),
+                _ => None,
+            })
             .reduce(|a, b| a + b)
     }
 
     /// Apply damage to shields on the entity. Damage should be positive (e.g. +400 to do 400 damage).
     fn apply_shield_damage(&mut self, entity: Entity, damage: i64) {
         let mut damage_left = damage;
-        if let Ok(children) = self.query_children.get(entity) {
+        if let Ok(mut auras) = self.query_auras.get_mut(entity) {
             // apply n damage to shields
-            let mut iter = self.query_shields.iter_many_mut(children);
-            while let Some(mut shield) = iter.fetch_next() {
-                let applied_dmg = shield.0.min(damage_left);
-                shield.0 -= applied_dmg;
-                damage_left -= applied_dmg;
+            for (_, shield) in auras.iter_of_type_mut(shared::AuraType::Shield) {
+                if let shared::AuraEffect::Shield(absorb) = &mut shield.effect {
+                    let applied_dmg = (*absorb).min(damage_left);
+                    *absorb -= applied_dmg;
+                    damage_left -= applied_dmg;
+                }
             }
         }
     }
 }
 
 #[derive(SystemParam)]
-struct HealthQuery<'w, 's> {
-    query_health: Query<'w, 's, &'static mut shared::Health>,
+pub(crate) struct HealthQuery<'w, 's> {
+    // the dead don't take damage or healing until they respawn
+    query_health: Query<'w, 's, &'static mut shared::Health, Without<shared::Dead>>,
 }
 
 impl<'w, 's> HealthQuery<'w, 's> {
-    /// Update entity hp by effect. Negative to deal damage.
-    fn apply_entity_hp(&mut self, entity: Entity, effect: i64) {
-        if let Ok(mut hp) = self.query_health.get_mut(entity) {
-            hp.0 += effect;
+    /// Whether `entity` has health that effects can change
+    fn can_apply(&self, entity: Entity) -> bool {
+        self.query_health.contains(entity)
+    }
+
+    /// Update entity hp by effect. Negative to deal damage. Returns the overheal, if any.
+    fn apply_entity_hp(&mut self, entity: Entity, effect: i64) -> i64 {
+        match self.query_health.get_mut(entity) {
+            Ok(mut hp) => hp.apply(effect),
+            Err(_) => 0,
         }
     }
 }
 
-/// Apply damage events with respect to active target auras.
-fn sys_process_damage_effects(
+/// Apply health effects with respect to crits, active attacker & target auras and the target's
+/// resistances, logging the outcome of each.
+pub(crate) fn sys_process_damage_effects(
+    mut commands: Commands,
     effect_events: Res<Events<events::EffectQueueEvent>>,
-    mut shield_query: ShieldQuery,
+    mut rng: ResMut<CombatRng>,
+    mut aura_query: AuraQuery,
     mut health_query: HealthQuery,
+    resistances_query: Query<&shared::Resistances>,
+    mut combat_log_ev_w: EventWriter<events::CombatLogEvent>,
 ) {
     for effect in effect_events.get_reader().read(&effect_events) {
-        if effect.health_effect.is_none() {
+        let mut health_effect = match effect.health_effect {
+            Some(health_effect) => health_effect,
+            None => continue,
+        };
+        // corpses & units without health are left alone, shields included, & nothing is logged
+        if !health_query.can_apply(effect.target) {
+            continue;
+        }
+        let crit = rng.roll(effect.crit_chance);
+        if crit {
+            health_effect = health_effect * (100 + CRIT_BONUS) / 100;
+        }
+
+        let is_damaging = health_effect.is_negative();
+        let mut resisted = 0;
+        let mut absorbed = 0;
+        if is_damaging {
+            health_effect = aura_query.modify_damage(effect.origin, effect.target, health_effect);
+            let resistance = resistances_query
+                .get(effect.target)
+                .map_or(0, |resistances| resistances.get(effect.school));
+            resisted = -health_effect * resistance / 100;
+            health_effect += resisted;
+            if let Some(target_shielding) = aura_query.get_total_entity_shielding(effect.target) {
+                // damage to shields <= total shielding
+                absorbed = (-health_effect).max(0).min(target_shielding);
+                log::debug!("{:?} absorbs {} damage", effect.target, absorbed);
+                health_effect += absorbed;
+                aura_query.apply_shield_damage(effect.target, absorbed);
+            }
+        }
+        let overheal = health_query.apply_entity_hp(effect.target, health_effect);
+        if health_effect.is_negative() {
+            commands
+                .entity(effect.target)
+                .insert(LastDamagedBy(effect.origin));
+        }
+        log::debug!(
+            "{:?} {:+} {:?} hp from {:?}{}",
+            effect.target,
+            health_effect,
+            effect.school,
+            effect.origin,
+            if crit { " (crit)" } else { "" }
+        );
+        let school = effect.school;
+        let event = match (is_damaging, health_effect) {
+            (true, 0) if absorbed > 0 => combat_log::CombatEvent::Absorb {
+                school,
+                amount: absorbed,
+            },
+            (true, _) => combat_log::CombatEvent::Damage {
+                school,
+                amount: -health_effect,
+                absorbed,
+                resisted,
+                crit,
+            },
+            (false, _) => combat_log::CombatEvent::Heal {
+                school,
+                amount: health_effect,
+                overheal,
+                crit,
+            },
+        };
+        combat_log_ev_w.send(events::CombatLogEvent {
+            origin: effect.origin,
+            target: effect.target,
+            event,
+        });
+    }
+}
+
+/// Damage that gets through to health sets back casts of spells with pushback
+fn sys_process_cast_pushback(
+    mut combat_log_ev_r: EventReader<events::CombatLogEvent>,
+    spell_list: Res<assets::SpellsAsset>,
+    mut casting_query: Query<&mut shared::CastingSpell>,
+) {
+    for ev in combat_log_ev_r.read() {
+        // hits shields soak up entirely are logged as absorbs
+        if !matches!(ev.event, combat_log::CombatEvent::Damage { amount, .. } if amount > 0) {
             continue;
         }
-        let mut health_effect = effect.health_effect.unwrap();
-
-        let is_damaging = effect.health_effect.is_some_and(|f| f.is_negative());
-        let target_shielding = shield_query.get_total_entity_shielding(effect.target);
-        if is_damaging && target_shielding.is_some() {
-            let shield_damage = health_effect.abs().min(target_shielding.unwrap()); // damage to shields <= total shielding
-            log::debug!("{:?} absorbs {} damage", effect.target, shield_damage);
-            health_effect = (target_shielding.unwrap() + health_effect).min(0);
-            shield_query.apply_shield_damage(effect.target, shield_damage);
+        if let Ok(mut casting) = casting_query.get_mut(ev.target) {
+            let has_pushback = spell_list
+                .get_spell_data(casting.spell_id)
+                .is_some_and(|spell| spell.pushback_on_damage);
+            if has_pushback {
+                casting.push_back(CAST_PUSHBACK);
+                log::debug!("{:?} cast pushed back", ev.target);
+            }
         }
-        health_query.apply_entity_hp(effect.target, health_effect);
-        log::debug!("{:?} {:+} hp", effect.target, health_effect);
     }
 }
 
@@ -87,6 +218,7 @@ fn sys_process_aura_effects(
         if let Some(aura) = effect.aura_effect {
             ev_w.send(events::AddAuraEvent {
                 aura_id: aura,
+                source: effect.origin,
                 target_entity: effect.target,
             });
         }
@@ -102,16 +234,22 @@ struct TimingResource {
     processing_time: Instant,
 }
 
-pub struct EffectPlugin;
+pub struct EffectPlugin {
+    /// Seed for crit rolls
+    pub rng_seed: u64,
+}
+
 impl Plugin for EffectPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.insert_resource(TimingResource {
             processing_time: Instant::now(),
         });
+        app.insert_resource(CombatRng::seeded(self.rng_seed));
         app.add_systems(
             FixedUpdate,
             (
                 sys_process_damage_effects,
+                sys_process_cast_pushback,
                 sys_process_aura_effects,
                 sys_drain_effect_evs,
             )
@@ -127,13 +265,37 @@ mod tests {
     use bevy::{
         app::{self, Update},
         ecs::event::Events,
-        hierarchy::BuildWorldChildren,
+        math::Vec3,
+        prelude::{Entity, IntoSystemConfigs},
     };
-    use lib_spells::shared;
+    use lib_spells::{combat_log::CombatEvent, shared};
+    use std::time::Duration;
 
-    use crate::game::{effect_application, events};
+    use crate::game::{
+        assets, events,
+        test_helpers::{aura_data, auras, with_aura},
+    };
 
-    use super::sys_process_damage_effects;
+    use super::{
+        sys_process_cast_pushback, sys_process_damage_effects, CombatRng, LastDamagedBy,
+        CAST_PUSHBACK,
+    };
+
+    fn damage_app() -> app::App {
+        let mut app = app::App::new();
+        // +50% damage done, +20%, -10% & -150% damage taken
+        app.insert_resource(assets::AurasAsset(vec![
+            aura_data("empowered", 50, shared::AuraType::DamageDone),
+            aura_data("exposed", 20, shared::AuraType::DamageTaken),
+            aura_data("guarded", -10, shared::AuraType::DamageTaken),
+            aura_data("warded", -150, shared::AuraType::DamageTaken),
+        ]));
+        app.init_resource::<Events<events::EffectQueueEvent>>();
+        app.add_event::<events::CombatLogEvent>();
+        app.insert_resource(CombatRng::seeded(0));
+        app.add_systems(Update, sys_process_damage_effects);
+        app
+    }
 
     #[test]
     fn test_shielded_damage() {
@@ -144,29 +306,311 @@ mod tests {
         let total_damage = hits.clone().into_iter().reduce(|a, b| a + b).unwrap();
         let expect_hp = hp + (total_shielding + total_damage);
 
-        let mut app = app::App::new();
-        app.init_resource::<Events<events::EffectQueueEvent>>();
-        app.add_systems(Update, sys_process_damage_effects);
+        let mut app = damage_app();
 
-        let skele = app.world.spawn(shared::Health(hp)).id();
-        for shield in shields {
-            let child = app.world.spawn(effect_application::ShieldAura(shield)).id();
-            app.world.entity_mut(skele).add_child(child);
-        }
+        let auras = auras(
+            shields
+                .into_iter()
+                .map(|shield| (0, shared::AuraEffect::Shield(shield))),
+        );
+        let skele = app.world.spawn((shared::Health::new(hp), auras)).id();
 
         for hit in hits {
             app.world
                 .get_resource_mut::<Events<events::EffectQueueEvent>>()
                 .unwrap()
                 .send(events::EffectQueueEvent {
+                    origin: Entity::PLACEHOLDER,
                     aura_effect: None,
+                    school: shared::SpellSchool::Physical,
                     health_effect: Some(hit),
+                    crit_chance: 0.0,
                     target: skele,
                 });
         }
         app.update();
 
-        let remaining_hp = app.world.get::<shared::Health>(skele).unwrap().0;
+        let remaining_hp = app.world.get::<shared::Health>(skele).unwrap().current;
         assert_eq!(remaining_hp, expect_hp);
     }
+
+    #[test]
+    fn test_damage_modifiers() {
+        let mut app = damage_app();
+
+        let attacker = app
+            .world
+            .spawn(with_aura(0, shared::AuraEffect::DamageDone))
+            .id();
+        let target_auras = auras([
+            (1, shared::AuraEffect::DamageTaken),
+            (2, shared::AuraEffect::DamageTaken),
+        ]);
+        let target = app
+            .world
+            .spawn((shared::Health::new(100), target_auras))
+            .id();
+        let bystander = app.world.spawn(shared::Health::new(100)).id();
+        for (origin, target, health_effect) in [
+            (attacker, target, -20),
+            (attacker, target, 10),
+            (Entity::PLACEHOLDER, bystander, -20),
+        ] {
+            app.world.send_event(events::EffectQueueEvent {
+                origin,
+                target,
+                school: shared::SpellSchool::Physical,
+                health_effect: Some(health_effect),
+                crit_chance: 0.0,
+                aura_effect: None,
+            });
+        }
+
+        app.update();
+
+        // heals are left alone
+        assert_eq!(
+            app.world.get::<shared::Health>(target).unwrap().current,
+            100 - 33 + 10
+        );
+        assert_eq!(
+            app.world.get::<shared::Health>(bystander).unwrap().current,
+            80
+        );
+        assert_eq!(
+            app.world.get::<LastDamagedBy>(target),
+            Some(&LastDamagedBy(attacker))
+        );
+    }
+
+    #[test]
+    fn test_damage_modifiers_floor() {
+        let mut app = damage_app();
+        let warded = app
+            .world
+            .spawn((
+                shared::Health::new(100),
+                auras([
+                    (3, shared::AuraEffect::DamageTaken),
+                    (0, shared::AuraEffect::Shield(10)),
+                ]),
+            ))
+            .id();
+        app.world.send_event(events::EffectQueueEvent {
+            origin: Entity::PLACEHOLDER,
+            target: warded,
+            school: shared::SpellSchool::Physical,
+            health_effect: Some(-20),
+            crit_chance: 0.0,
+            aura_effect: None,
+        });
+
+        app.update();
+
+        // -150% damage taken stops the hit, rather than healing & eating the shield
+        assert_eq!(
+            app.world.get::<shared::Health>(warded).unwrap().current,
+            100
+        );
+        let shields: Vec<shared::AuraEffect> = app
+            .world
+            .get::<shared::Auras>(warded)
+            .unwrap()
+            .iter_of_type(shared::AuraType::Shield)
+            .map(|(_, aura)| aura.effect.clone())
+            .collect();
+        assert_eq!(shields, vec![shared::AuraEffect::Shield(10)]);
+    }
+
+    #[test]
+    fn test_crits_and_resistances() {
+        let mut app = damage_app();
+        let shield = || with_aura(0, shared::AuraEffect::Shield(10));
+        let target = app
+            .world
+            .spawn((
+                shared::Health::new(200),
+                shared::Resistances::default().with(shared::SpellSchool::Fire, 25),
+                shield(),
+            ))
+            .id();
+        let shielded = app.world.spawn((shared::Health::new(200), shield())).id();
+        for (target, school, health_effect, crit_chance) in [
+            (target, shared::SpellSchool::Fire, -40, 1.0),
+            (target, shared::SpellSchool::Frost, -40, 0.0),
+            (target, shared::SpellSchool::Holy, 30, 1.0),
+            (shielded, shared::SpellSchool::Frost, -5, 0.0),
+            (shielded, shared::SpellSchool::Holy, 25, 0.0),
+        ] {
+            app.world.send_event(events::EffectQueueEvent {
+                origin: Entity::PLACEHOLDER,
+                target,
+                school,
+                health_effect: Some(health_effect),
+                crit_chance,
+                aura_effect: None,
+            });
+        }
+
+        app.update();
+
+        // crit to 80, 20 resisted & 10 absorbed, an unresisted hit, a critical heal, then a hit
+        // soaked up entirely & a heal on full health
+        let log: Vec<CombatEvent> = app
+            .world
+            .resource_mut::<Events<events::CombatLogEvent>>()
+            .drain()
+            .map(|ev| ev.event)
+            .collect();
+        assert_eq!(
+            log,
+            vec![
+                CombatEvent::Damage {
+                    school: shared::SpellSchool::Fire,
+                    amount: 50,
+                    absorbed: 10,
+                    resisted: 20,
+                    crit: true,
+                },
+                CombatEvent::Damage {
+                    school: shared::SpellSchool::Frost,
+                    amount: 40,
+                    absorbed: 0,
+                    resisted: 0,
+                    crit: false,
+                },
+                CombatEvent::Heal {
+                    school: shared::SpellSchool::Holy,
+                    amount: 60,
+                    overheal: 0,
+                    crit: true,
+                },
+                CombatEvent::Absorb {
+                    school: shared::SpellSchool::Frost,
+                    amount: 5,
+                },
+                CombatEvent::Heal {
+                    school: shared::SpellSchool::Holy,
+                    amount: 25,
+                    overheal: 25,
+                    crit: false,
+                },
+            ]
+        );
+        assert_eq!(
+            app.world.get::<shared::Health>(target).unwrap().current,
+            170
+        );
+        assert_eq!(
+            app.world.get::<shared::Health>(shielded).unwrap().current,
+            200
+        );
+    }
+
+    #[test]
+    fn test_no_health_applied() {
+        let mut app = damage_app();
+        let shield = with_aura(0, shared::AuraEffect::Shield(10));
+        let corpse = app
+            .world
+            .spawn((
+                shared::Health {
+                    current: 0,
+                    max: 100,
+                },
+                shared::Dead::new(Duration::from_secs(5), Vec3::ZERO),
+                shield,
+            ))
+            .id();
+        let projectile = app.world.spawn_empty().id();
+        for target in [corpse, projectile] {
+            for health_effect in [-20, 20] {
+                app.world.send_event(events::EffectQueueEvent {
+                    origin: Entity::PLACEHOLDER,
+                    target,
+                    school: shared::SpellSchool::Physical,
+                    health_effect: Some(health_effect),
+                    crit_chance: 0.0,
+                    aura_effect: None,
+                });
+            }
+        }
+
+        app.update();
+
+        assert!(app
+            .world
+            .resource::<Events<events::CombatLogEvent>>()
+            .is_empty());
+        assert_eq!(app.world.get::<shared::Health>(corpse).unwrap().current, 0);
+        let shield = app.world.get::<shared::Auras>(corpse).unwrap();
+        assert_eq!(
+            shield.iter().next().unwrap().1.effect,
+            shared::AuraEffect::Shield(10)
+        );
+    }
+
+    #[test]
+    fn test_seeded_crits_repeat() {
+        let rolls = |seed| {
+            let mut rng = CombatRng::seeded(seed);
+            (0..20).map(|_| rng.roll(0.5)).collect::<Vec<bool>>()
+        };
+        assert_eq!(rolls(7), rolls(7));
+        assert!(rolls(7).contains(&true) && rolls(7).contains(&false));
+    }
+
+    #[test]
+    fn test_cast_pushback() {
+        let cast_time = std::time::Duration::from_secs(2);
+        let mut app = damage_app();
+        app.insert_resource(assets::SpellsAsset(vec![
+            assets::SpellData::new("steady".into(), 2000),
+            {
+                let mut spell = assets::SpellData::new("fragile".into(), 2000);
+                spell.pushback_on_damage = true;
+                spell
+            },
+        ]));
+        app.add_systems(
+            Update,
+            sys_process_cast_pushback.after(sys_process_damage_effects),
+        );
+
+        // the last caster's shield soaks up the whole hit
+        let shielded = with_aura(0, shared::AuraEffect::Shield(10));
+        let mut casts = vec![];
+        for (spell_id, auras) in [(0, None), (1, None), (1, Some(shielded))] {
+            let mut casting =
+                shared::CastingSpell::new(spell_id.into(), Entity::PLACEHOLDER, cast_time);
+            casting.cast_timer.tick(cast_time / 2);
+            let mut caster = app.world.spawn((casting, shared::Health::new(100)));
+            if let Some(auras) = auras {
+                caster.insert(auras);
+            }
+            casts.push(caster.id());
+        }
+        for &target in casts.iter() {
+            app.world.send_event(events::EffectQueueEvent {
+                origin: Entity::PLACEHOLDER,
+                aura_effect: None,
+                school: shared::SpellSchool::Physical,
+                health_effect: Some(-5),
+                crit_chance: 0.0,
+                target,
+            });
+        }
+        app.update();
+
+        let elapsed = |entity| {
+            app.world
+                .get::<shared::CastingSpell>(entity)
+                .unwrap()
+                .cast_timer
+                .elapsed()
+        };
+        assert_eq!(elapsed(casts[0]), cast_time / 2);
+        assert_eq!(elapsed(casts[1]), cast_time / 2 - CAST_PUSHBACK);
+        assert_eq!(elapsed(casts[2]), cast_time / 2);
+    }
 }
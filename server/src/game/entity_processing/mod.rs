@@ -1,28 +1,135 @@
-use bevy::prelude::*;
-use lib_spells::shared;
+use std::time::Duration;
+
+use bevy::{log, prelude::*};
+use lib_spells::{combat_log, shared};
+
+use crate::game::{assets, effect_processing, events};
 
 use super::ServerSets;
 
-/// Tick auras & remove expired
-fn sys_tick_clean_auras(
+/// Where & how soon dead players come back
+#[derive(Resource, Debug, Clone)]
+pub struct RespawnSettings {
+    pub delay: Duration,
+    pub spawn_point: Vec3,
+}
+
+impl Default for RespawnSettings {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs(5),
+            spawn_point: Vec3::ZERO,
+        }
+    }
+}
+
+/// Remove expired auras
+fn sys_clean_expired_auras(
+    mut query: Query<(Entity, &mut shared::Auras)>,
+    mut combat_log_ev_w: EventWriter<events::CombatLogEvent>,
+) {
+    for (entity, mut auras) in query.iter_mut() {
+        for aura in auras.remove_expired() {
+            log::debug!("{:?} aura {} expired", entity, aura.id);
+            combat_log_ev_w.send(events::CombatLogEvent {
+                origin: aura.source,
+                target: entity,
+                event: combat_log::CombatEvent::AuraRemoved { aura_id: aura.id },
+            });
+        }
+    }
+}
+
+type DyingQuery = (
+    Entity,
+    &'static shared::Health,
+    Has<shared::Player>,
+    Option<&'static mut shared::Auras>,
+    Option<&'static effect_processing::LastDamagedBy>,
+);
+
+/// Players with no health left die, losing their cast & auras removed on death, and wait to
+/// respawn. Anything else is despawned, recursively (!!) Deaths are credited to whoever dealt
+/// the last damage.
+fn sys_handle_deaths(
     mut commands: Commands,
-    mut query: Query<(Entity, &mut shared::Aura)>,
-    time: Res<Time>,
+    settings: Res<RespawnSettings>,
+    auras_asset: Res<assets::AurasAsset>,
+    mut query: Query<DyingQuery, Without<shared::Dead>>,
+    mut combat_log_ev_w: EventWriter<events::CombatLogEvent>,
 ) {
-    for (entity, mut effect) in query.iter_mut() {
-        effect.duration.tick(time.delta());
-        if effect.duration.finished() {
+    for (entity, health, is_player, auras, killer) in query.iter_mut() {
+        if health.current > 0 {
+            continue;
+        }
+        combat_log_ev_w.send(events::CombatLogEvent {
+            origin: killer.map_or(entity, |killer| killer.0),
+            target: entity,
+            event: combat_log::CombatEvent::Death,
+        });
+        if !is_player {
             commands.entity(entity).despawn_recursive();
+            continue;
+        }
+
+        log::info!("{:?} died", entity);
+        if let Some(mut auras) = auras {
+            let lost: Vec<shared::AuraSlot> = auras
+                .iter()
+                .filter(|(_, aura)| {
+                    auras_asset
+                        .lookup(aura.id)
+                        .is_none_or(|aura_data| aura_data.remove_on_death)
+                })
+                .map(|(slot, _)| slot)
+                .collect();
+            for aura in lost.into_iter().filter_map(|slot| auras.remove(slot)) {
+                combat_log_ev_w.send(events::CombatLogEvent {
+                    origin: entity,
+                    target: entity,
+                    event: combat_log::CombatEvent::AuraRemoved { aura_id: aura.id },
+                });
+            }
         }
+        commands
+            .entity(entity)
+            .remove::<(
+                shared::CastingSpell,
+                shared::ChannelingSpell,
+                effect_processing::LastDamagedBy,
+            )>()
+            .insert((
+                shared::Dead::new(settings.delay, settings.spawn_point),
+                shared::Velocity::default(),
+            ));
     }
 }
 
-/// Kills entities with no health, recursively (!!)
-fn sys_despawn_dead(mut commands: Commands, query: Query<(Entity, &shared::Health)>) {
-    for (entity, health) in query.iter() {
-        if health.0 <= 0 {
-            commands.entity(entity).despawn_recursive();
+/// Bring dead players back at full health once their timer runs out
+fn sys_respawn(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut query: Query<(
+        Entity,
+        &mut shared::Dead,
+        &mut shared::Health,
+        &mut shared::Position,
+    )>,
+) {
+    for (entity, mut dead, mut health, mut pos) in query.iter_mut() {
+        if !dead.respawn_timer.tick(time.delta()).finished() {
+            continue;
         }
+        health.current = health.max;
+        pos.0 = dead.spawn_point;
+        commands.entity(entity).remove::<shared::Dead>();
+        log::info!("{:?} respawned", entity);
+    }
+}
+
+fn sys_regen_power(mut query: Query<&mut shared::Power>, time: Res<Time>) {
+    for mut power in query.iter_mut() {
+        power.regen(time.delta());
     }
 }
 
@@ -30,9 +137,140 @@ pub struct EntityProcessingPlugin;
 
 impl Plugin for EntityProcessingPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
+        app.init_resource::<RespawnSettings>();
         app.add_systems(
             FixedUpdate,
-            (sys_tick_clean_auras, sys_despawn_dead).in_set(ServerSets::EffectProcessing),
+            (
+                sys_clean_expired_auras,
+                sys_handle_deaths.after(effect_processing::sys_process_damage_effects),
+            )
+                .in_set(ServerSets::EffectProcessing),
+        );
+        app.add_systems(
+            FixedUpdate,
+            (sys_regen_power, sys_respawn).in_set(ServerSets::EntityProcessing),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sys_handle_deaths, sys_respawn, RespawnSettings};
+    use crate::game::{assets, effect_processing, events};
+    use bevy::{app, ecs::event::Events, prelude::*};
+    use lib_spells::{combat_log, shared};
+    use std::time::Duration;
+
+    #[test]
+    fn test_death_and_respawn() {
+        let mut app = app::App::new();
+        app.insert_resource(assets::AurasAsset(vec![
+            assets::AuraData::new(
+                "shield".into(),
+                "Shield".into(),
+                20,
+                Duration::from_secs(10),
+                shared::AuraType::Shield,
+            ),
+            assets::AuraData::new(
+                "soulstone".into(),
+                "Soulstone".into(),
+                0,
+                Duration::from_secs(600),
+                shared::AuraType::Shield,
+            )
+            .with_remove_on_death(false),
+        ]));
+        let settings = RespawnSettings {
+            delay: Duration::from_secs(5),
+            spawn_point: Vec3::new(1.0, 0.0, 2.0),
+        };
+        app.insert_resource(settings.clone());
+        app.init_resource::<Time>();
+        app.add_event::<events::CombatLogEvent>();
+        app.add_systems(Update, (sys_handle_deaths, sys_respawn).chain());
+
+        let mut auras = shared::Auras::default();
+        for aura_id in [0, 1] {
+            auras.add(shared::Aura::new(
+                aura_id.into(),
+                Entity::PLACEHOLDER,
+                Duration::from_secs(10),
+                shared::AuraEffect::Shield(0),
+            ));
+        }
+        let player = app
+            .world
+            .spawn((
+                shared::Player,
+                shared::Health {
+                    current: -5,
+                    max: 80,
+                },
+                shared::Position(Vec3::new(10.0, 0.0, 10.0)),
+                shared::Velocity(Vec3::X),
+                shared::CastingSpell::new(
+                    0.into(),
+                    shared::SpellTarget::SelfRadius,
+                    Duration::from_secs(1),
+                ),
+                auras,
+            ))
+            .id();
+        let killer = app.world.spawn_empty().id();
+        app.world
+            .entity_mut(player)
+            .insert(effect_processing::LastDamagedBy(killer));
+        let skele = app.world.spawn(shared::Health::new(0)).id();
+
+        app.update();
+        assert!(app.world.get_entity(skele).is_none());
+        // the player's death goes to their killer, the skeleton's to nobody
+        let deaths: Vec<(Entity, Entity)> = app
+            .world
+            .resource_mut::<Events<events::CombatLogEvent>>()
+            .drain()
+            .filter(|ev| ev.event == combat_log::CombatEvent::Death)
+            .map(|ev| (ev.origin, ev.target))
+            .collect();
+        assert!(deaths.contains(&(killer, player)));
+        assert!(deaths.contains(&(skele, skele)));
+        assert!(app
+            .world
+            .get::<effect_processing::LastDamagedBy>(player)
+            .is_none());
+        let dead = app.world.get::<shared::Dead>(player).unwrap();
+        assert_eq!(dead.spawn_point, settings.spawn_point);
+        assert!(app.world.get::<shared::CastingSpell>(player).is_none());
+        assert_eq!(
+            app.world.get::<shared::Velocity>(player).unwrap().0,
+            Vec3::ZERO
+        );
+        let remaining: Vec<_> = app
+            .world
+            .get::<shared::Auras>(player)
+            .unwrap()
+            .iter()
+            .map(|(_, aura)| aura.id)
+            .collect();
+        assert_eq!(remaining, vec![1.into()]);
+
+        // not yet
+        app.world
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_secs(4));
+        app.update();
+        assert!(app.world.get::<shared::Dead>(player).is_some());
+
+        app.world
+            .resource_mut::<Time>()
+            .advance_by(Duration::from_secs(1));
+        app.update();
+        assert!(app.world.get::<shared::Dead>(player).is_none());
+        assert_eq!(app.world.get::<shared::Health>(player).unwrap().current, 80);
+        assert_eq!(
+            app.world.get::<shared::Position>(player).unwrap().0,
+            settings.spawn_point
         );
     }
 }
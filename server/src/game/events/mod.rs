@@ -1,44 +1,109 @@
 /// general game events
 use bevy::prelude::*;
 
-use lib_spells::shared;
-/// Queue an effect onto the target
+use lib_spells::{combat_log, shared};
+use std::time::Duration;
+
+use crate::game::assets;
+/// Queue an effect onto the target, credited to `origin`. Health effects may crit, with a
+/// `crit_chance` from 0 to 1.
 #[derive(Event, Debug, Copy, Clone)]
 pub struct EffectQueueEvent {
+    pub origin: Entity,
     pub target: Entity,
+    pub school: shared::SpellSchool,
     pub health_effect: Option<i64>,
+    pub crit_chance: f32,
     pub aura_effect: Option<shared::AuraID>,
 }
 
+/// Something worth recording happened between `origin` & `target`, stamped with the server tick
+/// when it's sent out
+#[derive(Event, Debug, Copy, Clone, PartialEq)]
+pub struct CombatLogEvent {
+    pub origin: Entity,
+    pub target: Entity,
+    pub event: combat_log::CombatEvent,
+}
+
+impl CombatLogEvent {
+    pub fn entry(&self, tick: u64) -> combat_log::CombatLogEntry {
+        combat_log::CombatLogEntry {
+            tick,
+            origin: self.origin,
+            target: self.target,
+            event: self.event,
+        }
+    }
+}
+
 /// `spell_id` should be applied to `target`
 #[derive(Clone, Copy, Debug, Event)]
 pub struct SpellApplicationEvent {
     pub origin: Entity,
-    pub target: Entity,
+    pub target: shared::SpellTarget,
+    pub spell_id: shared::SpellID,
+}
+
+/// `spell_id` cast by `origin` has reached `target`, & should affect whatever it hits
+#[derive(Clone, Copy, Debug, Event)]
+pub struct SpellLandedEvent {
+    pub origin: Entity,
+    pub target: shared::SpellTarget,
     pub spell_id: shared::SpellID,
 }
 
-/// Request to add an aura child to the given entity
+/// `caster`'s cast of `spell_id` was refused or stopped
+#[derive(Event, Debug, Copy, Clone)]
+pub struct CastRejectedEvent {
+    pub caster: Entity,
+    pub spell_id: shared::SpellID,
+    pub reason: shared::CastRejectedReason,
+}
+
+/// Cancel `target`'s cast & lock its school out for `lockout`, on behalf of `origin`
+#[derive(Event, Debug, Copy, Clone)]
+pub struct InterruptEvent {
+    pub origin: Entity,
+    pub target: Entity,
+    pub lockout: Duration,
+}
+
+/// Request to add an aura to the given entity, applied by `source`
 #[derive(Event, Debug)]
 pub struct AddAuraEvent {
     pub aura_id: shared::AuraID,
+    pub source: Entity,
     pub target_entity: Entity,
 }
 
-/// Request to drop an aura child from the given entity
+/// Request to drop an aura from the given entity
 #[derive(Event, Debug)]
 pub struct RemoveAuraEvent {
     pub aura_id: shared::AuraID,
     pub target_entity: Entity,
 }
 
+/// Request to drop whatever auras `dispel` matches from the given entity, on behalf of `origin`
+#[derive(Event, Debug)]
+pub struct DispelEvent {
+    pub origin: Entity,
+    pub dispel: assets::Dispel,
+    pub target_entity: Entity,
+}
+
 pub struct GameEventsPlugin;
 
 impl Plugin for GameEventsPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         app.init_resource::<Events<EffectQueueEvent>>() // we want to manually clear this one
             .add_event::<SpellApplicationEvent>()
+            .add_event::<SpellLandedEvent>()
+            .add_event::<CastRejectedEvent>()
+            .add_event::<InterruptEvent>()
             .add_event::<AddAuraEvent>()
-            .add_event::<RemoveAuraEvent>();
+            .add_event::<RemoveAuraEvent>()
+            .add_event::<DispelEvent>()
+            .add_event::<CombatLogEvent>();
     }
 }
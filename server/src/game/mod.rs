@@ -1,10 +1,11 @@
 use clap::{Parser, Subcommand};
-use std::error::Error;
+use std::{error::Error, path::PathBuf};
 
 /// snapshots of world
 use bevy::{app, log::LogPlugin, prelude::*};
 
 pub mod assets;
+pub mod combat_log;
 pub mod effect_application;
 pub mod effect_creation;
 pub mod effect_processing;
@@ -12,6 +13,9 @@ pub mod entity_processing;
 pub mod events;
 pub mod net;
 pub mod scenes;
+pub mod spatial;
+#[cfg(test)]
+mod test_helpers;
 
 #[derive(Parser)]
 struct Cli {
@@ -19,6 +23,18 @@ struct Cli {
     #[arg(short, long)]
     password: Option<String>,
 
+    // Seed for combat rolls, so fights can be replayed. Random if not specified.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    // Write the combat log to this file, one JSON entry per line.
+    #[arg(long)]
+    combat_log: Option<PathBuf>,
+
+    // Send everything over TCP instead of offering clients UDP for packets & world state.
+    #[arg(long)]
+    tcp_only: bool,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -28,6 +44,17 @@ enum Commands {
     Scene { name: String },
 }
 
+/// Fixed updates per second
+pub const TICK_RATE: f64 = 20.0;
+
+/// Number of fixed updates the server has run
+#[derive(Resource, Debug, Default)]
+pub struct ServerTick(pub u64);
+
+fn sys_advance_tick(mut tick: ResMut<ServerTick>) {
+    tick.0 += 1;
+}
+
 /// Defines ordering of system processing across the game server.
 #[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ServerSets {
@@ -57,6 +84,14 @@ pub fn run_game_server() -> Result<(), Box<dyn Error>> {
         },
     }
 
+    let rng_seed = cli.seed.unwrap_or_else(|| fastrand::u64(..));
+    println!("combat rng seed {}", rng_seed);
+
+    if let Some(path) = &cli.combat_log {
+        println!("writing combat log to {}", path.display());
+        app.insert_resource(combat_log::CombatLogFile::create(path)?);
+    }
+
     if cli.password.is_some() {
         println!("running with password");
     } else {
@@ -65,18 +100,24 @@ pub fn run_game_server() -> Result<(), Box<dyn Error>> {
 
     app.add_plugins((
         MinimalPlugins,
+        AssetPlugin::default(),
         LogPlugin {
             filter: "".into(),
             level: bevy::log::Level::DEBUG,
             update_subscriber: None,
         },
         events::GameEventsPlugin,
-        net::NetPlugin { server_password: cli.password },
-        effect_processing::EffectPlugin,
+        net::NetPlugin {
+            server_password: cli.password,
+            udp: !cli.tcp_only,
+        },
+        effect_processing::EffectPlugin { rng_seed },
         effect_creation::EffectCreationPlugin,
         effect_application::EffectApplicationPlugin,
         entity_processing::EntityProcessingPlugin,
         assets::AssetsPlugin,
+        spatial::SpatialPlugin,
+        combat_log::CombatLogPlugin,
     ))
     .configure_sets(
         FixedUpdate,
@@ -88,9 +129,15 @@ pub fn run_game_server() -> Result<(), Box<dyn Error>> {
             ServerSets::EffectApplication,
             ServerSets::NetworkSend,
         )
-            .chain(),
+            .chain()
+            .run_if(assets::definitions_loaded),
+    )
+    .init_resource::<ServerTick>()
+    .add_systems(
+        FixedUpdate,
+        sys_advance_tick.before(ServerSets::NetworkFetch),
     )
-    .insert_resource(Time::<Fixed>::from_hz(20.0))
+    .insert_resource(Time::<Fixed>::from_hz(TICK_RATE))
     .run();
     Ok(())
 }
@@ -1,12 +1,16 @@
 mod server;
 
-use crate::game;
-use bevy::{log, prelude::*, tasks::IoTaskPool};
+use crate::game::{self, assets};
+use bevy::{log, prelude::*};
 use lib_spells::{
+    combat_log,
     net::{self, packet},
     shared,
 };
-use std::{collections::HashMap, sync::mpsc, time::Duration};
+use std::{collections::HashMap, sync::mpsc, thread, time::Duration};
+
+/// Most combat log entries sent to a client in one message
+const COMBAT_LOG_BATCH: usize = 256;
 
 #[derive(Component, Debug, Default)]
 struct LastPacketTime(Option<Duration>);
@@ -14,6 +18,10 @@ struct LastPacketTime(Option<Duration>);
 #[derive(Component, Debug, Default)]
 struct LastPacketSequence(u8);
 
+/// Tick of the last world state snapshot the client says it applied
+#[derive(Component, Debug, Default)]
+struct LastAckedSnapshot(Option<u64>);
+
 #[derive(Component, Debug)]
 struct ServerPlayer(server::Token);
 
@@ -22,11 +30,16 @@ struct ServerPlayerBundle {
     sp: ServerPlayer,
     lps: LastPacketSequence,
     lpt: LastPacketTime,
+    acked: LastAckedSnapshot,
     name: shared::Name,
     pos: shared::Position,
     player: shared::Player,
     vel: shared::Velocity,
     hp: shared::Health,
+    caster: shared::SpellCaster,
+    cooldowns: shared::Cooldowns,
+    power: shared::Power,
+    resistances: shared::Resistances,
 }
 
 impl ServerPlayerBundle {
@@ -35,10 +48,15 @@ impl ServerPlayerBundle {
             sp: ServerPlayer(token),
             lps: Default::default(),
             lpt: Default::default(),
+            acked: Default::default(),
             pos: Default::default(),
             vel: Default::default(),
             player: Default::default(),
-            hp: shared::Health(100),
+            hp: shared::Health::new(100),
+            caster: shared::SpellCaster,
+            cooldowns: Default::default(),
+            power: shared::Power::new(100.0, 4.0),
+            resistances: Default::default(),
             name: shared::Name(format!("Player {}", token)),
         }
     }
@@ -47,7 +65,7 @@ impl ServerPlayerBundle {
 fn sys_process_incoming(
     mut commands: Commands,
     server: NonSend<ServerComms>,
-    server_player_query: Query<(Entity, &ServerPlayer)>,
+    mut server_player_query: Query<(Entity, &ServerPlayer, &mut LastAckedSnapshot)>,
 ) -> HashMap<Entity, Vec<packet::Packet>> {
     let mut client_packets: HashMap<Entity, Vec<packet::Packet>> = HashMap::default();
 
@@ -57,12 +75,21 @@ fn sys_process_incoming(
                 commands.spawn(ServerPlayerBundle::new(token));
             }
             server::Incoming::Left(token) => {
-                if let Some((entity, _)) = server_player_query.iter().find(|(_, p)| p.0 == token) {
+                let player = server_player_query.iter().find(|(_, p, _)| p.0 == token);
+                if let Some((entity, ..)) = player {
                     commands.entity(entity).despawn_recursive();
                 }
             }
             server::Incoming::Data(token, packet) => {
-                if let Some((entity, _)) = server_player_query.iter().find(|(_, p)| p.0 == token) {
+                let player = server_player_query
+                    .iter_mut()
+                    .find(|(_, p, _)| p.0 == token);
+                if let Some((entity, _, mut acked)) = player {
+                    // acks only matter to state replication, so don't bother the game with them
+                    if let packet::PacketData::AckSnapshot(tick) = packet.command_data {
+                        acked.0 = acked.0.max(Some(tick));
+                        continue;
+                    }
                     if let Some(packets) = client_packets.get_mut(&entity) {
                         packets.push(packet);
                     } else {
@@ -77,17 +104,179 @@ fn sys_process_incoming(
     client_packets
 }
 
+type CasterQuery = (
+    Has<shared::Dead>,
+    Has<shared::CastingSpell>,
+    Has<shared::ChannelingSpell>,
+    Option<&'static shared::Cooldowns>,
+    Option<&'static shared::Power>,
+    Option<&'static shared::Auras>,
+);
+
+/// Start casting `cast` on `caster` if the spell exists & the caster is able to cast. The global
+/// cooldown starts once the cast's target & range are validated.
+fn start_client_cast(
+    commands: &mut Commands,
+    spells_asset: &assets::SpellsAsset,
+    q_casters: &mut Query<CasterQuery, With<shared::SpellCaster>>,
+    caster: Entity,
+    cast: packet::SpellCast,
+) -> Result<(), shared::CastRejectedReason> {
+    let spell = spells_asset
+        .get_spell_data(cast.spell_id)
+        .ok_or(shared::CastRejectedReason::UnknownSpell)?;
+    let caster_state = q_casters
+        .get_mut(caster)
+        .map_err(|_| shared::CastRejectedReason::InvalidTarget)?;
+    let (is_dead, is_casting, is_channeling, cooldowns, power, auras) = caster_state;
+    if is_dead {
+        return Err(shared::CastRejectedReason::Dead);
+    }
+    if let Some(reason) = auras.and_then(|auras| auras.cast_blocker()) {
+        return Err(reason);
+    }
+    if is_casting || is_channeling {
+        return Err(shared::CastRejectedReason::AlreadyCasting);
+    }
+    if cooldowns.is_some_and(|cooldowns| !cooldowns.is_ready(cast.spell_id, spell.school)) {
+        return Err(shared::CastRejectedReason::OnCooldown);
+    }
+    if power.is_some_and(|power| !power.can_afford(spell.cost)) {
+        return Err(shared::CastRejectedReason::NotEnoughResource);
+    }
+    if cast
+        .target
+        .entity()
+        .is_some_and(|target| commands.get_entity(target).is_none())
+    {
+        return Err(shared::CastRejectedReason::InvalidTarget);
+    }
+    commands.entity(caster).insert(shared::CastingSpell::new(
+        cast.spell_id,
+        cast.target,
+        spell.cast_time,
+    ));
+    Ok(())
+}
+
+type MovementQuery = (
+    Entity,
+    &'static mut shared::Position,
+    &'static mut shared::Velocity,
+    &'static mut LastPacketTime,
+    &'static mut LastPacketSequence,
+    Has<shared::Dead>,
+    Option<&'static shared::CastingSpell>,
+    Option<&'static shared::ChannelingSpell>,
+    Option<&'static shared::MovementSpeed>,
+);
+
+/// Cancel `caster`'s cast or channel of `spell_id`
+fn stop_cast(
+    commands: &mut Commands,
+    combat_log_ev_w: &mut EventWriter<game::events::CombatLogEvent>,
+    caster: Entity,
+    spell_id: shared::SpellID,
+) {
+    commands
+        .entity(caster)
+        .remove::<(shared::CastingSpell, shared::ChannelingSpell)>();
+    combat_log_ev_w.send(game::events::CombatLogEvent {
+        origin: caster,
+        target: caster,
+        event: combat_log::CombatEvent::CastStop { spell_id },
+    });
+}
+
+/// Cancel `caster`'s cast or channel if its spell can't be cast on the move
+fn interrupt_moving_cast(
+    commands: &mut Commands,
+    combat_log_ev_w: &mut EventWriter<game::events::CombatLogEvent>,
+    spells_asset: &assets::SpellsAsset,
+    caster: Entity,
+    spell_id: Option<shared::SpellID>,
+) {
+    let spell_id = match spell_id {
+        Some(spell_id) => spell_id,
+        None => return,
+    };
+    let interrupts = spells_asset
+        .get_spell_data(spell_id)
+        .is_some_and(|spell| spell.interrupt_on_move);
+    if interrupts {
+        log::debug!("{:?} cast of {} interrupted by movement", caster, spell_id);
+        stop_cast(commands, combat_log_ev_w, caster, spell_id);
+    }
+}
+
 fn sys_process_client_packets(
     In(packets): In<HashMap<Entity, Vec<packet::Packet>>>,
-    mut q_velocity_pos: Query<(
-        Entity,
-        &mut shared::Position,
-        &mut shared::Velocity,
-        &mut LastPacketTime,
-        &mut LastPacketSequence,
-    )>,
+    mut commands: Commands,
+    spells_asset: Res<assets::SpellsAsset>,
+    mut q_casters: Query<CasterQuery, With<shared::SpellCaster>>,
+    mut rejected_ev_w: EventWriter<game::events::CastRejectedEvent>,
+    mut q_velocity_pos: Query<MovementQuery>,
+    mut combat_log_ev_w: EventWriter<game::events::CombatLogEvent>,
 ) {
-    for (entity, mut pos, mut vel, mut last_t, mut last_sequence) in q_velocity_pos.iter_mut() {
+    for (&entity, entity_packets) in packets.iter() {
+        for p in entity_packets.iter() {
+            match (p.command_type, p.command_data) {
+                (packet::PacketType::CastSpell, packet::PacketData::CastSpell(cast)) => {
+                    let started = start_client_cast(
+                        &mut commands,
+                        &spells_asset,
+                        &mut q_casters,
+                        entity,
+                        cast,
+                    );
+                    if let Err(reason) = started {
+                        log::info!(
+                            "{:?} cast of {} at {:?} rejected: {}",
+                            entity,
+                            cast.spell_id,
+                            cast.target,
+                            reason
+                        );
+                        rejected_ev_w.send(game::events::CastRejectedEvent {
+                            caster: entity,
+                            spell_id: cast.spell_id,
+                            reason,
+                        });
+                    }
+                }
+                (packet::PacketType::CancelCast, _) => {
+                    let spell_id = match q_velocity_pos.get(entity) {
+                        Ok((.., Some(casting), _, _)) => casting.spell_id,
+                        Ok((.., Some(channeling), _)) => channeling.spell_id,
+                        _ => continue,
+                    };
+                    stop_cast(&mut commands, &mut combat_log_ev_w, entity, spell_id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    for (
+        entity,
+        mut pos,
+        mut vel,
+        mut last_t,
+        mut last_sequence,
+        is_dead,
+        casting,
+        channeling,
+        movement_speed,
+    ) in q_velocity_pos.iter_mut()
+    {
+        let spell_id = casting
+            .map(|c| c.spell_id)
+            .or(channeling.map(|c| c.spell_id));
+        // the dead, rooted & stunned stay in place, slows & hastes scale speed
+        let speed = match is_dead {
+            true => 0.0,
+            false => movement_speed.map_or(1.0, |speed| speed.0),
+        };
         let entity_packets = packets.get(&entity);
         let movement_packets = entity_packets.iter().flat_map(|p| {
             p.iter().filter_map(|p| match p.command_data {
@@ -96,12 +285,28 @@ fn sys_process_client_packets(
             })
         });
 
+        // the cast only goes away once commands are applied, so stop it once per tick
+        let mut interrupted = false;
         for (time, seq, dir) in movement_packets {
+            // inputs sent over TCP before a UDP session started can land after ones sent over it
+            if last_t.0.is_some_and(|lts| time < lts) {
+                continue;
+            }
             if let Some(lts) = last_t.0 {
                 let t = (time - lts).as_secs_f32();
                 pos.0 += vel.0 * t;
             }
-            vel.0 = Vec3::from(dir).normalize_or_zero();
+            vel.0 = Vec3::from(dir).normalize_or_zero() * speed;
+            if vel.0 != Vec3::ZERO && !interrupted {
+                interrupt_moving_cast(
+                    &mut commands,
+                    &mut combat_log_ev_w,
+                    &spells_asset,
+                    entity,
+                    spell_id,
+                );
+                interrupted = true;
+            }
             last_t.0 = Some(time);
             last_sequence.0 = seq;
             log::debug!("velocity: {}, pos: {}", vel.0, pos.0);
@@ -109,23 +314,75 @@ fn sys_process_client_packets(
     }
 }
 
+/// Send each player what changed since the last state they acknowledged, or everything if
+/// they haven't acknowledged one we still remember.
 fn sys_broadcast_state(
     In(world_state): In<net::WorldState>,
     server: NonSend<ServerComms>,
-    players_query: Query<(&ServerPlayer, &LastPacketSequence)>,
+    tick: Res<game::ServerTick>,
+    mut history: ResMut<net::SnapshotHistory>,
+    players_query: Query<(&ServerPlayer, &LastPacketSequence, &LastAckedSnapshot)>,
 ) {
-    for (player, sequence) in players_query.iter() {
+    // players tend to be on the same baseline, so only build each snapshot once
+    let mut snapshots: HashMap<Option<u64>, net::Snapshot> = HashMap::default();
+    for (player, sequence, acked) in players_query.iter() {
+        let baseline = acked
+            .0
+            .and_then(|acked| history.get(acked).map(|state| (acked, state)));
+        let snapshot = snapshots
+            .entry(baseline.map(|(acked, _)| acked))
+            .or_insert_with(|| world_state.snapshot(tick.0, baseline));
         server
             .outgoing
             .send(server::Outgoing::ClientState(
                 player.0,
                 server::ClientStateUpdate {
                     seq: sequence.0,
-                    world_state: world_state.clone(),
+                    snapshot: snapshot.clone(),
                 },
             ))
             .unwrap();
     }
+    history.push(tick.0, world_state);
+}
+
+/// Let players know why their casts were rejected
+fn sys_send_cast_rejections(
+    server: NonSend<ServerComms>,
+    mut rejected_ev_r: EventReader<game::events::CastRejectedEvent>,
+    players_query: Query<&ServerPlayer>,
+) {
+    for ev in rejected_ev_r.read() {
+        if let Ok(player) = players_query.get(ev.caster) {
+            server
+                .outgoing
+                .send(server::Outgoing::CastRejected(
+                    player.0,
+                    ev.spell_id,
+                    ev.reason,
+                ))
+                .unwrap();
+        }
+    }
+}
+
+/// Send every player what happened in combat this tick
+fn sys_send_combat_log(
+    server: NonSend<ServerComms>,
+    tick: Res<game::ServerTick>,
+    mut combat_log_ev_r: EventReader<game::events::CombatLogEvent>,
+    players_query: Query<&ServerPlayer>,
+) {
+    let entries: Vec<combat_log::CombatLogEntry> =
+        combat_log_ev_r.read().map(|ev| ev.entry(tick.0)).collect();
+    for batch in entries.chunks(COMBAT_LOG_BATCH) {
+        for player in players_query.iter() {
+            server
+                .outgoing
+                .send(server::Outgoing::CombatLog(player.0, batch.to_vec()))
+                .unwrap();
+        }
+    }
 }
 
 fn sys_on_player_spawned(
@@ -159,28 +416,39 @@ impl ServerComms {
 
 pub struct NetPlugin {
     pub server_password: Option<String>,
+    /// Offer clients UDP for packets & world state
+    pub udp: bool,
 }
 
 impl Plugin for NetPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         let (broadcast_tx, broadcast_rx) = mpsc::channel();
         let (incoming_tx, incoming_rx) = mpsc::channel();
-        let mut server = server::Server::create().unwrap();
+        let mut server = server::Server::create(self.udp).unwrap();
 
         let password = self.server_password.clone();
-        IoTaskPool::get()
-            .spawn(async move {
-                log::debug!("client event loop task spawned");
-                if let Err(err) = server.event_loop(incoming_tx, broadcast_rx, password) {
+        // the event loop blocks forever, so keep it off the IO task pool (asset loading uses it)
+        thread::Builder::new()
+            .name("client event loop".into())
+            .spawn(move || {
+                log::debug!("client event loop thread spawned");
+                let exited =
+                    server.event_loop(incoming_tx, broadcast_rx, password, game::TICK_RATE);
+                if let Err(err) = exited {
                     log::error!("client event loop exited: {}", err);
                 }
             })
-            .detach();
+            .unwrap();
 
         app.insert_non_send_resource(ServerComms::new(incoming_rx, broadcast_tx));
+        app.init_resource::<net::SnapshotHistory>();
         app.add_systems(
             FixedUpdate,
-            ((net::query_world_state.pipe(sys_broadcast_state).map(drop)),)
+            (
+                net::query_world_state.pipe(sys_broadcast_state).map(drop),
+                sys_send_cast_rejections,
+                sys_send_combat_log,
+            )
                 .in_set(game::ServerSets::NetworkSend),
         );
         app.add_systems(
@@ -194,3 +462,74 @@ impl Plugin for NetPlugin {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::{event::Events, system::RunSystemOnce};
+
+    fn movement(ms: u64, seq: u8, dir: u8) -> packet::Packet {
+        packet::Packet {
+            timestamp: Duration::from_millis(ms),
+            seq,
+            command_type: packet::PacketType::Move,
+            command_data: packet::PacketData::Movement(packet::MovementDirection(dir)),
+        }
+    }
+
+    #[test]
+    fn test_movement_interrupts_cast_once() {
+        let mut app = App::new();
+        let mut spell = assets::SpellData::new("cast".into(), 0);
+        spell.interrupt_on_move = true;
+        app.insert_resource(assets::SpellsAsset(vec![spell]));
+        app.add_event::<game::events::CastRejectedEvent>();
+        app.add_event::<game::events::CombatLogEvent>();
+        let casting = || shared::CastingSpell {
+            cast_timer: Timer::from_seconds(1.0, TimerMode::Once),
+            spell_id: 0.into(),
+            target: shared::SpellTarget::SelfRadius,
+        };
+        let caster = app
+            .world
+            .spawn((ServerPlayerBundle::new(server::Token::new(1)), casting()))
+            .id();
+
+        // moving, stopping & moving again in one tick stops the cast once
+        let packets = vec![
+            movement(1, 1, packet::MOVE_LEFT),
+            movement(2, 2, packet::MOVE_NONE),
+            movement(3, 3, packet::MOVE_RIGHT),
+        ];
+        app.world.run_system_once_with(
+            HashMap::from([(caster, packets)]),
+            sys_process_client_packets,
+        );
+        assert!(app.world.get::<shared::CastingSpell>(caster).is_none());
+        let stops = app
+            .world
+            .resource_mut::<Events<game::events::CombatLogEvent>>()
+            .drain()
+            .filter(|ev| matches!(ev.event, combat_log::CombatEvent::CastStop { .. }))
+            .count();
+        assert_eq!(stops, 1);
+
+        // casts started on the move stop on the next movement too
+        app.world.entity_mut(caster).insert(casting());
+        let packets = vec![movement(4, 4, packet::MOVE_UP)];
+        app.world.run_system_once_with(
+            HashMap::from([(caster, packets)]),
+            sys_process_client_packets,
+        );
+        assert!(app.world.get::<shared::CastingSpell>(caster).is_none());
+
+        // stopping doesn't interrupt
+        app.world.entity_mut(caster).insert(casting());
+        let packets = vec![movement(5, 5, packet::MOVE_NONE)];
+        app.world.run_system_once_with(
+            HashMap::from([(caster, packets)]),
+            sys_process_client_packets,
+        );
+        assert!(app.world.get::<shared::CastingSpell>(caster).is_some());
+    }
+}
@@ -1,12 +1,23 @@
 use crate::game::net::server;
-use lib_spells::{message_stream, net::packet};
+use bevy::log;
+use lib_spells::{
+    combat_log, message_stream,
+    net::{self, handshake, packet},
+    shared,
+};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 
+/// Past this many unsent bytes a client is falling behind, so state updates for it are dropped.
+/// The next one sent is a delta from whatever it last acknowledged, so nothing is lost.
+const STATE_BACKLOG_BYTES: usize = 1024 * 1024;
+
 #[derive(Debug)]
 pub enum ClientError {
     StreamError(message_stream::MessageStreamError),
     PacketError(packet::InvalidPacketError),
+    /// The client's write queue is full, so a message that mustn't be dropped couldn't be sent
+    Backlogged(usize),
 }
 
 impl Display for ClientError {
@@ -18,6 +29,9 @@ impl Display for ClientError {
             Self::PacketError(err) => {
                 write!(f, "packet error: {}", err)
             }
+            Self::Backlogged(queued) => {
+                write!(f, "{} bytes still unsent", queued)
+            }
         }
     }
 }
@@ -36,23 +50,36 @@ impl From<packet::InvalidPacketError> for ClientError {
 
 pub type Result<T> = std::result::Result<T, ClientError>;
 
+/// What a client's `handshake::Welcome` tells it besides its `ClientInfo`
+#[derive(Debug, Copy, Clone)]
+pub struct Session {
+    pub version: u16,
+    pub id: u64,
+    pub features: handshake::Features,
+    pub udp_port: Option<u16>,
+}
+
 struct ConnectedClient<T: std::io::Read + std::io::Write> {
     info_sent: bool,
     stream: message_stream::MessageStream<T>,
     client_info: Option<lib_spells::net::ClientInfo>,
+    session: Session,
 }
 
 pub struct ConnectedClients<T: std::io::Read + std::io::Write> {
     map: HashMap<server::Token, ConnectedClient<T>>,
     // clients that are OK to send broadcast data to
     send_targets: HashSet<server::Token>,
+    /// Server ticks per second, for welcomes
+    tick_rate: f64,
 }
 
 impl<T: std::io::Read + std::io::Write> ConnectedClients<T> {
-    pub fn new() -> Self {
+    pub fn new(tick_rate: f64) -> Self {
         Self {
             map: HashMap::default(),
             send_targets: HashSet::default(),
+            tick_rate,
         }
     }
 
@@ -67,7 +94,7 @@ impl<T: std::io::Read + std::io::Write> ConnectedClients<T> {
         }
     }
 
-    /// Tries to write info to any clients that need info and we have updated info state for
+    /// Tries to welcome any clients that haven't been and we have updated info state for
     pub fn try_write_client_info(
         &mut self,
     ) -> Vec<(server::Token, message_stream::MessageStreamError)> {
@@ -82,8 +109,16 @@ impl<T: std::io::Read + std::io::Write> ConnectedClients<T> {
                 None => continue,
             };
 
-            let serialized_client_info = lib_spells::net::serialize(&info).unwrap();
-            match client.stream.try_write_prefixed(&serialized_client_info) {
+            let welcome = handshake::ServerHandshake::Welcome(handshake::Welcome {
+                version: client.session.version,
+                tick_rate: self.tick_rate,
+                session: client.session.id,
+                features: client.session.features,
+                udp_port: client.session.udp_port,
+                client_info: info,
+            });
+            let serialized_welcome = lib_spells::net::serialize(&welcome).unwrap();
+            match client.stream.try_write_prefixed(&serialized_welcome) {
                 Ok(did_send) if did_send => {
                     self.send_targets.insert(*token);
                 }
@@ -94,12 +129,18 @@ impl<T: std::io::Read + std::io::Write> ConnectedClients<T> {
         errors
     }
 
-    pub fn add_client(&mut self, token: server::Token, stream: message_stream::MessageStream<T>) {
+    pub fn add_client(
+        &mut self,
+        token: server::Token,
+        stream: message_stream::MessageStream<T>,
+        session: Session,
+    ) {
         self.map.insert(
             token,
             ConnectedClient {
                 stream,
                 client_info: None,
+                session,
                 info_sent: false,
             },
         );
@@ -121,21 +162,66 @@ impl<T: std::io::Read + std::io::Write> ConnectedClients<T> {
         self.map.contains_key(&token)
     }
 
-    pub fn send_state(
+    /// Send a world state update, unless the client is still working through earlier ones
+    pub fn send_state(&mut self, token: server::Token, state: net::ServerMessage) -> Result<()> {
+        let queued = self.map.get(&token).map_or(0, |c| c.stream.queued_bytes());
+        if queued > STATE_BACKLOG_BYTES {
+            log::debug!("{} backed up with {} bytes, dropping state", token, queued);
+            return Ok(());
+        }
+        if !self.write_message(token, &state)? {
+            log::debug!("{} write queue full, dropping state", token);
+        }
+        Ok(())
+    }
+
+    pub fn send_cast_rejected(
         &mut self,
         token: server::Token,
-        seq: u8,
-        state: lib_spells::net::WorldState,
+        spell_id: shared::SpellID,
+        reason: shared::CastRejectedReason,
     ) -> Result<()> {
-        if !self.send_targets.contains(&token) {
+        self.send_message(
+            token,
+            &net::ServerMessage::CastRejected { spell_id, reason },
+        )
+    }
+
+    pub fn send_combat_log(
+        &mut self,
+        token: server::Token,
+        entries: Vec<combat_log::CombatLogEntry>,
+    ) -> Result<()> {
+        self.send_message(token, &net::ServerMessage::CombatLog(entries))
+    }
+
+    /// Send a message the client can't do without. Only states are dropped when it's backed up.
+    fn send_message(&mut self, token: server::Token, message: &net::ServerMessage) -> Result<()> {
+        if self.write_message(token, message)? {
             return Ok(());
         }
+        let queued = self.map.get(&token).map_or(0, |c| c.stream.queued_bytes());
+        Err(ClientError::Backlogged(queued))
+    }
+
+    /// Queue `message` for the client, returning false if its write queue is too full to take it
+    fn write_message(
+        &mut self,
+        token: server::Token,
+        message: &net::ServerMessage,
+    ) -> Result<bool> {
+        if !self.send_targets.contains(&token) {
+            return Ok(true);
+        }
 
         let target = self.map.get_mut(&token).unwrap();
-        let serialized_state = lib_spells::net::serialize(&state).unwrap();
-        let data = [&[seq], &serialized_state[..]].concat();
-        target.stream.try_write_prefixed(&data)?;
-        Ok(())
+        let data = net::serialize(message).unwrap();
+        Ok(target.stream.try_write_prefixed(&data)?)
+    }
+
+    /// Write out whatever the client's stream couldn't take earlier
+    pub fn try_flush(&mut self, token: server::Token) -> Result<()> {
+        Ok(self.map.get_mut(&token).unwrap().stream.flush()?)
     }
 
     pub fn try_receive(&mut self, token: server::Token) -> Result<Vec<packet::Packet>> {
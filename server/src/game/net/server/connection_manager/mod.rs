@@ -2,17 +2,23 @@
 broadcast, etc */
 use crate::game::net::server;
 use bevy::log;
-use lib_spells::message_stream;
+use lib_spells::{
+    message_stream,
+    net::{self, handshake},
+};
 use std::sync::mpsc;
 
 mod connected_clients;
 mod pending_clients;
+mod udp_clients;
 
 pub struct ConnectionManager<T: std::io::Read + std::io::Write> {
     inc_tx: mpsc::Sender<server::Incoming>,
     out_rx: mpsc::Receiver<server::Outgoing>,
     connected: connected_clients::ConnectedClients<T>,
     pending: pending_clients::PendingClients<T>,
+    /// Sessions for clients sending packets over UDP, if we offer it
+    udp: Option<udp_clients::UdpClients>,
     dead: Vec<message_stream::MessageStream<T>>,
 }
 
@@ -21,12 +27,19 @@ impl<T: std::io::Read + std::io::Write> ConnectionManager<T> {
         inc_tx: mpsc::Sender<server::Incoming>,
         out_rx: mpsc::Receiver<server::Outgoing>,
         password: Option<String>,
+        udp_socket: Option<mio::net::UdpSocket>,
+        tick_rate: f64,
     ) -> Self {
+        let supported = handshake::Features {
+            udp: udp_socket.is_some(),
+            compression: false,
+        };
         Self {
             inc_tx,
             out_rx,
-            connected: connected_clients::ConnectedClients::<T>::new(),
-            pending: pending_clients::PendingClients::new(password),
+            connected: connected_clients::ConnectedClients::<T>::new(tick_rate),
+            pending: pending_clients::PendingClients::new(password, supported),
+            udp: udp_socket.map(udp_clients::UdpClients::new),
             dead: vec![],
         }
     }
@@ -38,6 +51,9 @@ impl<T: std::io::Read + std::io::Write> ConnectionManager<T> {
         self.connect_validated_pending();
         self.kick_expired();
         self.check_outgoing();
+        if let Some(udp) = self.udp.as_mut() {
+            udp.send_acks();
+        }
     }
 
     /// Take ownership of a stream to be managed. Once it's kicked, it'll be available in
@@ -66,6 +82,53 @@ impl<T: std::io::Read + std::io::Write> ConnectionManager<T> {
         }
     }
 
+    /// Read every datagram waiting on the UDP socket, passing on their packets
+    pub fn try_read_datagrams(&mut self) {
+        let received = match self.udp.as_mut().map(|udp| udp.try_receive()) {
+            Some(Ok(received)) => received,
+            Some(Err(err)) => {
+                log::error!("udp read error: {}", err);
+                return;
+            }
+            None => return,
+        };
+        for (token, packets) in received {
+            match packets {
+                Ok(packets) => {
+                    for packet in packets {
+                        self.inc_tx
+                            .send(server::Incoming::Data(token, packet))
+                            .expect("receiver died");
+                    }
+                }
+                Err(err) => {
+                    log::info!("datagram error {}: {}", token, err);
+                    self.kick_client(token);
+                }
+            }
+        }
+    }
+
+    /// Flush writes queued for the client at the given token now that it's writable
+    pub fn try_flush(&mut self, token: server::Token) {
+        if self.pending.has_client(token) {
+            match self.pending.try_flush(token) {
+                Ok(()) if self.pending.rejection_sent(token) => self.kick_client(token),
+                Ok(()) => {}
+                Err(err) => {
+                    log::info!("write error {}: {}", token, err);
+                    self.kick_client(token);
+                }
+            }
+        }
+        if self.connected.has_client(token) {
+            if let Err(err) = self.connected.try_flush(token) {
+                log::info!("write error {}: {}", token, err);
+                self.kick_client(token);
+            }
+        }
+    }
+
     pub fn collect_dead<F>(&mut self, f: F)
     where
         F: FnOnce(message_stream::MessageStream<T>) + Copy,
@@ -82,7 +145,19 @@ impl<T: std::io::Read + std::io::Write> ConnectionManager<T> {
             .into_iter()
             .for_each(|out| match out {
                 server::Outgoing::ClientState(token, update) => {
-                    if let Err(err) = self.connected.send_state(token, update.seq, update.world_state) {
+                    if let Err(err) = self.send_state(token, update) {
+                        log::info!("write error: {}", err);
+                        self.kick_client(token);
+                    }
+                }
+                server::Outgoing::CastRejected(token, spell_id, reason) => {
+                    if let Err(err) = self.connected.send_cast_rejected(token, spell_id, reason) {
+                        log::info!("write error: {}", err);
+                        self.kick_client(token);
+                    }
+                }
+                server::Outgoing::CombatLog(token, entries) => {
+                    if let Err(err) = self.connected.send_combat_log(token, entries) {
                         log::info!("write error: {}", err);
                         self.kick_client(token);
                     }
@@ -96,10 +171,32 @@ impl<T: std::io::Read + std::io::Write> ConnectionManager<T> {
             });
     }
 
+    /// Send state over UDP if the client has a session & it fits a datagram, else over TCP
+    fn send_state(
+        &mut self,
+        token: server::Token,
+        update: server::ClientStateUpdate,
+    ) -> connected_clients::Result<()> {
+        let message = net::ServerMessage::WorldState {
+            seq: update.seq,
+            snapshot: update.snapshot,
+        };
+        if let Some(udp) = self.udp.as_mut() {
+            if udp.try_send(token, &net::serialize(&message).unwrap()) {
+                return Ok(());
+            }
+        }
+        self.connected.send_state(token, message)
+    }
+
     fn read_pending_validation(&mut self, token: server::Token) {
-        if let Err(err) = self.pending.try_read_password(token) {
+        if let Err(err) = self.pending.try_read_handshake(token) {
             log::info!("validation error {}: {}", token, err);
-            self.kick_client(token);
+            // rejected clients wait to be told why, unless that's already happened
+            let rejected = matches!(err, pending_clients::ClientValidationError::Rejected(_));
+            if !rejected || self.pending.rejection_sent(token) {
+                self.kick_client(token);
+            }
         }
     }
 
@@ -121,18 +218,51 @@ impl<T: std::io::Read + std::io::Write> ConnectionManager<T> {
 
     /// Take all validated pending clients and move them to `connected`
     fn connect_validated_pending(&mut self) {
-        for (token, client) in self.pending.remove_validated() {
+        for (token, client, hello) in self.pending.remove_validated() {
             log::info!("client validated & connected: {}", token);
-            self.connected.add_client(token, client);
+            let id = self.new_session_id();
+            let udp_port = match self.udp.as_mut() {
+                Some(udp) if hello.features.udp => {
+                    udp.add_client(token, id);
+                    udp.port().ok()
+                }
+                _ => None,
+            };
+            let session = connected_clients::Session {
+                version: hello.version,
+                id,
+                features: handshake::Features {
+                    udp: udp_port.is_some(),
+                    ..hello.features
+                },
+                udp_port,
+            };
+            self.connected.add_client(token, client, session);
             self.inc_tx
                 .send(server::Incoming::Joined(token))
                 .expect("receiver dead");
         }
     }
 
+    /// A session ID no UDP client has. It's all that authenticates datagrams, so it comes from
+    /// the OS's secure random source.
+    fn new_session_id(&self) -> u64 {
+        loop {
+            let mut bytes = [0; 8];
+            getrandom::getrandom(&mut bytes).expect("no OS random source");
+            let id = u64::from_le_bytes(bytes);
+            if !self.udp.as_ref().is_some_and(|udp| udp.has_session(id)) {
+                return id;
+            }
+        }
+    }
+
     /// Tries to pull `token` out of either `connected` or `pending` and move it into `dead`.
     fn kick_client(&mut self, token: server::Token) {
         log::info!("kick: {}", token);
+        if let Some(udp) = self.udp.as_mut() {
+            udp.remove_client(token);
+        }
         if let Some(client) = self.connected.remove_client(token) {
             // make sure we notify about connected clients leaving
             self.inc_tx
@@ -1,16 +1,20 @@
 use crate::game::net::server;
+use bevy::log;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::time::{Duration, Instant};
 use std::{io, time};
 
-use lib_spells::message_stream;
+use lib_spells::{
+    message_stream,
+    net::{self, handshake},
+};
 
 const PENDING_TIMEOUT: Duration = Duration::from_millis(1000);
 
 pub enum ClientValidationError {
     StreamError(message_stream::MessageStreamError),
-    BadPassword,
+    Rejected(handshake::Rejection),
 }
 
 impl Display for ClientValidationError {
@@ -19,8 +23,8 @@ impl Display for ClientValidationError {
             ClientValidationError::StreamError(err) => {
                 write!(f, "stream error: {}", err)
             }
-            ClientValidationError::BadPassword => {
-                write!(f, "wrong password")
+            ClientValidationError::Rejected(reason) => {
+                write!(f, "rejected: {}", reason)
             }
         }
     }
@@ -32,21 +36,31 @@ impl From<message_stream::MessageStreamError> for ClientValidationError {
     }
 }
 
+/// What we agreed to with a client that sent an acceptable `ClientHello`
+#[derive(Debug, Copy, Clone)]
+pub struct AcceptedHello {
+    pub version: u16,
+    pub features: handshake::Features,
+}
+
 #[derive(Debug)]
 struct TimedClient<T: std::io::Read+  std::io::Write> {
     created_at: time::Instant,
     stream: message_stream::MessageStream<T>,
     sent_header: bool,
-    validated: bool,
+    accepted: Option<AcceptedHello>,
+    /// Told why it was turned away, & only waiting for that to be written out
+    rejected: bool,
 }
 
 impl<T: std::io::Read + std::io::Write> TimedClient<T> {
-    pub fn new(client: message_stream::MessageStream<T>, passworded: bool) -> Self {
+    pub fn new(client: message_stream::MessageStream<T>) -> Self {
         Self {
             stream: client,
             created_at: Instant::now(),
             sent_header: false,
-            validated: !passworded,
+            accepted: None,
+            rejected: false,
         }
     }
 
@@ -59,28 +73,73 @@ impl<T: std::io::Read + std::io::Write> TimedClient<T> {
         if self.sent_header {
             return Ok(());
         }
-        if self.stream.try_write_prefixed(lib_spells::SERVER_HEADER)? {
+        let max_read = self.stream.max_read() as u32;
+        let header = [lib_spells::SERVER_HEADER, &max_read.to_le_bytes()].concat();
+        if self.stream.try_write_prefixed(&header)? {
             self.sent_header = true;
+            self.stream.set_framing(message_stream::Framing::V2);
+        }
+        Ok(())
+    }
+
+    /// The client's first message is its `ClientHello`. Anything after it is ignored until
+    /// it's connected.
+    fn read_hello(
+        &mut self,
+        password: Option<&String>,
+        supported: handshake::Features,
+        message: &[u8],
+    ) -> Result<(), handshake::Rejection> {
+        if self.accepted.is_some() {
+            return Ok(());
+        }
+        let hello: handshake::ClientHello =
+            net::deserialize(message).map_err(|_| handshake::Rejection::MalformedHello)?;
+        let version = hello.negotiate_version()?;
+        if password.is_some_and(|password| hello.password.as_ref() != Some(password)) {
+            return Err(handshake::Rejection::BadPassword);
         }
+        log::info!(
+            "hello from {} speaking protocol {}",
+            hello.build_id,
+            version
+        );
+        self.stream.set_max_write(hello.max_message_bytes as usize);
+        self.accepted = Some(AcceptedHello {
+            version,
+            features: hello.features.intersection(supported),
+        });
         Ok(())
     }
+
+    /// Tell the client why it's turned away, returning the rejection as an error
+    fn reject(&mut self, reason: handshake::Rejection) -> Result<(), ClientValidationError> {
+        let rejected = handshake::ServerHandshake::Rejected(reason);
+        self.stream
+            .try_write_prefixed(&net::serialize(&rejected).unwrap())?;
+        self.rejected = true;
+        Err(ClientValidationError::Rejected(reason))
+    }
 }
 
 pub struct PendingClients<T: std::io::Read + std::io::Write> {
     pending: HashMap<server::Token, TimedClient<T>>,
     password: Option<String>,
+    /// Features clients can have if they ask
+    supported: handshake::Features,
 }
 
 impl<T: std::io::Read + std::io::Write> PendingClients<T> {
-    pub fn new(password: Option<String>) -> Self {
+    pub fn new(password: Option<String>, supported: handshake::Features) -> Self {
         Self {
             password,
+            supported,
             pending: HashMap::default(),
         }
     }
 
     pub fn add_client(&mut self, token: server::Token, client: message_stream::MessageStream<T>) {
-        let pending = TimedClient::new(client, self.password.is_some());
+        let pending = TimedClient::new(client);
         self.pending.insert(token, pending);
     }
 
@@ -97,14 +156,23 @@ impl<T: std::io::Read + std::io::Write> PendingClients<T> {
             .collect()
     }
 
-    /// Moves all fully validated streams out to the caller
-    pub fn remove_validated(&mut self) -> Vec<(server::Token, message_stream::MessageStream<T>)> {
+    /// Moves all accepted streams out to the caller, with what they were accepted with
+    pub fn remove_validated(
+        &mut self,
+    ) -> Vec<(
+        server::Token,
+        message_stream::MessageStream<T>,
+        AcceptedHello,
+    )> {
         self.pending
             .iter()
-            .filter_map(|(t, s)| (s.validated && s.sent_header).then_some(*t))
+            .filter_map(|(t, s)| (s.accepted.is_some() && s.sent_header).then_some(*t))
             .collect::<Vec<server::Token>>() // borrow checker
             .iter()
-            .map(|t| (*t, self.pending.remove(t).unwrap().stream))
+            .map(|t| {
+                let client = self.pending.remove(t).unwrap();
+                (*t, client.stream, client.accepted.unwrap())
+            })
             .collect()
     }
 
@@ -119,28 +187,122 @@ impl<T: std::io::Read + std::io::Write> PendingClients<T> {
             .collect()
     }
 
-    /// Try to read a password off of a pending client, marking it as validated if it was sent
-    /// correctly.
-    pub fn try_read_password(&mut self, token: server::Token) -> Result<(), ClientValidationError> {
+    /// Try to read the `ClientHello` off of a pending client, accepting it if it's acceptable.
+    /// Rejected clients are told why before the error is returned, & aren't read from again.
+    pub fn try_read_handshake(
+        &mut self,
+        token: server::Token,
+    ) -> Result<(), ClientValidationError> {
         let client = self.pending.get_mut(&token).unwrap();
-        if let Some(password) = &self.password {
-            match client.stream.try_read_messages() {
-                Ok(messages) => {
-                    if let Some(message) = messages.first() {
-                        if password.as_bytes() == message {
-                            client.validated = true;
-                        } else {
-                            return Err(ClientValidationError::BadPassword);
-                        }
-                    }
-                }
-                Err(err) => return Err(err.into()),
+        if client.rejected {
+            return Ok(());
+        }
+        let messages = match client.stream.try_read_messages() {
+            Ok(messages) => messages,
+            // a hello too big to read is as good as unreadable
+            Err(message_stream::MessageStreamError::InvalidHeaderSize(_)) => {
+                return client.reject(handshake::Rejection::MalformedHello)
+            }
+            Err(err) => return Err(err.into()),
+        };
+        for message in messages {
+            if let Err(reason) = client.read_hello(self.password.as_ref(), self.supported, &message)
+            {
+                return client.reject(reason);
             }
         }
         Ok(())
     }
 
+    /// Has the client been rejected & told why, so it can be dropped
+    pub fn rejection_sent(&self, token: server::Token) -> bool {
+        self.pending
+            .get(&token)
+            .is_some_and(|client| client.rejected && client.stream.queued_bytes() == 0)
+    }
+
+    /// Write out whatever the client's stream couldn't take earlier
+    pub fn try_flush(&mut self, token: server::Token) -> Result<(), ClientValidationError> {
+        Ok(self.pending.get_mut(&token).unwrap().stream.flush()?)
+    }
+
     pub fn has_client(&self, token: server::Token) -> bool {
         self.pending.contains_key(&token)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads `incoming`, then would block. Writes go to `written` until it holds `capacity` bytes.
+    #[derive(Debug)]
+    struct FakeStream {
+        incoming: Vec<u8>,
+        written: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl io::Read for FakeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.incoming.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.incoming.len());
+            buf[..n].copy_from_slice(&self.incoming[..n]);
+            self.incoming.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl io::Write for FakeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.capacity - self.written.len());
+            if n == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_oversized_hello_rejected() {
+        let mut pending = PendingClients::new(None, handshake::Features::default());
+        let token = server::Token::new(1);
+        let stream = FakeStream {
+            // the start of a 2000 byte message
+            incoming: vec![0xd0, 0x0f, 0, 0, 0],
+            written: vec![],
+            capacity: 0,
+        };
+        let stream = message_stream::MessageStream::create(stream, 1024).unwrap();
+        pending.add_client(token, stream);
+        assert!(pending.try_send_headers().is_empty());
+
+        assert!(matches!(
+            pending.try_read_handshake(token),
+            Err(ClientValidationError::Rejected(
+                handshake::Rejection::MalformedHello
+            ))
+        ));
+        // the rejection is still on its way
+        assert!(!pending.rejection_sent(token));
+
+        let client = pending.pending.get_mut(&token).unwrap();
+        client.stream.inner().capacity = usize::MAX;
+        assert!(pending.try_flush(token).is_ok());
+        assert!(pending.rejection_sent(token));
+        let rejected = handshake::ServerHandshake::Rejected(handshake::Rejection::MalformedHello);
+        let client = pending.pending.get_mut(&token).unwrap();
+        assert!(client
+            .stream
+            .inner()
+            .written
+            .ends_with(&net::serialize(&rejected).unwrap()));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::game::net::server;
+use bevy::log;
+use lib_spells::{datagram, net::packet};
+use mio::net::UdpSocket;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+
+/// Packets a client sent in one datagram, or why they couldn't be parsed
+pub type ReceivedPackets = (
+    server::Token,
+    Result<Vec<packet::Packet>, packet::InvalidPacketError>,
+);
+
+struct UdpClient {
+    channel: datagram::Channel,
+    packets: datagram::ReliableReceiver,
+    /// Where the client's datagrams come from, once it has sent one
+    addr: Option<SocketAddr>,
+    /// The client sent datagrams we haven't acknowledged yet
+    needs_ack: bool,
+}
+
+/// Connected clients' UDP sessions, all sharing one socket
+pub struct UdpClients {
+    socket: UdpSocket,
+    sessions: HashMap<u64, server::Token>,
+    clients: HashMap<server::Token, UdpClient>,
+}
+
+impl UdpClients {
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            sessions: HashMap::default(),
+            clients: HashMap::default(),
+        }
+    }
+
+    /// Port clients should send their datagrams to
+    pub fn port(&self) -> io::Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    /// Take datagrams tagged with `session` as coming from `token`
+    pub fn add_client(&mut self, token: server::Token, session: u64) {
+        self.sessions.insert(session, token);
+        self.clients.insert(
+            token,
+            UdpClient {
+                channel: datagram::Channel::new(session),
+                packets: Default::default(),
+                addr: None,
+                needs_ack: false,
+            },
+        );
+    }
+
+    pub fn has_session(&self, session: u64) -> bool {
+        self.sessions.contains_key(&session)
+    }
+
+    pub fn remove_client(&mut self, token: server::Token) {
+        if let Some(client) = self.clients.remove(&token) {
+            self.sessions.remove(&client.channel.session());
+        }
+    }
+
+    /// Read every waiting datagram, returning the new packets per client. Datagrams that aren't
+    /// for a session are dropped, since anyone can send them.
+    pub fn try_receive(&mut self) -> io::Result<Vec<ReceivedPackets>> {
+        let mut received = vec![];
+        let mut buf = [0; datagram::MAX_DATAGRAM_BYTES];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buf) {
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            match self.read_datagram(&buf[..len], addr) {
+                Ok(Some(packets)) => received.push(packets),
+                Ok(None) => {}
+                Err(err) => log::debug!("dropping datagram from {}: {}", addr, err),
+            }
+        }
+        Ok(received)
+    }
+
+    fn read_datagram(
+        &mut self,
+        data: &[u8],
+        addr: SocketAddr,
+    ) -> datagram::Result<Option<ReceivedPackets>> {
+        let (header, _) = datagram::Header::parse(data)?;
+        let token = match self.sessions.get(&header.session) {
+            Some(token) => *token,
+            None => return Err(datagram::DatagramError::WrongSession),
+        };
+        let client = self.clients.get_mut(&token).unwrap();
+        let bundle = match client.channel.decode(data)?.payload {
+            Some(bundle) => bundle,
+            None => return Ok(None),
+        };
+        // follow the client if its address changes, the session is what identifies it
+        client.addr = Some(addr);
+        client.needs_ack = true;
+        let packets = client
+            .packets
+            .receive(bundle)?
+            .into_iter()
+            .map(packet::Packet::deserialize)
+            .collect();
+        Ok(Some((token, packets)))
+    }
+
+    /// Send `message` to the client if we know where it is & it fits in a datagram. Returns
+    /// false if it should go over TCP instead.
+    pub fn try_send(&mut self, token: server::Token, message: &[u8]) -> bool {
+        let client = match self.clients.get_mut(&token) {
+            Some(client) => client,
+            None => return false,
+        };
+        let addr = match client.addr {
+            Some(addr) if message.len() <= datagram::MAX_PAYLOAD_BYTES => addr,
+            _ => return false,
+        };
+        client.needs_ack = false;
+        let datagram = client.channel.encode(message).unwrap();
+        match self.socket.send_to(&datagram, addr) {
+            // datagrams may be lost anyway, so one the socket can't take is just lost early
+            Ok(_) => true,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => true,
+            Err(err) => {
+                log::info!("datagram to {} failed: {}", token, err);
+                false
+            }
+        }
+    }
+
+    /// Send an empty datagram to every client whose datagrams we haven't acknowledged, since
+    /// world states too big for a datagram go over TCP & can't carry acks. Without them clients
+    /// would resend the same packets forever.
+    pub fn send_acks(&mut self) {
+        let tokens: Vec<server::Token> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.needs_ack)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in tokens {
+            self.try_send(token, &[]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn receive_all(clients: &mut UdpClients) -> Vec<ReceivedPackets> {
+        // loopback delivery isn't instant
+        std::thread::sleep(Duration::from_millis(50));
+        clients.try_receive().unwrap()
+    }
+
+    #[test]
+    fn test_udp_session_over_loopback() {
+        let socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = socket.local_addr().unwrap();
+        let mut clients = UdpClients::new(socket);
+        let token = server::Token::new(1);
+        let session = 42;
+        clients.add_client(token, session);
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server_addr).unwrap();
+        let mut channel = datagram::Channel::new(session);
+        let mut sender = datagram::ReliableSender::default();
+
+        // nowhere to send to until the client speaks up
+        assert!(!clients.try_send(token, b"state"));
+
+        let packet = packet::Packet {
+            timestamp: Duration::from_millis(5),
+            seq: 1,
+            command_type: packet::PacketType::AckSnapshot,
+            command_data: packet::PacketData::AckSnapshot(3),
+        };
+        sender.push(packet.serialize()).unwrap();
+        let datagram = channel.encode(&sender.bundle(0)).unwrap();
+        client.send(&datagram).unwrap();
+        client.send(&datagram).unwrap();
+        // unknown sessions are ignored
+        client
+            .send(&datagram::Channel::new(session + 1).encode(&[]).unwrap())
+            .unwrap();
+
+        let received = receive_all(&mut clients);
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, token);
+        assert_eq!(received[0].1.as_ref().unwrap(), &vec![packet]);
+
+        assert!(clients.try_send(token, b"state"));
+        let mut buf = [0; datagram::MAX_DATAGRAM_BYTES];
+        let len = client.recv(&mut buf).unwrap();
+        let reply = channel.decode(&buf[..len]).unwrap();
+        assert_eq!(reply.payload, Some(&b"state"[..]));
+        assert_eq!(reply.acked, vec![0]);
+        assert!(!clients.try_send(token, &[0; datagram::MAX_PAYLOAD_BYTES + 1]));
+
+        // a state too big for a datagram still gets the client's packets acknowledged
+        sender.push(packet.serialize()).unwrap();
+        client
+            .send(&channel.encode(&sender.bundle(1)).unwrap())
+            .unwrap();
+        assert_eq!(receive_all(&mut clients).len(), 1);
+        assert!(!clients.try_send(token, &[0; datagram::MAX_PAYLOAD_BYTES + 1]));
+        clients.send_acks();
+        let len = client.recv(&mut buf).unwrap();
+        let reply = channel.decode(&buf[..len]).unwrap();
+        assert_eq!(reply.payload, Some(&[][..]));
+        assert_eq!(reply.acked, vec![1]);
+        // nothing new to acknowledge
+        clients.send_acks();
+        client.set_nonblocking(true).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(client.recv(&mut buf).is_err());
+
+        clients.remove_client(token);
+        client
+            .send(&channel.encode(&sender.bundle(2)).unwrap())
+            .unwrap();
+        assert!(receive_all(&mut clients).is_empty());
+    }
+}
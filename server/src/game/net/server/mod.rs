@@ -1,8 +1,9 @@
-/*! TCP server implementation for managing connected game clients */
+/*! TCP server implementation for managing connected game clients, with packets & world state
+optionally going over UDP once a client is connected */
 
 mod connection_manager;
 
-use mio::net::TcpListener;
+use mio::net::{TcpListener, UdpSocket};
 use mio::{Events, Interest, Poll};
 
 use std::fmt::Display;
@@ -10,12 +11,15 @@ use std::io;
 use std::sync::mpsc;
 use std::time::Duration;
 
-use lib_spells::{net::packet, message_stream};
+use lib_spells::{net::packet, message_stream, shared};
 
 use bevy::log;
 
-const MAX_MESSAGE_SIZE: usize = 128;
+/// Largest message clients may send, leaving room for a `ClientHello` with a long build id &
+/// password
+const MAX_MESSAGE_SIZE: usize = 1024;
 const SERVER_TOKEN: Token = Token(mio::Token(0));
+const UDP_TOKEN: Token = Token(mio::Token(usize::MAX));
 const EVENT_BUFFER_SIZE: usize = 1028;
 const MIN_TICK: Duration = Duration::from_millis(100);
 const SERVER_ADDR: &str = "0.0.0.0:7776";
@@ -51,7 +55,7 @@ impl From<Token> for mio::Token {
 #[derive(Debug, Clone)]
 pub struct ClientStateUpdate {
     pub seq: u8,
-    pub world_state: lib_spells::net::WorldState,
+    pub snapshot: lib_spells::net::Snapshot,
 }
 
 
@@ -67,24 +71,38 @@ pub enum Outgoing {
     Kick(Token),
     ClientState(Token, ClientStateUpdate),
     ClientInfo(Token, lib_spells::net::ClientInfo),
+    CastRejected(Token, shared::SpellID, shared::CastRejectedReason),
+    CombatLog(Token, Vec<lib_spells::combat_log::CombatLogEntry>),
 }
 
 pub struct Server {
     listener: TcpListener,
+    udp_socket: Option<UdpSocket>,
     events: Events,
     poll: Poll,
 }
 
 impl Server {
-    pub fn create() -> io::Result<Server> {
+    /// Bind the server, also taking packets over UDP on the same port if `udp` is set
+    pub fn create(udp: bool) -> io::Result<Server> {
         log::info!("binding server to {SERVER_ADDR}");
         let mut listener = TcpListener::bind(SERVER_ADDR.parse().unwrap())?;
         let poll = Poll::new()?;
         poll.registry()
             .register(&mut listener, SERVER_TOKEN.into(), Interest::READABLE)?;
+        let udp_socket = match udp {
+            true => {
+                let mut socket = UdpSocket::bind(SERVER_ADDR.parse().unwrap())?;
+                poll.registry()
+                    .register(&mut socket, UDP_TOKEN.into(), Interest::READABLE)?;
+                Some(socket)
+            }
+            false => None,
+        };
         let events = Events::with_capacity(EVENT_BUFFER_SIZE);
         Ok(Server {
             listener,
+            udp_socket,
             poll,
             events,
         })
@@ -96,8 +114,15 @@ impl Server {
         inc_tx: mpsc::Sender<Incoming>,
         out_rx: mpsc::Receiver<Outgoing>,
         password: Option<String>,
+        tick_rate: f64,
     ) -> io::Result<()> {
-        let mut manager = connection_manager::ConnectionManager::new(inc_tx, out_rx, password);
+        let mut manager = connection_manager::ConnectionManager::new(
+            inc_tx,
+            out_rx,
+            password,
+            self.udp_socket.take(),
+            tick_rate,
+        );
 
         let mut next_socket = 1_usize;
         let mut next_token = || {
@@ -145,8 +170,12 @@ impl Server {
                             ev.is_readable(),
                         );
                     },
+                    UDP_TOKEN => manager.try_read_datagrams(),
                     // this is a managed client
                     token => {
+                        if ev.is_writable() {
+                            manager.try_flush(token);
+                        }
                         if ev.is_readable() {
                             manager.try_read(token);
                         }
@@ -167,13 +196,13 @@ mod tests {
     fn test_incoming_client_recv() {
         let (_keep, rx) = mpsc::channel();
         let (tx, _keep) = mpsc::channel();
-        let mut server = Server::create().unwrap();
+        let mut server = Server::create(false).unwrap();
 
         let password = "bob".to_string();
         let server_pass = Some(password.clone());
 
         let server_h = thread::spawn(move || {
-            server.event_loop(tx, rx, server_pass).unwrap();
+            server.event_loop(tx, rx, server_pass, 20.0).unwrap();
         });
 
         let connect = |password: String| {
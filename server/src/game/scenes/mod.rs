@@ -4,7 +4,7 @@
 
 use std::time::Duration;
 
-use crate::game::{effect_application, events};
+use crate::game::events;
 use bevy::{log, prelude::*};
 
 use lib_spells::shared;
@@ -33,12 +33,17 @@ pub fn sys_many_effects(world: &mut World) {
 
     let mut defender_entities = vec![];
     for _ in 0..n_defenders {
-        let defender = world.spawn(shared::Health(defender_hp)).id();
-        defender_entities.push(defender);
+        let mut auras = shared::Auras::default();
         for _ in 0..n_shields {
-            let shield = world.spawn(effect_application::ShieldAura(shield_val)).id();
-            world.entity_mut(defender).add_child(shield);
+            auras.add(shared::Aura::new(
+                0.into(),
+                Entity::PLACEHOLDER,
+                Duration::from_secs(1000),
+                shared::AuraEffect::Shield(shield_val),
+            ));
         }
+        let defender = world.spawn((shared::Health::new(defender_hp), auras)).id();
+        defender_entities.push(defender);
     }
     for target in defender_entities.iter() {
         for _ in 0..n_effects_per_defender {
@@ -46,8 +51,11 @@ pub fn sys_many_effects(world: &mut World) {
                 .get_resource_mut::<Events<events::EffectQueueEvent>>()
                 .unwrap()
                 .send(events::EffectQueueEvent {
+                    origin: Entity::PLACEHOLDER,
                     target: *target,
+                    school: shared::SpellSchool::Physical,
                     health_effect: Some(effect_dmg),
+                    crit_chance: 0.0,
                     aura_effect: None,
                 });
         }
@@ -55,7 +63,7 @@ pub fn sys_many_effects(world: &mut World) {
 }
 
 pub fn sys_spells(world: &mut World) {
-    let skeleton = world.spawn(shared::Health(25)).id();
+    let skeleton = world.spawn(shared::Health::new(25)).id();
     world.entity_mut(skeleton).insert((
         shared::SpellCaster,
         shared::CastingSpell::new(2.into(), skeleton, Duration::from_secs(1000)),
@@ -78,15 +86,15 @@ pub fn sys_spells(world: &mut World) {
 }
 
 pub fn sys_auras(world: &mut World) {
-    let target = world.spawn(shared::SpellCaster).id();
-    let aura = world
-        .spawn(shared::Aura {
-            id: 0.into(),
-            duration: Timer::from_seconds(5.0, TimerMode::Once),
-            owner: target,
-        })
-        .id();
+    let mut auras = shared::Auras::default();
+    let slot = auras.add(shared::Aura::new(
+        0.into(),
+        Entity::PLACEHOLDER,
+        Duration::from_secs(5),
+        shared::AuraEffect::TickingHP(Timer::from_seconds(1.0, TimerMode::Repeating)),
+    ));
+    let target = world.spawn((shared::SpellCaster, auras)).id();
 
     log::info!("aura TARGET: {:?}", target);
-    log::info!("aura ENTITY: {:?}", aura);
+    log::info!("aura SLOT: {:?}", slot);
 }
@@ -0,0 +1,85 @@
+/*! Grid of unit positions, rebuilt every tick, so area lookups only check nearby units */
+use bevy::{prelude::*, utils::HashMap};
+use lib_spells::shared;
+
+use super::ServerSets;
+
+const CELL_SIZE: f32 = 8.0;
+
+#[derive(Resource, Debug, Default)]
+pub struct SpatialIndex {
+    cells: HashMap<IVec3, Vec<(Entity, Vec3)>>,
+}
+
+impl SpatialIndex {
+    fn cell(pos: Vec3) -> IVec3 {
+        (pos / CELL_SIZE).floor().as_ivec3()
+    }
+
+    pub fn insert(&mut self, entity: Entity, pos: Vec3) {
+        self.cells
+            .entry(Self::cell(pos))
+            .or_default()
+            .push((entity, pos));
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+    }
+
+    /// Every indexed entity within `radius` of `center`, with its position
+    pub fn within(&self, center: Vec3, radius: f32) -> impl Iterator<Item = (Entity, Vec3)> + '_ {
+        let min = Self::cell(center - Vec3::splat(radius));
+        let max = Self::cell(center + Vec3::splat(radius));
+        (min.x..=max.x)
+            .flat_map(move |x| (min.y..=max.y).map(move |y| (x, y)))
+            .flat_map(move |(x, y)| (min.z..=max.z).map(move |z| IVec3::new(x, y, z)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .filter(move |(_, pos)| pos.distance(center) <= radius)
+    }
+}
+
+/// Index every unit. Projectiles have positions too, but aren't something spells can hit.
+pub(crate) fn sys_rebuild_spatial_index(
+    mut index: ResMut<SpatialIndex>,
+    query: Query<(Entity, &shared::Position), Without<shared::Projectile>>,
+) {
+    index.clear();
+    for (entity, pos) in query.iter() {
+        index.insert(entity, pos.0);
+    }
+}
+
+pub struct SpatialPlugin;
+
+impl Plugin for SpatialPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SpatialIndex>();
+        app.add_systems(
+            FixedUpdate,
+            sys_rebuild_spatial_index.in_set(ServerSets::EntityProcessing),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_within() {
+        let mut index = SpatialIndex::default();
+        let near = Entity::from_raw(1);
+        let edge = Entity::from_raw(2);
+        let far = Entity::from_raw(3);
+        index.insert(near, Vec3::new(1.0, 0.0, -1.0));
+        index.insert(edge, Vec3::new(-10.0, 0.0, 0.0));
+        index.insert(far, Vec3::new(30.0, 2.0, 0.0));
+
+        let mut found: Vec<Entity> = index.within(Vec3::ZERO, 10.0).map(|(e, _)| e).collect();
+        found.sort();
+        assert_eq!(found, vec![near, edge]);
+    }
+}
@@ -0,0 +1,36 @@
+//! Fixtures shared between the game systems' tests
+use bevy::prelude::*;
+use lib_spells::shared;
+use std::time::Duration;
+
+use crate::game::assets;
+
+/// 10s aura data, named after its key
+pub fn aura_data(key: &str, percent: i64, aura_type: shared::AuraType) -> assets::AuraData {
+    assets::AuraData::new(
+        key.into(),
+        key.into(),
+        percent,
+        Duration::from_secs(10),
+        aura_type,
+    )
+}
+
+/// Auras holding a 10s instance of each `(aura_id, effect)`, cast by nobody in particular
+pub fn auras(effects: impl IntoIterator<Item = (usize, shared::AuraEffect)>) -> shared::Auras {
+    let mut auras = shared::Auras::default();
+    for (aura_id, effect) in effects {
+        auras.add(shared::Aura::new(
+            aura_id.into(),
+            Entity::PLACEHOLDER,
+            Duration::from_secs(10),
+            effect,
+        ));
+    }
+    auras
+}
+
+/// Auras holding a single 10s instance of `aura_id`
+pub fn with_aura(aura_id: usize, effect: shared::AuraEffect) -> shared::Auras {
+    auras([(aura_id, effect)])
+}